@@ -0,0 +1,83 @@
+use bitcask_rs::db::Engine;
+use bitcask_rs::options::{IndexType, Options};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use std::{fs, thread};
+
+const KEY_NUM: u64 = 10_000;
+
+/// 多个读线程读取固定的一组 key，同时一个写线程不停写入另一组 key，
+/// 比较 Hash 和 ShardedHash 索引下读的吞吐:
+/// cargo run --release --example concurrent_read_bench [读线程数] [每种索引运行的秒数]
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let readers: usize = args.next().map_or(4, |s| s.parse().unwrap());
+    let secs: u64 = args.next().map_or(3, |s| s.parse().unwrap());
+
+    for index_type in [IndexType::Hash, IndexType::ShardedHash] {
+        let (reads, writes) = run(index_type.clone(), readers, Duration::from_secs(secs));
+        println!(
+            "{:?}: {} readers, {:.0} reads/s, {:.0} writes/s",
+            index_type,
+            readers,
+            reads as f64 / secs as f64,
+            writes as f64 / secs as f64
+        );
+    }
+}
+
+fn run(index_type: IndexType, readers: usize, duration: Duration) -> (u64, u64) {
+    let dir = std::env::temp_dir().join(format!("bitcask-rs-bench-{:?}", index_type));
+    let _ = fs::remove_dir_all(&dir);
+    let engine = Engine::open(Options {
+        dir_path: dir.display().to_string(),
+        file_threshold: 1 << 30,
+        index_type,
+        ..Default::default()
+    })
+    .unwrap();
+    for i in 0..KEY_NUM {
+        engine
+            .put(format!("read-{}", i), format!("value-{}", i).into_bytes())
+            .unwrap();
+    }
+
+    let stop = AtomicBool::new(false);
+    let reads = AtomicU64::new(0);
+    let writes = AtomicU64::new(0);
+    thread::scope(|s| {
+        for t in 0..readers {
+            let (engine, stop, reads) = (&engine, &stop, &reads);
+            s.spawn(move || {
+                let mut seed = t as u64 * 2 + 1;
+                let mut count = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    // xorshift
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    engine.read(format!("read-{}", seed % KEY_NUM)).unwrap();
+                    count += 1;
+                }
+                reads.fetch_add(count, Ordering::Relaxed);
+            });
+        }
+        let (engine, stop, writes) = (&engine, &stop, &writes);
+        s.spawn(move || {
+            let mut count = 0;
+            while !stop.load(Ordering::Relaxed) {
+                engine
+                    .put(format!("write-{}", count % KEY_NUM), vec![0; 64])
+                    .unwrap();
+                count += 1;
+            }
+            writes.fetch_add(count, Ordering::Relaxed);
+        });
+
+        thread::sleep(duration);
+        stop.store(true, Ordering::Relaxed);
+    });
+    drop(engine);
+    let _ = fs::remove_dir_all(&dir);
+    (reads.into_inner(), writes.into_inner())
+}
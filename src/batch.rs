@@ -0,0 +1,179 @@
+use crate::data::entry::Entry;
+use crate::db::Engine;
+use crate::error::R;
+use std::sync::atomic::Ordering;
+
+/// 一组 put 和 delete，通过 Engine::write_batch 原子提交，要么全部生效，要么全部不生效
+#[derive(Default)]
+pub struct WriteBatch {
+    entries: Vec<Entry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> R<()> {
+        self.entries.push(Entry::new(key.as_ref().to_vec(), value)?);
+        Ok(())
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> R<()> {
+        self.entries
+            .push(Entry::get_tombstone_with_given_key(key.as_ref().to_vec())?);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Engine {
+    /// 原子提交 batch, 同一个 key 多次写入时以最后一次为准，有 key 超过索引的大小限制时什么都不写入。
+    /// batch 中的 entry 带上同一个序列号，与提交标记一起一次性写入 active file，
+    /// 启动时只有读到提交标记的 batch 才会生效
+    pub fn write_batch(&self, batch: WriteBatch) -> R<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        for entry in &batch.entries {
+            self.check_key_size(entry.k())?;
+        }
+        let entries = self.seal_batch(batch.entries);
+        self.append_entries_to_active_file(&entries)?;
+        Ok(())
+    }
+
+    /// 给 entry 分配同一个 batch 序列号并追加提交标记
+    pub(crate) fn seal_batch(&self, mut entries: Vec<Entry>) -> Vec<Entry> {
+        let batch_seq = self.batch_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        for entry in entries.iter_mut() {
+            entry.set_batch_seq(batch_seq);
+        }
+        entries.push(Entry::get_batch_finished(batch_seq));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{get_default_options, open_engine};
+    use crate::error::E::Nil;
+
+    #[test]
+    fn test_write_batch() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("deleted", "0".as_bytes().to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("record", "1".as_bytes().to_vec()).unwrap();
+        batch.put("index-a", "record".as_bytes().to_vec()).unwrap();
+        batch.put("index-b", "record".as_bytes().to_vec()).unwrap();
+        batch
+            .put("index-b", "record-2".as_bytes().to_vec())
+            .unwrap();
+        batch.delete("deleted").unwrap();
+        assert_eq!(batch.len(), 5);
+        assert!(batch.put("", vec![1]).is_err());
+        engine.write_batch(batch).unwrap();
+        engine.write_batch(WriteBatch::new()).unwrap();
+
+        let check = |engine: &Engine| {
+            assert_eq!(engine.read("record").unwrap(), "1".as_bytes());
+            assert_eq!(engine.read("index-a").unwrap(), "record".as_bytes());
+            assert_eq!(engine.read("index-b").unwrap(), "record-2".as_bytes());
+            assert!(matches!(engine.read("deleted"), Err(Nil)));
+        };
+        check(&engine);
+        drop(engine);
+
+        let engine = open_engine(options);
+        check(&engine);
+        assert_eq!(engine.batch_seq.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_uncommitted_batch() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("record", "0".as_bytes().to_vec()).unwrap();
+
+        // 模拟崩溃: batch 的 entry 已经写入，提交标记没有写入
+        let mut entries = vec![
+            Entry::new("record".as_bytes().to_vec(), "1".as_bytes().to_vec()).unwrap(),
+            Entry::new("index".as_bytes().to_vec(), "record".as_bytes().to_vec()).unwrap(),
+        ];
+        for entry in entries.iter_mut() {
+            entry.set_batch_seq(5);
+        }
+        engine.append_entries_to_active_file(&entries).unwrap();
+        engine.put("after", "2".as_bytes().to_vec()).unwrap();
+        drop(engine);
+
+        let engine = open_engine(options.clone());
+        assert_eq!(engine.read("record").unwrap(), "0".as_bytes());
+        assert!(matches!(engine.read("index"), Err(Nil)));
+        assert_eq!(engine.read("after").unwrap(), "2".as_bytes());
+        let dead_bytes: usize = entries.iter().map(|e| e.get_self_size()).sum();
+        assert!(engine.file_stats()[0].dead_bytes >= dead_bytes);
+
+        // 新的 batch 不能复用未提交的序列号，否则会让旧的 entry 生效
+        let mut batch = WriteBatch::new();
+        batch.put("other", "3".as_bytes().to_vec()).unwrap();
+        engine.write_batch(batch).unwrap();
+        assert_eq!(engine.batch_seq.load(Ordering::SeqCst), 6);
+        drop(engine);
+
+        let engine = open_engine(options);
+        assert_eq!(engine.read("record").unwrap(), "0".as_bytes());
+        assert!(matches!(engine.read("index"), Err(Nil)));
+        assert_eq!(engine.read("other").unwrap(), "3".as_bytes());
+    }
+
+    #[test]
+    fn test_write_batch_with_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for i in 0..10 {
+            let mut batch = WriteBatch::new();
+            batch
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+            batch
+                .put(format!("index-{}", i), format!("key-{}", i).into_bytes())
+                .unwrap();
+            engine.write_batch(batch).unwrap();
+        }
+        assert!(engine.active_file.read().file_id() > 0);
+        engine.merge().unwrap();
+        drop(engine);
+
+        // 不使用 hint file，merged data file 中的 entry 不依赖 batch 的提交标记
+        for entry in std::fs::read_dir(&options.dir_path).unwrap().flatten() {
+            if entry.path().extension().is_some_and(|ext| ext == "hint") {
+                std::fs::remove_file(entry.path()).unwrap();
+            }
+        }
+        let engine = open_engine(options);
+        for i in 0..10 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                format!("value-{}", i).into_bytes()
+            );
+            assert_eq!(
+                engine.read(format!("index-{}", i)).unwrap(),
+                format!("key-{}", i).into_bytes()
+            );
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use bitcask_rs::options::Options;
+use bitcask_rs::repair::repair;
+use std::process::ExitCode;
+
+/// 离线修复数据目录: bitcask-repair <dir_path>
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let dir_path = match std::env::args().nth(1) {
+        Some(dir_path) => dir_path,
+        None => {
+            eprintln!("usage: bitcask-repair <dir_path>");
+            return ExitCode::from(2);
+        }
+    };
+
+    let opts = Options {
+        dir_path,
+        ..Default::default()
+    };
+    match repair(&opts) {
+        Ok(report) => {
+            println!("{}", report);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("repair failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
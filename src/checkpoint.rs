@@ -0,0 +1,526 @@
+use crate::data::datafile::DataFile;
+use crate::data::file_stat::FileStat;
+use crate::data::meta_data::MetaData;
+use crate::db::{data_files_checksum, Engine};
+use crate::error::E::Failed2WriteIndexFile;
+use crate::error::R;
+use crate::options::{IndexType, IteratorOptions};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use log::{error, warn};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+
+/// 索引检查点保存在数据目录下的这个文件中
+pub const CHECKPOINT_FILE_NAME: &str = "index.ckpt";
+
+/// 检查点先写到这个文件，落盘后再重命名
+const CHECKPOINT_TMP_FILE_NAME: &str = "index.ckpt.tmp";
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"BCKP";
+
+const CHECKPOINT_VERSION: u32 = 2;
+
+/// 某一时刻的整个内存索引以及它覆盖到的位置: id 小于 file_id 的 data file 全部覆盖，
+/// file_id 覆盖到 offset。disk 上的表示形式
+/// magic-version-file_id-offset-older_files_checksum-tail_checksum-max_batch_seq-file_stats-keys-crc，
+/// 整数都是定长小端序，crc 覆盖之前的所有字节
+#[derive(Debug, PartialEq)]
+pub(crate) struct Checkpoint {
+    pub(crate) file_id: u32,
+    pub(crate) offset: usize,
+
+    /// id 小于 file_id 的 data file 的校验和，merge 或者修复改变了这些文件时检查点失效
+    pub(crate) older_files_checksum: u32,
+
+    /// file_id 的前 offset 个字节的末尾内容的校验和，见 DataFile::tail_checksum
+    pub(crate) tail_checksum: u32,
+
+    /// 最近一次 WriteBatch 使用的序列号
+    pub(crate) max_batch_seq: u64,
+
+    /// 每个 data file 的空间使用情况，file_id 只统计到 offset
+    pub(crate) file_stats: Vec<FileStat>,
+
+    pub(crate) items: Vec<(Vec<u8>, MetaData)>,
+}
+
+impl Checkpoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(CHECKPOINT_MAGIC);
+        buf.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.file_id.to_le_bytes());
+        buf.extend_from_slice(&(self.offset as u64).to_le_bytes());
+        buf.extend_from_slice(&self.older_files_checksum.to_le_bytes());
+        buf.extend_from_slice(&self.tail_checksum.to_le_bytes());
+        buf.extend_from_slice(&self.max_batch_seq.to_le_bytes());
+        buf.extend_from_slice(&(self.file_stats.len() as u32).to_le_bytes());
+        for file_stat in &self.file_stats {
+            buf.extend_from_slice(&file_stat.file_id.to_le_bytes());
+            buf.extend_from_slice(&(file_stat.total_bytes as u64).to_le_bytes());
+            buf.extend_from_slice(&(file_stat.dead_bytes as u64).to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.items.len() as u64).to_le_bytes());
+        for (key, meta_data) in &self.items {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&meta_data.file_id.to_le_bytes());
+            buf.extend_from_slice(&(meta_data.entry_sz as u64).to_le_bytes());
+            buf.extend_from_slice(&(meta_data.entry_start_pos as u64).to_le_bytes());
+            buf.extend_from_slice(&meta_data.tstamp.to_le_bytes());
+        }
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// 校验失败或者格式不对时返回 None
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (content, crc) = buf.split_at_checked(buf.len().checked_sub(4)?)?;
+        if Crc::<u32>::new(&CRC_32_ISO_HDLC)
+            .checksum(content)
+            .to_le_bytes()
+            != crc
+        {
+            return None;
+        }
+        let mut r = Reader {
+            buf: content,
+            pos: 0,
+        };
+        if r.bytes(4)? != CHECKPOINT_MAGIC || r.u32()? != CHECKPOINT_VERSION {
+            return None;
+        }
+        let file_id = r.u32()?;
+        let offset = r.u64()? as usize;
+        let older_files_checksum = r.u32()?;
+        let tail_checksum = r.u32()?;
+        let max_batch_seq = r.u64()?;
+        let mut file_stats = Vec::new();
+        for _ in 0..r.u32()? {
+            file_stats.push(FileStat {
+                file_id: r.u32()?,
+                total_bytes: r.u64()? as usize,
+                dead_bytes: r.u64()? as usize,
+            });
+        }
+        let mut items = Vec::new();
+        for _ in 0..r.u64()? {
+            let ksz = r.u32()? as usize;
+            let key = r.bytes(ksz)?.to_vec();
+            let meta_data = MetaData::new(r.u32()?, r.u64()? as usize, r.u64()? as usize, r.u64()?);
+            items.push((key, meta_data));
+        }
+        if r.pos != content.len() {
+            return None;
+        }
+        Some(Self {
+            file_id,
+            offset,
+            older_files_checksum,
+            tail_checksum,
+            max_batch_seq,
+            file_stats,
+            items,
+        })
+    }
+
+    /// 读取数据目录中的检查点，不存在或者校验失败时返回 None
+    pub(crate) fn load(dir_path: &str) -> Option<Self> {
+        let buf = match fs::read(Path::new(dir_path).join(CHECKPOINT_FILE_NAME)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("failed to read index checkpoint, {}", e);
+                return None;
+            }
+        };
+        let checkpoint = Self::decode(&buf);
+        if checkpoint.is_none() {
+            warn!("index checkpoint is corrupted, fall back to scanning data files");
+        }
+        checkpoint
+    }
+
+    /// 检查点覆盖的 data file 没有变化时才能使用，data_files 按 file id 排序，读取失败时视为不可用
+    pub(crate) fn is_valid_for(&self, data_files: &[DataFile]) -> bool {
+        let older_files = data_files.iter().filter(|f| f.file_id() < self.file_id);
+        if data_files_checksum(older_files).ok() != Some(self.older_files_checksum) {
+            return false;
+        }
+        data_files.iter().any(|f| {
+            f.file_id() == self.file_id
+                && f.next_write_begin_pos() >= self.offset
+                && f.tail_checksum(self.offset).ok() == Some(self.tail_checksum)
+        })
+    }
+
+    /// 先写入临时文件，落盘后再重命名，中途崩溃不会破坏已有的检查点
+    fn write(&self, dir_path: &str) -> R<()> {
+        let dir = Path::new(dir_path);
+        let tmp_path = dir.join(CHECKPOINT_TMP_FILE_NAME);
+        let res = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(&self.encode())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, dir.join(CHECKPOINT_FILE_NAME)));
+        if let Err(e) = res {
+            error!("failed to write index checkpoint, {}", e);
+            return Err(Failed2WriteIndexFile);
+        }
+        Ok(())
+    }
+}
+
+/// 从字节数组中依次读取定长小端序整数
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/// 删除检查点，merge 和修复改变 older file 之前调用
+pub(crate) fn remove_checkpoint_file(dir_path: &str) -> R<()> {
+    match fs::remove_file(Path::new(dir_path).join(CHECKPOINT_FILE_NAME)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            error!("failed to remove index checkpoint, {}", e);
+            Err(Failed2WriteIndexFile)
+        }
+    }
+}
+
+impl Engine {
+    /// 把整个内存索引和它覆盖到的位置写入检查点，下次启动时只需要重放之后写入的数据。
+    /// 写入者只在复制索引期间被阻塞，BPlusTree 索引本身保存在磁盘上，不需要检查点
+    pub fn checkpoint(&self) -> R<()> {
+        if matches!(self.options.index_type, IndexType::BPlusTree) {
+            return Ok(());
+        }
+
+        // 与 merge 互斥，避免 merge 删除检查点之后又写入旧的检查点
+        let _checkpoint_guard = self.checkpoint_lock.lock();
+        let checkpoint = {
+            // 加锁顺序与写入一致，持有 active file 的可升级读锁保证没有写到一半的 batch
+            let active_file = self.active_file.upgradable_read();
+            let older_files = self.older_files.read();
+            let mem_index = self.mem_index.read();
+            let file_stats = self.file_stats.read();
+
+            let mut items = Vec::with_capacity(mem_index.key_num());
+            let mut index_iter = mem_index.iterator(&IteratorOptions::default());
+            while let Some(item) = index_iter.next() {
+                let (key, meta_data) = item?;
+                items.push((key.to_vec(), *meta_data));
+            }
+            let mut file_stats: Vec<FileStat> = file_stats.values().copied().collect();
+            file_stats.sort_by_key(|file_stat| file_stat.file_id);
+            // 检查点覆盖的数据必须先落盘，否则崩溃后检查点可能指向丢失的 entry
+            active_file.sync()?;
+            let offset = active_file.next_write_begin_pos();
+            Checkpoint {
+                file_id: active_file.file_id(),
+                offset,
+                older_files_checksum: data_files_checksum(older_files.values())?,
+                tail_checksum: active_file.tail_checksum(offset)?,
+                max_batch_seq: self.batch_seq.load(Ordering::SeqCst),
+                file_stats,
+                items,
+            }
+        };
+        checkpoint.write(&self.options.dir_path)
+    }
+}
+
+/// 后台检查点线程，按 Options::checkpoint_interval 定期写入检查点
+pub(crate) struct CheckpointWorker {
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CheckpointWorker {
+    /// 没有设置 checkpoint_interval 时不启动线程
+    pub(crate) fn start(engine: &Engine) -> Self {
+        let interval = match engine.options.checkpoint_interval {
+            Some(interval) => interval,
+            None => {
+                return Self {
+                    stop_sender: None,
+                    handle: None,
+                }
+            }
+        };
+
+        let engine = engine.share();
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("bitcask-checkpoint".to_string())
+            .spawn(move || {
+                // 收到停止信号或者 sender 已经被 drop 时退出
+                while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    if let Err(e) = engine.checkpoint() {
+                        warn!("background checkpoint failed, {}", e);
+                    }
+                }
+            });
+        match handle {
+            Ok(handle) => Self {
+                stop_sender: Some(stop_sender),
+                handle: Some(handle),
+            },
+            Err(e) => {
+                error!("failed to start background checkpoint, {}", e);
+                Self {
+                    stop_sender: None,
+                    handle: None,
+                }
+            }
+        }
+    }
+
+    /// 停止后台线程并等待正在写入的检查点结束
+    pub(crate) fn stop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatch;
+    use crate::db::tests::{get_default_options, index_types, open_engine};
+    use crate::error::E::Nil;
+
+    fn checkpoint_path(dir_path: &str) -> std::path::PathBuf {
+        Path::new(dir_path).join(CHECKPOINT_FILE_NAME)
+    }
+
+    #[test]
+    fn test_checkpoint_encode_decode() {
+        let checkpoint = Checkpoint {
+            file_id: 3,
+            offset: 1024,
+            older_files_checksum: 42,
+            tail_checksum: 43,
+            max_batch_seq: 7,
+            file_stats: vec![FileStat {
+                file_id: 0,
+                total_bytes: 100,
+                dead_bytes: 30,
+            }],
+            items: vec![
+                ("a".as_bytes().to_vec(), MetaData::new(0, 10, 8, 1)),
+                ("b".as_bytes().to_vec(), MetaData::new(3, 20, 18, 2)),
+            ],
+        };
+        let mut buf = checkpoint.encode();
+        assert_eq!(Checkpoint::decode(&buf), Some(checkpoint));
+
+        // 任意一个字节损坏或者被截断都不能通过校验
+        buf[20] ^= 0xff;
+        assert_eq!(Checkpoint::decode(&buf), None);
+        buf[20] ^= 0xff;
+        assert_eq!(Checkpoint::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(Checkpoint::decode(&[]), None);
+    }
+
+    #[test]
+    fn test_checkpoint_replay() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.file_threshold = 256;
+            options.index_type = index_type.clone();
+            let engine = open_engine(options.clone());
+            for i in 0..30 {
+                engine
+                    .put(format!("key-{:02}", i), format!("value-{}", i).into_bytes())
+                    .unwrap();
+            }
+            engine.checkpoint().unwrap();
+
+            // 检查点之后的写入跨越了多个文件，重启时重放
+            for i in 0..30 {
+                match i % 3 {
+                    0 => {
+                        engine.delete(format!("key-{:02}", i)).unwrap();
+                    }
+                    1 => engine
+                        .put(
+                            format!("key-{:02}", i),
+                            format!("new-value-{}", i).into_bytes(),
+                        )
+                        .unwrap(),
+                    _ => {}
+                }
+            }
+            let mut batch = WriteBatch::new();
+            batch.put("batch", "1".as_bytes().to_vec()).unwrap();
+            batch.delete("key-02").unwrap();
+            engine.write_batch(batch).unwrap();
+            let file_stats = engine.file_stats();
+            drop(engine);
+
+            let check = |options: &crate::options::Options| {
+                let engine = open_engine(options.clone());
+                for i in 0..30 {
+                    let v = engine.read(format!("key-{:02}", i));
+                    match i {
+                        2 => assert!(matches!(v, Err(Nil))),
+                        _ if i % 3 == 0 => assert!(matches!(v, Err(Nil)), "{:?}", index_type),
+                        _ if i % 3 == 1 => {
+                            assert_eq!(v.unwrap(), format!("new-value-{}", i).into_bytes())
+                        }
+                        _ => assert_eq!(v.unwrap(), format!("value-{}", i).into_bytes()),
+                    }
+                }
+                assert_eq!(engine.read("batch").unwrap(), "1".as_bytes());
+                assert_eq!(engine.file_stats(), file_stats, "{:?}", index_type);
+            };
+            if !matches!(index_type, IndexType::BPlusTree) {
+                assert!(Checkpoint::load(&options.dir_path).is_some());
+            }
+            check(&options);
+
+            // 检查点损坏时退回到扫描全部 data file，结果相同
+            if !matches!(index_type, IndexType::BPlusTree) {
+                let path = checkpoint_path(&options.dir_path);
+                let mut buf = fs::read(&path).unwrap();
+                let last = buf.len() - 1;
+                buf[last] ^= 0xff;
+                fs::write(&path, buf).unwrap();
+                assert!(Checkpoint::load(&options.dir_path).is_none());
+            }
+            check(&options);
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_on_close() {
+        let mut options = get_default_options();
+        options.checkpoint_on_close = true;
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        drop(engine);
+        let checkpoint = Checkpoint::load(&options.dir_path).unwrap();
+        assert_eq!(checkpoint.items.len(), 1);
+
+        // 检查点覆盖了所有数据，重启后不需要重放
+        let engine = open_engine(options.clone());
+        assert_eq!(engine.read("a").unwrap(), "1".as_bytes());
+        engine.put("b", "2".as_bytes().to_vec()).unwrap();
+        drop(engine);
+        let engine = open_engine(options.clone());
+        assert_eq!(engine.read("b").unwrap(), "2".as_bytes());
+        assert_eq!(Checkpoint::load(&options.dir_path).unwrap().items.len(), 2);
+        drop(engine);
+    }
+
+    #[test]
+    fn test_checkpoint_invalidated_by_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for i in 0..30 {
+            engine
+                .put(format!("key-{:02}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        for i in 0..30 {
+            engine.delete(format!("key-{:02}", i)).unwrap();
+        }
+        engine.put("kept", "1".as_bytes().to_vec()).unwrap();
+        engine.checkpoint().unwrap();
+        assert!(checkpoint_path(&options.dir_path).is_file());
+
+        // merge 替换了检查点引用的 older file
+        engine.merge().unwrap();
+        assert!(!checkpoint_path(&options.dir_path).exists());
+        drop(engine);
+
+        let engine = open_engine(options.clone());
+        assert_eq!(engine.read("kept").unwrap(), "1".as_bytes());
+        assert!(matches!(engine.read("key-00"), Err(Nil)));
+    }
+
+    #[test]
+    fn test_checkpoint_invalidated_by_rewrite() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.index_type = index_type.clone();
+            let engine = open_engine(options.clone());
+            engine.put("a", "1".as_bytes().to_vec()).unwrap();
+            engine.put("b", "2".as_bytes().to_vec()).unwrap();
+            drop(engine);
+
+            // 用长度相同、内容不同的文件替换检查点和保存的索引覆盖的 data file
+            let mut other_options = get_default_options();
+            other_options.index_type = index_type.clone();
+            let other = open_engine(other_options.clone());
+            other.put("b", "3".as_bytes().to_vec()).unwrap();
+            other.put("a", "1".as_bytes().to_vec()).unwrap();
+            drop(other);
+            let data_file_path =
+                |dir_path: &str| DataFile::get_file_full_path(dir_path.to_string(), 0.to_string());
+            let buf = fs::read(data_file_path(&other_options.dir_path)).unwrap();
+            assert_eq!(
+                fs::metadata(data_file_path(&options.dir_path))
+                    .unwrap()
+                    .len(),
+                buf.len() as u64
+            );
+            fs::write(data_file_path(&options.dir_path), buf).unwrap();
+
+            let engine = open_engine(options.clone());
+            assert_eq!(
+                engine.read("a").unwrap(),
+                "1".as_bytes(),
+                "{:?}",
+                index_type
+            );
+            assert_eq!(
+                engine.read("b").unwrap(),
+                "3".as_bytes(),
+                "{:?}",
+                index_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_background_checkpoint() {
+        let mut options = get_default_options();
+        options.checkpoint_interval = Some(std::time::Duration::from_millis(10));
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while Checkpoint::load(&options.dir_path).is_none_or(|c| c.items.is_empty()) {
+            assert!(std::time::Instant::now() < deadline);
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        drop(engine);
+    }
+}
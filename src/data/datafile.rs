@@ -1,22 +1,40 @@
+use crate::data::entry::Entry;
 use crate::data::entry_with_meta_data::EntryWithMetaData;
-use crate::error::E::{CanNotOpenOrCreateDateFile, CanNotWriteOldFile};
-use crate::error::R;
+use crate::data::meta_data::MetaData;
+use crate::error::E::{
+    CanNotOpenOrCreateDateFile, CanNotWriteOldFile, DataCorrupted, Failed2Write2DataFile,
+    UnknownDataFileFormat, UnsupportedDataFileVersion,
+};
+use crate::error::{E, R};
 use crate::fio::file_io::FileIO;
-use crate::fio::IOManager;
+use crate::fio::{new_io_manager, IOManager};
+use crate::options::IOType;
+use crc::{Crc, CRC_32_ISO_HDLC};
 use log::error;
 use parking_lot::RwLock;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Error;
 use std::mem;
-use std::os::windows::fs::FileExt;
-use std::os::windows::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub const DATA_FILE_SUFFIX: &str = ".bck";
 const UNIX_FILE_SPLITTER: &str = "/";
 
+/// 每个 data file 开头的文件头 magic-version，version 是 u32 小端序
+/// 文件格式变化时增加 version，打开时据此识别不兼容的文件
+pub const DATA_FILE_MAGIC: &[u8; 4] = b"BCSK";
+pub const DATA_FILE_VERSION: u32 = 4;
+
+/// 仍然可以读取的最低版本，每个旧版本的 entry 格式见 Entry::decode_with_version。
+/// 旧版本的文件只读，merge 时按当前格式重写
+pub const MIN_DATA_FILE_VERSION: u32 = 1;
+pub const DATA_FILE_HEADER_SIZE: usize = DATA_FILE_MAGIC.len() + mem::size_of::<u32>();
+
+/// tail_checksum 覆盖的字节数
+const TAIL_CHECKSUM_SIZE: usize = 4096;
+
 /// older file 和 active file 的抽象
 /// 即 DataFile 既可以表示 older file，也可以表示 active file
 pub struct DataFile {
@@ -32,6 +50,9 @@ pub struct DataFile {
 
     /// 文件类型
     file_type: DataFileType,
+
+    /// 文件头中的格式版本
+    version: u32,
 }
 
 pub enum DataFileType {
@@ -48,14 +69,17 @@ impl DataFile {
         let full_path = Self::get_file_full_path(dir_path, file_id.to_string());
         match Self::get_file(true, true, &full_path) {
             Ok(file) => {
-                let nwbp = Arc::new(RwLock::new(0));
+                // 文件可能已经存在，下次写的位置是当前文件大小
+                let file_len = file.metadata().unwrap().len() as usize;
                 let file_type = DataFileType::ACTIVE;
                 let io_manager = Box::new(FileIO::new(file)) as Box<dyn IOManager>;
+                let (nwbp, version) = Self::init_header(io_manager.as_ref(), file_len)?;
                 Ok(Self {
                     file_full_path: full_path.display().to_string(),
-                    next_write_begin_pos: nwbp,
+                    next_write_begin_pos: Arc::new(RwLock::new(nwbp)),
                     io_manager,
                     file_type,
+                    version,
                 })
             }
             Err(e) => {
@@ -66,19 +90,28 @@ impl DataFile {
         }
     }
 
-    /// 专用于根据已存在的 file 去创建 DataFile
-    pub fn create_from_full_path(full_path: String, file_type: DataFileType) -> R<Self> {
+    /// 专用于根据已存在的 file 去创建 DataFile, io_type 决定读取该文件使用的 IO 方式
+    pub fn create_from_full_path(
+        full_path: String,
+        file_type: DataFileType,
+        io_type: IOType,
+    ) -> R<Self> {
         match Self::get_file(true, true, &Path::new(full_path.as_str()).to_path_buf()) {
             Ok(file) => {
                 // 已存在的文件的下次写的位置是当前文件大小，即从末尾开始写
-                let nwbp = Arc::new(RwLock::new(file.metadata().unwrap().file_size() as usize));
-                let file_type = file_type;
-                let io_manager = Box::new(FileIO::new(file)) as Box<dyn IOManager>;
+                let file_len = file.metadata().unwrap().len() as usize;
+                let file_io = FileIO::new(file);
+                let (nwbp, version) = Self::init_header(&file_io, file_len)?;
+                let io_manager = match io_type {
+                    IOType::StandardFIO => Box::new(file_io) as Box<dyn IOManager>,
+                    IOType::MemoryMap => new_io_manager(&full_path, io_type)?,
+                };
                 Ok(Self {
                     file_full_path: full_path.to_string(),
-                    next_write_begin_pos: nwbp,
+                    next_write_begin_pos: Arc::new(RwLock::new(nwbp)),
                     io_manager,
                     file_type,
+                    version,
                 })
             }
             Err(e) => {
@@ -89,6 +122,30 @@ impl DataFile {
         }
     }
 
+    /// 空文件写入文件头并落盘，非空文件校验文件头，返回下次写的位置和文件的版本
+    fn init_header(io_manager: &dyn IOManager, file_len: usize) -> R<(usize, u32)> {
+        if file_len == 0 {
+            let mut header = Vec::with_capacity(DATA_FILE_HEADER_SIZE);
+            header.extend(DATA_FILE_MAGIC);
+            header.extend(&DATA_FILE_VERSION.to_le_bytes());
+            io_manager.append(&header)?;
+            io_manager.sync()?;
+            return Ok((DATA_FILE_HEADER_SIZE, DATA_FILE_VERSION));
+        }
+
+        let mut header = vec![0; DATA_FILE_HEADER_SIZE];
+        let bytes_read = io_manager.read(&mut header, 0)?;
+        if bytes_read < DATA_FILE_HEADER_SIZE || &header[..DATA_FILE_MAGIC.len()] != DATA_FILE_MAGIC
+        {
+            return Err(UnknownDataFileFormat);
+        }
+        let version = u32::from_le_bytes(header[DATA_FILE_MAGIC.len()..].try_into().unwrap());
+        if !(MIN_DATA_FILE_VERSION..=DATA_FILE_VERSION).contains(&version) {
+            return Err(UnsupportedDataFileVersion(version));
+        }
+        Ok((file_len, version))
+    }
+
     /// 不存在则以读写模式创建然后返回，已存在以读写模式直接返回
     fn get_file(readable: bool, appendable: bool, full_path: &PathBuf) -> Result<File, Error> {
         let mut open_options = OpenOptions::new();
@@ -101,10 +158,9 @@ impl DataFile {
         }
     }
 
-    fn get_file_full_path(dir_path: String, file_id: String) -> PathBuf {
+    pub fn get_file_full_path(dir_path: String, file_id: String) -> PathBuf {
         let full_path = dir_path + UNIX_FILE_SPLITTER + &file_id + DATA_FILE_SUFFIX;
-        let path_buf = PathBuf::from(full_path);
-        path_buf
+        PathBuf::from(full_path)
     }
 
     pub fn append(&self, buf: Vec<u8>) -> R<usize> {
@@ -129,61 +185,308 @@ impl DataFile {
         self.io_manager.sync()
     }
 
+    /// 前 len 个字节中最后 TAIL_CHECKSUM_SIZE 个字节的 crc，其中包含最后几个 entry 的 crc，
+    /// 用来发现长度不变但是内容被改写的文件
+    pub fn tail_checksum(&self, len: usize) -> R<u32> {
+        let begin = len.saturating_sub(TAIL_CHECKSUM_SIZE);
+        let mut buf = vec![0; len - begin];
+        if self.read_with_given_pos(begin, &mut buf)? < buf.len() {
+            return Err(self.corrupted_at(begin));
+        }
+        Ok(Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&buf))
+    }
+
+    /// 文件头中的格式版本，低于 DATA_FILE_VERSION 的文件不能追加写
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
     pub fn file_id(&self) -> u32 {
         let file_name_with_suffix = Path::new(&self.file_full_path).file_name().unwrap();
         let file_name_with_suffix = file_name_with_suffix.to_str().unwrap();
         let file_name =
             &file_name_with_suffix[0..file_name_with_suffix.len() - DATA_FILE_SUFFIX.len()];
-        u32::from_str_radix(file_name, 10).unwrap()
+        file_name.parse::<u32>().unwrap()
     }
 
-    pub fn read_with_given_pos(&self, pos: usize, buf: &mut Vec<u8>) -> R<usize> {
-        let file = File::open(&self.file_full_path).unwrap();
-        Ok(file.seek_read(buf, pos as u64).unwrap())
+    pub fn read_with_given_pos(&self, pos: usize, buf: &mut [u8]) -> R<usize> {
+        self.io_manager.read(buf, pos as u64)
     }
 
     pub fn set_filetype(&mut self, t: DataFileType) {
         self.file_type = t;
     }
 
+    /// 切换读写文件使用的 IO 方式，active file 必须使用 StandardFIO 才能追加写
+    pub fn set_io_manager(&mut self, io_type: IOType) -> R<()> {
+        self.io_manager = new_io_manager(&self.file_full_path, io_type)?;
+        Ok(())
+    }
+
+    /// 读取 pos 处长度为 entry_sz 的 entry 并校验 crc
+    pub fn read_entry(&self, pos: usize, entry_sz: usize) -> R<Entry> {
+        let mut buf = vec![0; entry_sz];
+        let bytes_read = self.read_with_given_pos(pos, &mut buf)?;
+        if bytes_read < entry_sz {
+            return Err(self.corrupted_at(pos));
+        }
+        Entry::decode_with_version(buf, self.version).ok_or_else(|| self.corrupted_at(pos))
+    }
+
+    fn corrupted_at(&self, offset: usize) -> E {
+        let file_id = self.file_id();
+        error!("data corrupted, file id {}, offset {}", file_id, offset);
+        DataCorrupted { file_id, offset }
+    }
+
+    /// 顺序扫描文件中的所有 entry, 每个 entry 都会校验 crc，用于 older file。
+    /// older file 不会有写了一半的 entry，不完整或者校验失败的 entry 都返回 DataCorrupted，
+    /// 否则之后的 entry (包括 tombstone) 会被丢弃，已经删除的 key 重新出现
     pub fn get_all_entries_with_metadata(&self) -> R<Vec<EntryWithMetaData>> {
-        let mut entries_with_metadata = Vec::new();
-        let file = File::open(&self.file_full_path).unwrap();
+        self.get_all_entries_with_metadata_from(DATA_FILE_HEADER_SIZE)
+    }
+
+    /// 从 pos 开始扫描，pos 必须是某个 entry 的起始位置
+    pub fn get_all_entries_with_metadata_from(&self, pos: usize) -> R<Vec<EntryWithMetaData>> {
+        let (entries_with_metadata, _) = self.scan_entries(pos, false)?;
+        Ok(entries_with_metadata)
+    }
+
+    /// 用于崩溃后的 active file, 遇到不完整或者校验失败的 entry 时停止扫描
+    /// 返回之前所有完好的 entry 以及最后一个完好 entry 的结束位置，之后的数据都不可信
+    pub fn get_valid_entries_with_metadata(&self) -> R<(Vec<EntryWithMetaData>, usize)> {
+        self.get_valid_entries_with_metadata_from(DATA_FILE_HEADER_SIZE)
+    }
 
-        let mut size = 0;
-        let usize_bytes = mem::size_of::<usize>()
-        size += mem::size_of::<u32>(); // crc
-        size += mem::size_of::<u64>(); // tstamp
-        size += mem::size_of::<usize>(); // ksz
-        size += mem::size_of::<usize>(); // value_sz
+    /// 从 pos 开始扫描，pos 必须是某个 entry 的起始位置
+    pub fn get_valid_entries_with_metadata_from(
+        &self,
+        pos: usize,
+    ) -> R<(Vec<EntryWithMetaData>, usize)> {
+        self.scan_entries(pos, true)
+    }
+
+    fn scan_entries(
+        &self,
+        mut pos: usize,
+        stop_at_corruption: bool,
+    ) -> R<(Vec<EntryWithMetaData>, usize)> {
+        let mut entries_with_metadata = Vec::new();
+        let file_id = self.file_id();
+        let file_len = self.next_write_begin_pos();
 
-        let mut buf = vec![0; size];
-        let mut pos = 0;
+        let header_size = Entry::header_size(self.version);
+        let mut header_buf = vec![0; header_size];
         loop {
-            let bytes_read = file.seek_read(&mut buf, pos as u64).unwrap();
-            if bytes_read == 0 {
+            if pos >= file_len {
                 break;
             }
+            let bytes_read = self.read_with_given_pos(pos, &mut header_buf)?;
+            if bytes_read < header_size {
+                if stop_at_corruption {
+                    break;
+                }
+                return Err(self.corrupted_at(pos));
+            }
 
-            let entry_crc = u32::from_be_bytes(buf[0..4].try_into().unwrap());
-            if entry_crc == 0 {
-                break;
+            // 损坏的 ksz 和 value_sz 可能超出文件大小，不能据此分配内存
+            let (entry_ksz, entry_value_sz) =
+                Entry::decode_sizes_with_version(&header_buf, self.version);
+            let entry_len = header_size + entry_ksz + entry_value_sz;
+            if pos + entry_len > file_len {
+                if stop_at_corruption {
+                    break;
+                }
+                return Err(self.corrupted_at(pos));
             }
 
-            let entry_tstamp = u64::from_be_bytes(buf[4..12].try_into().unwrap());
-            let entry_ksz = u32::from_be_bytes(buf[12..16].try_into().unwrap()) as usize;
-            let entry_value_sz = u32::from_be_bytes(buf[16..20].try_into().unwrap()) as usize;
-            
+            let entry = match self.read_entry(pos, entry_len) {
+                Ok(entry) => entry,
+                Err(DataCorrupted { .. }) if stop_at_corruption => break,
+                Err(e) => return Err(e),
+            };
+            let meta_data = MetaData::new(file_id, entry_len, pos, entry.tstamp());
+            entries_with_metadata.push(EntryWithMetaData::new(entry, meta_data));
+            pos += entry_len;
+        }
+        Ok((entries_with_metadata, pos))
+    }
+
+    /// 将文件截断到 len 并刷盘，用于丢弃崩溃时写了一半的 entry
+    pub fn truncate(&self, len: usize) -> R<()> {
+        let mut write_begin_pos = self.next_write_begin_pos.write();
+        let res = OpenOptions::new()
+            .write(true)
+            .open(&self.file_full_path)
+            .and_then(|file| {
+                file.set_len(len as u64)?;
+                file.sync_all()
+            });
+        if let Err(e) = res {
+            error!(
+                "failed to truncate data file {}, {}",
+                self.file_full_path, e
+            );
+            return Err(Failed2Write2DataFile);
+        }
+        *write_begin_pos = len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn tmp_dir_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "bitcask-rs-datafile-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.display().to_string()
+    }
+
+    #[test]
+    fn test_new_file_header() {
+        let dir_path = tmp_dir_path("header");
+        let data_file = DataFile::new(dir_path.clone(), 0).unwrap();
+        assert_eq!(data_file.next_write_begin_pos(), DATA_FILE_HEADER_SIZE);
 
+        let entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        data_file.append(entry.encode()).unwrap();
+        data_file.sync().unwrap();
 
-            let entry_len = u32::from_be_bytes(buf.try_into().unwrap()) as usize;
-            let mut entry_buf = vec![0; entry_len];
-            let _ = file.seek_read(&mut entry_buf, (pos + 4) as u64).unwrap();
-            let entry = bincode::deserialize(&entry_buf).unwrap();
-            let meta_data = EntryWithMetaData::new(entry, pos);
-            entries_with_metadata.push(meta_data);
-            pos += entry_len + 4;
+        let full_path = DataFile::get_file_full_path(dir_path, "0".to_string());
+        let bytes = std::fs::read(&full_path).unwrap();
+        assert_eq!(&bytes[..4], DATA_FILE_MAGIC);
+        assert_eq!(&bytes[4..8], &DATA_FILE_VERSION.to_le_bytes());
+
+        for io_type in [IOType::StandardFIO, IOType::MemoryMap] {
+            let data_file = DataFile::create_from_full_path(
+                full_path.display().to_string(),
+                DataFileType::OLD,
+                io_type,
+            )
+            .unwrap();
+            let entries = data_file.get_all_entries_with_metadata().unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].entry, entry);
+            assert_eq!(entries[0].meta_data.entry_start_pos, DATA_FILE_HEADER_SIZE);
         }
-        Ok(entries_with_metadata)
+    }
+
+    #[test]
+    fn test_incompatible_file_header() {
+        let dir_path = tmp_dir_path("incompatible");
+        let full_path = DataFile::get_file_full_path(dir_path.clone(), "0".to_string());
+        std::fs::File::create(&full_path)
+            .unwrap()
+            .write_all(b"not a data file")
+            .unwrap();
+        assert!(matches!(
+            DataFile::new(dir_path.clone(), 0),
+            Err(UnknownDataFileFormat)
+        ));
+
+        let full_path = DataFile::get_file_full_path(dir_path.clone(), "1".to_string());
+        let mut header = DATA_FILE_MAGIC.to_vec();
+        header.extend(&(DATA_FILE_VERSION + 1).to_le_bytes());
+        std::fs::File::create(&full_path)
+            .unwrap()
+            .write_all(&header)
+            .unwrap();
+        assert!(matches!(
+            DataFile::create_from_full_path(
+                full_path.display().to_string(),
+                DataFileType::OLD,
+                IOType::StandardFIO
+            ),
+            Err(UnsupportedDataFileVersion(v)) if v == DATA_FILE_VERSION + 1
+        ));
+
+        let full_path = DataFile::get_file_full_path(dir_path.clone(), "2".to_string());
+        let mut header = DATA_FILE_MAGIC.to_vec();
+        header.extend(&(MIN_DATA_FILE_VERSION - 1).to_le_bytes());
+        std::fs::File::create(&full_path)
+            .unwrap()
+            .write_all(&header)
+            .unwrap();
+        assert!(matches!(
+            DataFile::new(dir_path.clone(), 2),
+            Err(UnsupportedDataFileVersion(0))
+        ));
+    }
+
+    #[test]
+    fn test_valid_entries_with_torn_tail() {
+        let dir_path = tmp_dir_path("torn");
+        let data_file = DataFile::new(dir_path.clone(), 0).unwrap();
+        let entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        data_file.append(entry.encode()).unwrap();
+        let valid_len = data_file.next_write_begin_pos();
+        // 崩溃时只写入了一部分
+        let torn = Entry::new("key".as_bytes().to_vec(), vec![4, 5, 6]).unwrap();
+        data_file.append(torn.encode()[..10].to_vec()).unwrap();
+
+        let (entries, end) = data_file.get_valid_entries_with_metadata().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(end, valid_len);
+
+        data_file.truncate(end).unwrap();
+        assert_eq!(data_file.next_write_begin_pos(), valid_len);
+        data_file.append(torn.encode()).unwrap();
+        let entries = data_file.get_all_entries_with_metadata().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].entry, torn);
+    }
+
+    #[test]
+    fn test_all_entries_with_corrupted_length() {
+        let dir_path = tmp_dir_path("corrupted-length");
+        let data_file = DataFile::new(dir_path.clone(), 0).unwrap();
+        let entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        data_file.append(entry.encode()).unwrap();
+        let corrupted_pos = data_file.next_write_begin_pos();
+        data_file.append(entry.encode()).unwrap();
+        let tombstone = Entry::get_tombstone_with_given_key("key".as_bytes().to_vec()).unwrap();
+        data_file.append(tombstone.encode()).unwrap();
+        data_file.sync().unwrap();
+
+        // 第二个 entry 的 value_sz 被改大，超出文件大小
+        let full_path = DataFile::get_file_full_path(dir_path, "0".to_string());
+        let mut bytes = std::fs::read(&full_path).unwrap();
+        bytes[corrupted_pos + Entry::HEADER_SIZE - 1] = 0x7f;
+        std::fs::write(&full_path, &bytes).unwrap();
+
+        let data_file = DataFile::create_from_full_path(
+            full_path.display().to_string(),
+            DataFileType::OLD,
+            IOType::StandardFIO,
+        )
+        .unwrap();
+        assert!(matches!(
+            data_file.get_all_entries_with_metadata(),
+            Err(DataCorrupted { file_id: 0, offset }) if offset == corrupted_pos
+        ));
+        let (entries, end) = data_file.get_valid_entries_with_metadata().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(end, corrupted_pos);
+
+        // 文件末尾不足一个 header 的字节同样是损坏
+        std::fs::write(&full_path, &bytes[..corrupted_pos + 3]).unwrap();
+        let data_file = DataFile::create_from_full_path(
+            full_path.display().to_string(),
+            DataFileType::OLD,
+            IOType::StandardFIO,
+        )
+        .unwrap();
+        assert!(matches!(
+            data_file.get_all_entries_with_metadata(),
+            Err(DataCorrupted { file_id: 0, offset }) if offset == corrupted_pos
+        ));
     }
 }
@@ -1,138 +1,304 @@
-use crate::error::E::{EmptyKey, EmptyValue};
+use crate::data::datafile::DATA_FILE_VERSION;
+use crate::error::E::{EmptyKey, EmptyValue, KeyTooLarge, ValueTooLarge};
 use crate::error::R;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use std::fmt::Display;
 use std::mem;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// disk 上的表示形式 crc-tstamp-ksz-valuesz-k-v
+/// disk 上的表示形式 crc-tstamp-batchseq-expireat-type-ksz-valuesz-k-v
+/// 所有整数都是定长小端序: crc u32, tstamp u64, batch_seq u64, expire_at u64, type u8, ksz u32, value_sz u32，
+/// 与机器字长和字节序无关。crc 覆盖 crc 之后的所有字节。
+/// 旧版本的 data file 仍然可以读取: 版本 3 没有 expire_at，视为永不过期；
+/// 版本 1 和 2 只有 crc-tstamp-ksz-valuesz，视为不属于任何 batch，版本 1 的 crc 只覆盖 v
 #[derive(Debug)]
 pub struct Entry {
     crc: u32,
     tstamp: u64,
+
+    /// 所属 WriteBatch 的序列号，0 表示不属于任何 batch
+    batch_seq: u64,
+
+    /// 过期时间，与 tstamp 一样是毫秒时间戳，0 表示永不过期
+    expire_at: u64,
+    entry_type: EntryType,
     ksz: usize,
     value_sz: usize,
-    k: String,
+
+    /// 字节数组，可以是任意二进制数据
+    k: Vec<u8>,
 
     /// 字节数组
     v: Vec<u8>,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryType {
+    /// 普通的数据 entry 和 tombstone
+    Normal = 0,
+
+    /// WriteBatch 的提交标记，k 和 v 都为空，只有看到该标记时同一 batch_seq 的 entry 才生效
+    BatchFinished = 1,
+}
+
+impl EntryType {
+    fn from_u8(t: u8) -> Option<Self> {
+        match t {
+            0 => Some(EntryType::Normal),
+            1 => Some(EntryType::BatchFinished),
+            _ => None,
+        }
+    }
+}
+
 /// 正常数据 entry 和 tombstone 区分点在于
 /// 1. tombstone 的 value_sz 是 0，
 /// 2. v 的 len 是 0，也就是没有值
 impl Entry {
-    pub fn calculate_crc_by_vec(v: &Vec<u8>) -> u32 {
-        Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&v[..])
+    /// crc + tstamp + batch_seq + expire_at + type + ksz + value_sz
+    pub const HEADER_SIZE: usize = mem::size_of::<u32>() // crc
+        + mem::size_of::<u64>() // tstamp
+        + mem::size_of::<u64>() // batch_seq
+        + mem::size_of::<u64>() // expire_at
+        + mem::size_of::<u8>() // type
+        + mem::size_of::<u32>() // ksz
+        + mem::size_of::<u32>(); // value_sz
+
+    /// 版本 1 和 2 的 header 只有 crc + tstamp + ksz + value_sz
+    pub const V1_HEADER_SIZE: usize = mem::size_of::<u32>() // crc
+        + mem::size_of::<u64>() // tstamp
+        + mem::size_of::<u32>() // ksz
+        + mem::size_of::<u32>(); // value_sz
+
+    /// 版本 3 的 header 没有 expire_at
+    pub const V3_HEADER_SIZE: usize = Self::HEADER_SIZE - mem::size_of::<u64>();
+
+    /// header 中的 ksz 和 value_sz 是 u32，k 和 v 不能超过这个长度
+    pub const MAX_KEY_SIZE: usize = u32::MAX as usize;
+    pub const MAX_VALUE_SIZE: usize = u32::MAX as usize;
+
+    /// version 版本的 data file 中 entry header 的字节数
+    pub fn header_size(version: u32) -> usize {
+        match version {
+            1 | 2 => Self::V1_HEADER_SIZE,
+            3 => Self::V3_HEADER_SIZE,
+            _ => Self::HEADER_SIZE,
+        }
     }
 
-    fn get_tstamp() -> u64 {
+    pub fn calculate_crc_by_vec(v: &[u8]) -> u32 {
+        Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(v)
+    }
+
+    pub(crate) fn get_tstamp() -> u64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis() as u64
     }
 
-    pub fn get_tombstone_with_given_key(k: String) -> R<Self> {
-        if k.len() <= 0 {
+    pub fn get_tombstone_with_given_key(k: Vec<u8>) -> R<Self> {
+        if k.is_empty() {
             return Err(EmptyKey);
         }
+
+        if k.len() > Self::MAX_KEY_SIZE {
+            return Err(KeyTooLarge(Self::MAX_KEY_SIZE));
+        }
         let v = Vec::with_capacity(0);
         Ok(Self::get_entry(k, v))
     }
 
-    pub fn new(k: String, v: Vec<u8>) -> R<Self> {
-        if k.len() <= 0 {
+    /// batch_seq 对应的 WriteBatch 的提交标记
+    pub fn get_batch_finished(batch_seq: u64) -> Self {
+        let mut entry = Self::get_entry(Vec::new(), Vec::new());
+        entry.entry_type = EntryType::BatchFinished;
+        entry.set_batch_seq(batch_seq);
+        entry
+    }
+
+    pub fn new(k: Vec<u8>, v: Vec<u8>) -> R<Self> {
+        if k.is_empty() {
             return Err(EmptyKey);
         }
 
-        if v.len() <= 0 {
+        if v.is_empty() {
             return Err(EmptyValue);
         }
+
+        if k.len() > Self::MAX_KEY_SIZE {
+            return Err(KeyTooLarge(Self::MAX_KEY_SIZE));
+        }
+
+        if v.len() > Self::MAX_VALUE_SIZE {
+            return Err(ValueTooLarge(Self::MAX_VALUE_SIZE));
+        }
         Ok(Self::get_entry(k, v))
     }
 
-    fn get_entry(k: String, v: Vec<u8>) -> Self {
-        let crc = Self::calculate_crc_by_vec(&v);
+    fn get_entry(k: Vec<u8>, v: Vec<u8>) -> Self {
         let tstamp = Self::get_tstamp();
         let value_sz = v.len();
         let ksz = k.len();
-        Self {
-            crc,
+        let mut entry = Self {
+            crc: 0,
             tstamp,
+            batch_seq: 0,
+            expire_at: 0,
+            entry_type: EntryType::Normal,
             ksz,
             value_sz,
             k,
             v,
-        }
+        };
+        entry.update_crc();
+        entry
     }
 
-    /// 将整个 entry 解析成 Vec<u8>
-    pub fn encode(&self) -> Vec<u8> {
-        let mut ans: Vec<u8> = Vec::new();
+    fn update_crc(&mut self) {
+        self.crc = Self::calculate_crc_by_vec(&self.encode()[mem::size_of::<u32>()..]);
+    }
 
-        // native endian
-        ans.extend(&self.crc.to_ne_bytes());
-        ans.extend(&self.tstamp.to_ne_bytes());
-        ans.extend(&(self.ksz).to_ne_bytes());
-        ans.extend(&(self.value_sz).to_ne_bytes());
+    /// 设置所属 WriteBatch 的序列号并重新计算 crc
+    pub fn set_batch_seq(&mut self, batch_seq: u64) {
+        self.batch_seq = batch_seq;
+        self.update_crc();
+    }
 
-        // 字符串转成字节
-        ans.extend(self.k.as_bytes());
+    /// 设置为写入 ttl 之后过期并重新计算 crc，ttl 为 0 时立即过期
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.expire_at = self.tstamp.saturating_add(ttl);
+        self.update_crc();
+    }
+
+    /// 将整个 entry 解析成 Vec<u8>
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ans: Vec<u8> = Vec::with_capacity(self.get_self_size());
+
+        // little endian
+        ans.extend(&self.crc.to_le_bytes());
+        ans.extend(&self.tstamp.to_le_bytes());
+        ans.extend(&self.batch_seq.to_le_bytes());
+        ans.extend(&self.expire_at.to_le_bytes());
+        ans.push(self.entry_type as u8);
+        ans.extend(&(self.ksz as u32).to_le_bytes());
+        ans.extend(&(self.value_sz as u32).to_le_bytes());
+
+        ans.extend(&self.k[..]);
         ans.extend(&self.v[..]);
         ans
     }
 
-    /// 根据 Vec<u8> 解析出 entry
-    pub fn decode(entry: Vec<u8>) -> Self {
-        let usize_bytes = mem::size_of::<usize>();
-        let mut idx = 0;
+    /// 从 entry 的 header 中解析出 ksz 和 value_sz
+    pub fn decode_sizes(header: &[u8]) -> (usize, usize) {
+        Self::decode_sizes_with_version(header, DATA_FILE_VERSION)
+    }
 
-        // crc 32bit=4Byte
-        let crc_bytes = entry[idx..=idx + 3].to_vec();
-        idx += 4;
-        let crc = u32::from_ne_bytes(crc_bytes.try_into().unwrap());
+    /// 按 version 版本的格式解析 ksz 和 value_sz，它们总是 header 的最后 8 个字节
+    pub fn decode_sizes_with_version(header: &[u8], version: u32) -> (usize, usize) {
+        let pos = Self::header_size(version) - 8;
+        let ksz = u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+        let value_sz = u32::from_le_bytes(header[pos + 4..pos + 8].try_into().unwrap());
+        (ksz as usize, value_sz as usize)
+    }
 
-        // tstamp u64bit=8Byte
-        let tstamp_bytes = entry[idx..=idx + 7].to_vec();
-        idx += 8;
-        let tstamp = u64::from_ne_bytes(tstamp_bytes.try_into().unwrap());
+    /// header 是否满足写入时的约束: tstamp 不为 0，数据 entry 和 tombstone 的 k 不为空，
+    /// 提交标记的 k 和 v 为空并且属于某个 batch，过期时间不早于写入时间。
+    /// 修复时用它排除大部分损坏的位置，不必读取整个 entry 校验 crc
+    pub(crate) fn is_valid_header(header: &[u8], version: u32) -> bool {
+        let (ksz, value_sz) = Self::decode_sizes_with_version(header, version);
+        let u64_at = |pos: usize| u64::from_le_bytes(header[pos..pos + 8].try_into().unwrap());
+        if u64_at(4) == 0 {
+            return false;
+        }
+        let (batch_seq, expire_at, entry_type) = match Self::header_size(version) {
+            Self::V1_HEADER_SIZE => (0, 0, Some(EntryType::Normal)),
+            Self::V3_HEADER_SIZE => (u64_at(12), 0, EntryType::from_u8(header[20])),
+            _ => (u64_at(12), u64_at(20), EntryType::from_u8(header[28])),
+        };
+        match entry_type {
+            Some(EntryType::Normal) => ksz > 0 && (expire_at == 0 || expire_at >= u64_at(4)),
+            Some(EntryType::BatchFinished) => ksz == 0 && value_sz == 0 && batch_seq != 0,
+            None => false,
+        }
+    }
 
-        // ksz usize=mem::size_of::<usize>()
-        let ksz_bytes = entry[idx..=idx + usize_bytes - 1].to_vec();
-        idx += usize_bytes;
-        let ksz = usize::from_ne_bytes(ksz_bytes.try_into().unwrap());
+    /// 根据 Vec<u8> 解析出 entry, 长度与 header 不一致或者 crc 校验失败说明数据已损坏，返回 None
+    pub fn decode(entry: Vec<u8>) -> Option<Self> {
+        Self::decode_with_version(entry, DATA_FILE_VERSION)
+    }
 
-        // value_sz usize=mem::size_of::<usize>()
-        let value_sz_bytes = entry[idx..=idx + usize_bytes - 1].to_vec();
-        idx += usize_bytes;
-        let value_sz = usize::from_ne_bytes(value_sz_bytes.try_into().unwrap());
+    /// 按 version 版本的格式解析 entry。旧版本的 entry 按当前格式重新计算 crc，之后可以直接按当前格式写入
+    pub fn decode_with_version(entry: Vec<u8>, version: u32) -> Option<Self> {
+        let header_size = Self::header_size(version);
+        if entry.len() < header_size {
+            return None;
+        }
 
-        // k String -> 字节转字符串
-        let k_bytes = entry[idx..=idx + ksz - 1].to_vec();
-        idx += ksz;
-        let k = String::from_utf8(k_bytes).unwrap();
-
-        // v Vec<u8>
-        let v = if value_sz == 0 {
-            // tombstone
-            Vec::<u8>::new()
-        } else {
-            entry[idx..=idx + value_sz - 1].to_vec()
+        // ksz, value_sz u32=4Byte
+        let (ksz, value_sz) = Self::decode_sizes_with_version(&entry[..header_size], version);
+        if header_size + ksz + value_sz != entry.len() {
+            return None;
+        }
+
+        // crc u32=4Byte，版本 1 只覆盖 v
+        let crc = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+        let covered = match version {
+            1 => &entry[header_size + ksz..],
+            _ => &entry[4..],
         };
+        if crc != Self::calculate_crc_by_vec(covered) {
+            return None;
+        }
+
+        // tstamp u64=8Byte
+        let tstamp = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+
+        // batch_seq, expire_at u64=8Byte, type u8=1Byte
+        let u64_at = |pos: usize| u64::from_le_bytes(entry[pos..pos + 8].try_into().unwrap());
+        let (batch_seq, expire_at, entry_type) = match header_size {
+            Self::V1_HEADER_SIZE => (0, 0, EntryType::Normal),
+            Self::V3_HEADER_SIZE => (u64_at(12), 0, EntryType::from_u8(entry[20])?),
+            _ => (u64_at(12), u64_at(20), EntryType::from_u8(entry[28])?),
+        };
+
+        // k Vec<u8>
+        let mut idx = header_size;
+        let k = entry[idx..idx + ksz].to_vec();
+        idx += ksz;
 
-        Self {
+        // v Vec<u8>, tombstone 的 v 为空
+        let v = entry[idx..idx + value_sz].to_vec();
+
+        let mut decoded = Self {
             crc,
             tstamp,
+            batch_seq,
+            expire_at,
+            entry_type,
             ksz,
             value_sz,
             k,
             v,
+        };
+        if version != DATA_FILE_VERSION {
+            decoded.update_crc();
         }
+        Some(decoded)
     }
 
     pub fn is_tombstone(&self) -> bool {
-        self.value_sz == 0 && self.v.len() == 0
+        self.entry_type == EntryType::Normal && self.value_sz == 0 && self.v.is_empty()
+    }
+
+    pub fn is_batch_finished(&self) -> bool {
+        self.entry_type == EntryType::BatchFinished
+    }
+
+    /// 设置了过期时间并且已经过期
+    pub fn is_expired(&self) -> bool {
+        self.expire_at != 0 && Self::get_tstamp() >= self.expire_at
     }
 
     pub fn crc(&self) -> u32 {
@@ -141,36 +307,38 @@ impl Entry {
     pub fn tstamp(&self) -> u64 {
         self.tstamp
     }
+    pub fn batch_seq(&self) -> u64 {
+        self.batch_seq
+    }
+    pub fn expire_at(&self) -> u64 {
+        self.expire_at
+    }
     pub fn ksz(&self) -> usize {
         self.ksz
     }
     pub fn value_sz(&self) -> usize {
         self.value_sz
     }
-    pub fn k(&self) -> &str {
+    pub fn k(&self) -> &[u8] {
         &self.k
     }
+    pub fn into_k(self) -> Vec<u8> {
+        self.k
+    }
     pub fn v(&self) -> &Vec<u8> {
         &self.v
     }
 
     pub fn get_self_size(&self) -> usize {
-        let mut size = 0;
-        size += mem::size_of::<u32>(); // crc
-        size += mem::size_of::<u64>(); // tstamp
-        size += mem::size_of::<usize>(); // ksz
-        size += mem::size_of::<usize>(); // value_sz
-        size += self.ksz; // k
-        size += self.value_sz; // v
-        size
+        Self::HEADER_SIZE + self.ksz + self.value_sz
     }
 }
 
 impl Display for Entry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = format!(
-            "crc: {:?}, tstamp: {:?}, ksz: {:?} bytes, value_sz: {:?} bytes, k: {:?}, v: {:?}, total size is {}",
-            self.crc, self.tstamp, self.ksz, self.value_sz, self.k, self.v, self.get_self_size()
+            "crc: {:?}, tstamp: {:?}, batch_seq: {:?}, expire_at: {:?}, type: {:?}, ksz: {:?} bytes, value_sz: {:?} bytes, k: {:?}, v: {:?}, total size is {}",
+            self.crc, self.tstamp, self.batch_seq, self.expire_at, self.entry_type, self.ksz, self.value_sz, self.k, self.v, self.get_self_size()
         );
         write!(f, "{}", str)
     }
@@ -180,6 +348,9 @@ impl PartialEq for Entry {
     fn eq(&self, other: &Self) -> bool {
         self.crc == other.crc
             && self.tstamp == other.tstamp
+            && self.batch_seq == other.batch_seq
+            && self.expire_at == other.expire_at
+            && self.entry_type == other.entry_type
             && self.ksz == other.ksz
             && self.value_sz == other.value_sz
             && self.k == other.k
@@ -189,73 +360,291 @@ impl PartialEq for Entry {
 
 impl Eq for Entry {}
 
+#[cfg(test)]
+impl Entry {
+    /// 按 version 版本的格式编码，用于测试读取旧版本的 data file
+    pub(crate) fn encode_with_version(&self, version: u32) -> Vec<u8> {
+        let header_size = Self::header_size(version);
+        let mut ans: Vec<u8> = Vec::with_capacity(header_size + self.ksz + self.value_sz);
+        ans.extend(&[0; 4]);
+        ans.extend(&self.tstamp.to_le_bytes());
+        if header_size != Self::V1_HEADER_SIZE {
+            ans.extend(&self.batch_seq.to_le_bytes());
+        }
+        if header_size == Self::HEADER_SIZE {
+            ans.extend(&self.expire_at.to_le_bytes());
+        }
+        if header_size != Self::V1_HEADER_SIZE {
+            ans.push(self.entry_type as u8);
+        }
+        ans.extend(&(self.ksz as u32).to_le_bytes());
+        ans.extend(&(self.value_sz as u32).to_le_bytes());
+        ans.extend(&self.k[..]);
+        ans.extend(&self.v[..]);
+        let crc = match version {
+            1 => Self::calculate_crc_by_vec(&self.v),
+            _ => Self::calculate_crc_by_vec(&ans[4..]),
+        };
+        ans[..4].copy_from_slice(&crc.to_le_bytes());
+        ans
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::datafile::MIN_DATA_FILE_VERSION;
 
     #[test]
     fn test_entry() {
-        let k = "key".to_string();
+        let k = "key".as_bytes().to_vec();
         let v = vec![1, 2, 3];
         let entry = Entry::new(k.clone(), v.clone()).unwrap();
         assert_eq!(entry.k, k);
         assert_eq!(entry.v, v);
         assert_eq!(entry.ksz, 3);
         assert_eq!(entry.value_sz, 3);
-        assert_eq!(entry.crc, Entry::calculate_crc_by_vec(&v));
-        assert_eq!(entry.tstamp, Entry::get_tstamp());
+        assert_eq!(entry.crc, Entry::calculate_crc_by_vec(&entry.encode()[4..]));
+        assert!(entry.tstamp <= Entry::get_tstamp());
 
         let tombstone = Entry::get_tombstone_with_given_key(k.clone()).unwrap();
         assert_eq!(tombstone.k, k);
         assert_eq!(tombstone.v, vec![]);
         assert_eq!(tombstone.ksz, 3);
         assert_eq!(tombstone.value_sz, 0);
-        assert_eq!(tombstone.crc, Entry::calculate_crc_by_vec(&vec![]));
-        assert_eq!(tombstone.tstamp, Entry::get_tstamp());
+        assert_eq!(
+            tombstone.crc,
+            Entry::calculate_crc_by_vec(&tombstone.encode()[4..])
+        );
+        assert!(tombstone.tstamp <= Entry::get_tstamp());
+    }
+
+    /// 全 0 的大数组按需分配物理内存，只检查长度时不会真正占用 4 GiB
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_entry_too_large() {
+        let large = || vec![0u8; u32::MAX as usize + 1];
+        assert!(matches!(
+            Entry::new(large(), vec![1]),
+            Err(KeyTooLarge(Entry::MAX_KEY_SIZE))
+        ));
+        assert!(matches!(
+            Entry::new(vec![1], large()),
+            Err(ValueTooLarge(Entry::MAX_VALUE_SIZE))
+        ));
+        assert!(matches!(
+            Entry::get_tombstone_with_given_key(large()),
+            Err(KeyTooLarge(Entry::MAX_KEY_SIZE))
+        ));
     }
 
     #[test]
     fn test_encode_decode() {
-        let k = "key".to_string();
+        let k = "key".as_bytes().to_vec();
         let v = vec![1, 2, 3];
         let entry = Entry::new(k.clone(), v.clone()).unwrap();
         let encoded = entry.encode();
-        let decoded = Entry::decode(encoded);
+        let decoded = Entry::decode(encoded).unwrap();
+        assert_eq!(decoded, entry);
+    }
+
+    #[test]
+    fn test_encode_decode_binary_key() {
+        let k = vec![0xff, 0x00, 0xc3, 0x28];
+        let v = vec![1, 2, 3];
+        let entry = Entry::new(k.clone(), v.clone()).unwrap();
+        let decoded = Entry::decode(entry.encode()).unwrap();
+        assert_eq!(decoded.k(), &k[..]);
         assert_eq!(decoded, entry);
     }
 
+    #[test]
+    fn test_encode_little_endian() {
+        let entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        let encoded = entry.encode();
+        assert_eq!(&encoded[0..4], &entry.crc.to_le_bytes());
+        assert_eq!(&encoded[4..12], &entry.tstamp.to_le_bytes());
+        assert_eq!(&encoded[12..20], &[0; 8]);
+        assert_eq!(&encoded[20..28], &[0; 8]);
+        assert_eq!(encoded[28], EntryType::Normal as u8);
+        assert_eq!(&encoded[29..33], &[3, 0, 0, 0]);
+        assert_eq!(&encoded[33..37], &[3, 0, 0, 0]);
+        assert_eq!(&encoded[37..], &[b'k', b'e', b'y', 1, 2, 3]);
+        assert_eq!(Entry::decode_sizes(&encoded), (3, 3));
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        let entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        let encoded = entry.encode();
+
+        // header, key, value 中任意一个字节损坏都能被 crc 发现
+        for i in 0..encoded.len() {
+            let mut corrupted = encoded.clone();
+            corrupted[i] ^= 0x01;
+            assert!(Entry::decode(corrupted).is_none());
+        }
+
+        // 长度与 header 不一致
+        assert!(Entry::decode(encoded[..encoded.len() - 1].to_vec()).is_none());
+        assert!(Entry::decode(encoded[..Entry::HEADER_SIZE - 1].to_vec()).is_none());
+    }
+
+    #[test]
+    fn test_decode_old_versions() {
+        for version in MIN_DATA_FILE_VERSION..=DATA_FILE_VERSION {
+            let mut entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+            if version >= 3 {
+                entry.set_batch_seq(7);
+            }
+            let encoded = entry.encode_with_version(version);
+            let header_size = Entry::header_size(version);
+            assert_eq!(encoded.len(), header_size + 6);
+            assert_eq!(Entry::decode_sizes_with_version(&encoded, version), (3, 3));
+
+            // 旧版本的 entry 永不过期，crc 按当前格式重新计算
+            let decoded = Entry::decode_with_version(encoded.clone(), version).unwrap();
+            assert_eq!(decoded, entry);
+            assert_eq!(decoded.expire_at(), 0);
+            assert_eq!(Entry::decode(decoded.encode()).unwrap(), entry);
+
+            let mut corrupted = encoded.clone();
+            corrupted[header_size + 3] ^= 0x01;
+            assert!(Entry::decode_with_version(corrupted, version).is_none());
+            if version != DATA_FILE_VERSION {
+                assert!(Entry::decode(encoded).is_none());
+            }
+        }
+        assert_eq!(Entry::header_size(1), Entry::V1_HEADER_SIZE);
+        assert_eq!(Entry::header_size(2), Entry::V1_HEADER_SIZE);
+        assert_eq!(Entry::header_size(3), Entry::V3_HEADER_SIZE);
+
+        // 版本 1 和 2 的 tombstone 仍然是 v 为空的 entry
+        let tombstone = Entry::get_tombstone_with_given_key("key".as_bytes().to_vec()).unwrap();
+        for version in [1, 2] {
+            let decoded =
+                Entry::decode_with_version(tombstone.encode_with_version(version), version)
+                    .unwrap();
+            assert!(decoded.is_tombstone());
+            assert_eq!(decoded, tombstone);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_header() {
+        let entry = Entry::new("hello".as_bytes().to_vec(), "world".as_bytes().to_vec()).unwrap();
+        let mut entry_with_ttl =
+            Entry::new("a".as_bytes().to_vec(), "b".as_bytes().to_vec()).unwrap();
+        entry_with_ttl.set_ttl(Duration::from_secs(1));
+        let tombstone = Entry::get_tombstone_with_given_key("hello".as_bytes().to_vec()).unwrap();
+        let finished = Entry::get_batch_finished(7);
+        for version in MIN_DATA_FILE_VERSION..=DATA_FILE_VERSION {
+            for e in [&entry, &entry_with_ttl, &tombstone, &finished] {
+                if version < 3 && e.is_batch_finished() {
+                    continue;
+                }
+                let encoded = e.encode_with_version(version);
+                assert!(
+                    Entry::is_valid_header(&encoded, version),
+                    "{} {}",
+                    version,
+                    e
+                );
+            }
+        }
+
+        // 全 0 的区域和未知的 type 都不是合法的 header
+        for version in MIN_DATA_FILE_VERSION..=DATA_FILE_VERSION {
+            assert!(!Entry::is_valid_header(&[0; Entry::HEADER_SIZE], version));
+        }
+        let mut encoded = entry.encode();
+        encoded[28] = 2;
+        assert!(!Entry::is_valid_header(&encoded, DATA_FILE_VERSION));
+        let mut encoded = finished.encode();
+        encoded[Entry::HEADER_SIZE - 8] = 1;
+        assert!(!Entry::is_valid_header(&encoded, DATA_FILE_VERSION));
+        let mut encoded = entry_with_ttl.encode();
+        encoded[20..28].copy_from_slice(&1u64.to_le_bytes());
+        assert!(!Entry::is_valid_header(&encoded, DATA_FILE_VERSION));
+    }
+
+    #[test]
+    fn test_batch_entries() {
+        let mut entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        entry.set_batch_seq(7);
+        let decoded = Entry::decode(entry.encode()).unwrap();
+        assert_eq!(decoded.batch_seq(), 7);
+        assert!(!decoded.is_batch_finished());
+
+        let finished = Entry::get_batch_finished(7);
+        let decoded = Entry::decode(finished.encode()).unwrap();
+        assert_eq!(decoded.batch_seq(), 7);
+        assert!(decoded.is_batch_finished());
+        assert!(!decoded.is_tombstone());
+
+        // 未知的 entry 类型视为损坏
+        let mut encoded = finished.encode();
+        encoded[28] = 0xff;
+        let crc = Entry::calculate_crc_by_vec(&encoded[4..]);
+        encoded[0..4].copy_from_slice(&crc.to_le_bytes());
+        assert!(Entry::decode(encoded).is_none());
+    }
+
     #[test]
     fn test_is_tombstone() {
-        let k = "key".to_string();
+        let k = "key".as_bytes().to_vec();
         let v = vec![1, 2, 3];
         let entry = Entry::new(k.clone(), v.clone()).unwrap();
-        assert_eq!(entry.is_tombstone(), false);
+        assert!(!entry.is_tombstone());
 
         let tombstone = Entry::get_tombstone_with_given_key(k.clone()).unwrap();
-        assert_eq!(tombstone.is_tombstone(), true);
+        assert!(tombstone.is_tombstone());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut entry = Entry::new("key".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        assert_eq!(entry.expire_at(), 0);
+        assert!(!entry.is_expired());
+
+        entry.set_ttl(Duration::from_secs(60));
+        assert_eq!(entry.expire_at(), entry.tstamp() + 60_000);
+        assert!(!entry.is_expired());
+        let decoded = Entry::decode(entry.encode()).unwrap();
+        assert_eq!(decoded.expire_at(), entry.expire_at());
+        assert_eq!(decoded, entry);
+
+        entry.set_ttl(Duration::ZERO);
+        assert!(entry.is_expired());
+        entry.set_ttl(Duration::MAX);
+        assert_eq!(entry.expire_at(), u64::MAX);
+        assert!(!entry.is_expired());
     }
 
     #[test]
     fn test_get_self_size() {
-        let k = "key".to_string();
+        let k = "key".as_bytes().to_vec();
         let v = vec![1, 2, 3];
         let entry = Entry::new(k.clone(), v.clone()).unwrap();
-        assert_eq!(entry.get_self_size(), 32);
+        // crc(4) + tstamp(8) + batch_seq(8) + expire_at(8) + type(1) + ksz(4) + value_sz(4) + k(3) + v(3)
+        assert_eq!(entry.get_self_size(), 43);
+        assert_eq!(entry.get_self_size(), entry.encode().len());
 
         let tombstone = Entry::get_tombstone_with_given_key(k.clone()).unwrap();
-        assert_eq!(tombstone.get_self_size(), 24);
+        assert_eq!(tombstone.get_self_size(), 40);
+        assert_eq!(tombstone.get_self_size(), tombstone.encode().len());
     }
 
     #[test]
     fn test_to_string() {
-        let k = "key".to_string();
+        let k = "key".as_bytes().to_vec();
         let v = vec![1, 2, 3];
         let entry = Entry::new(k.clone(), v.clone()).unwrap();
         assert_eq!(
             entry.to_string(),
             format!(
-                "crc: {:?} tstamp: {:?} ksz: {:?} value_sz: {:?} k: {:?} v: {:?}",
-                entry.crc, entry.tstamp, entry.ksz, entry.value_sz, entry.k, entry.v
+                "crc: {:?}, tstamp: {:?}, batch_seq: {:?}, expire_at: {:?}, type: {:?}, ksz: {:?} bytes, value_sz: {:?} bytes, k: {:?}, v: {:?}, total size is {}",
+                entry.crc, entry.tstamp, entry.batch_seq, entry.expire_at, entry.entry_type, entry.ksz, entry.value_sz, entry.k, entry.v, entry.get_self_size()
             )
         )
     }
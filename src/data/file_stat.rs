@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+/// 单个 data file 的空间使用情况
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileStat {
+    pub file_id: u32,
+
+    /// 文件中所有 entry 占用的字节数
+    pub total_bytes: usize,
+
+    /// 被覆盖或者删除的 entry 以及 tombstone 占用的字节数，merge 可以回收这部分空间
+    pub dead_bytes: usize,
+}
+
+impl FileStat {
+    pub fn new(file_id: u32) -> Self {
+        Self {
+            file_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.total_bytes.saturating_sub(self.dead_bytes)
+    }
+
+    /// 可回收的字节数占文件大小的比例
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+pub(crate) fn add_total_bytes(file_stats: &mut HashMap<u32, FileStat>, file_id: u32, bytes: usize) {
+    file_stats
+        .entry(file_id)
+        .or_insert_with(|| FileStat::new(file_id))
+        .total_bytes += bytes;
+}
+
+pub(crate) fn add_dead_bytes(file_stats: &mut HashMap<u32, FileStat>, file_id: u32, bytes: usize) {
+    file_stats
+        .entry(file_id)
+        .or_insert_with(|| FileStat::new(file_id))
+        .dead_bytes += bytes;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_stat() {
+        let mut file_stats = HashMap::new();
+        add_total_bytes(&mut file_stats, 1, 100);
+        add_dead_bytes(&mut file_stats, 1, 25);
+        add_dead_bytes(&mut file_stats, 2, 10);
+
+        let stat = file_stats[&1];
+        assert_eq!(stat.file_id, 1);
+        assert_eq!(stat.live_bytes(), 75);
+        assert_eq!(stat.dead_ratio(), 0.25);
+
+        let stat = file_stats[&2];
+        assert_eq!(stat.live_bytes(), 0);
+        assert_eq!(stat.dead_ratio(), 0.0);
+    }
+}
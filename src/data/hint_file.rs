@@ -0,0 +1,342 @@
+use crate::data::entry::Entry;
+use crate::data::meta_data::MetaData;
+use crate::error::E::DataCorrupted;
+use crate::error::R;
+use crate::fio::file_io::FileIO;
+use crate::fio::{new_io_manager, IOManager};
+use crate::options::IOType;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+pub const HINT_FILE_SUFFIX: &str = ".hint";
+
+/// 每个 hint file 开头的文件头 magic-version，与 data file 的文件头格式相同
+pub const HINT_FILE_MAGIC: &[u8; 4] = b"BCHT";
+pub const HINT_FILE_VERSION: u32 = 1;
+pub const HINT_FILE_HEADER_SIZE: usize = HINT_FILE_MAGIC.len() + mem::size_of::<u32>();
+
+/// hint file 的一条记录，disk 上的表示形式 crc-tstamp-ksz-entry_sz-entry_start_pos-expire_at-k
+/// 所有整数都是定长小端序，crc 覆盖 crc 之后的所有字节，file_id 就是 hint file 的文件名，不需要存储
+#[derive(Debug, Eq, PartialEq)]
+pub struct HintEntry {
+    tstamp: u64,
+    ksz: u32,
+    entry_sz: u64,
+    entry_start_pos: u64,
+
+    /// 与 entry 中的过期时间相同，0 表示永不过期
+    expire_at: u64,
+    k: Vec<u8>,
+}
+
+impl HintEntry {
+    const HEADER_SIZE: usize = mem::size_of::<u32>() // crc
+        + mem::size_of::<u64>() // tstamp
+        + mem::size_of::<u32>() // ksz
+        + mem::size_of::<u64>() // entry_sz
+        + mem::size_of::<u64>() // entry_start_pos
+        + mem::size_of::<u64>(); // expire_at
+
+    pub fn new(k: Vec<u8>, meta_data: &MetaData, expire_at: u64) -> Self {
+        Self {
+            tstamp: meta_data.tstamp,
+            ksz: k.len() as u32,
+            entry_sz: meta_data.entry_sz as u64,
+            entry_start_pos: meta_data.entry_start_pos as u64,
+            expire_at,
+            k,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ans: Vec<u8> = Vec::with_capacity(Self::HEADER_SIZE + self.k.len());
+        ans.extend(&[0; 4]);
+        ans.extend(&self.tstamp.to_le_bytes());
+        ans.extend(&self.ksz.to_le_bytes());
+        ans.extend(&self.entry_sz.to_le_bytes());
+        ans.extend(&self.entry_start_pos.to_le_bytes());
+        ans.extend(&self.expire_at.to_le_bytes());
+        ans.extend(&self.k[..]);
+        let crc = Entry::calculate_crc_by_vec(&ans[4..]);
+        ans[..4].copy_from_slice(&crc.to_le_bytes());
+        ans
+    }
+
+    /// 解析一条完整的记录，crc 校验失败或者长度与 ksz 不一致时返回 None
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (header, k) = buf.split_at_checked(Self::HEADER_SIZE)?;
+        let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        if crc != Entry::calculate_crc_by_vec(&buf[4..]) {
+            return None;
+        }
+        let ksz = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if ksz as usize != k.len() {
+            return None;
+        }
+        Some(Self {
+            tstamp: u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            ksz,
+            entry_sz: u64::from_le_bytes(header[16..24].try_into().unwrap()),
+            entry_start_pos: u64::from_le_bytes(header[24..32].try_into().unwrap()),
+            expire_at: u64::from_le_bytes(header[32..40].try_into().unwrap()),
+            k: k.to_vec(),
+        })
+    }
+
+    pub fn k(&self) -> &[u8] {
+        &self.k
+    }
+
+    pub fn into_k(self) -> Vec<u8> {
+        self.k
+    }
+
+    pub fn expire_at(&self) -> u64 {
+        self.expire_at
+    }
+
+    /// 设置了过期时间并且已经过期
+    pub fn is_expired(&self) -> bool {
+        self.expire_at != 0 && Entry::get_tstamp() >= self.expire_at
+    }
+
+    /// 还原出内存索引中的 MetaData
+    pub fn meta_data(&self, file_id: u32) -> MetaData {
+        MetaData::new(
+            file_id,
+            self.entry_sz as usize,
+            self.entry_start_pos as usize,
+            self.tstamp,
+        )
+    }
+}
+
+/// 与 merged data file 一一对应的 hint file，启动时读取 hint file 即可构建索引，不需要扫描 data file
+pub struct HintFile {
+    file_id: u32,
+    io_manager: Box<dyn IOManager>,
+
+    /// 打开时的文件大小，读取时据此检查记录中的长度
+    file_len: usize,
+}
+
+impl HintFile {
+    /// 创建用于写入的 hint file，空文件先写入文件头
+    pub fn new(dir_path: &str, file_id: u32) -> R<Self> {
+        let full_path = Self::get_file_full_path(dir_path, file_id);
+        let io_manager = Box::new(FileIO::from(&full_path)?);
+        let mut file_len = Self::file_len(&full_path);
+        if file_len == 0 {
+            let mut header = Vec::with_capacity(HINT_FILE_HEADER_SIZE);
+            header.extend(HINT_FILE_MAGIC);
+            header.extend(&HINT_FILE_VERSION.to_le_bytes());
+            file_len = io_manager.append(&header)?;
+        }
+        Ok(Self {
+            file_id,
+            io_manager,
+            file_len,
+        })
+    }
+
+    /// 打开已存在的 hint file 用于读取
+    pub fn open(dir_path: &str, file_id: u32, io_type: IOType) -> R<Self> {
+        let full_path = Self::get_file_full_path(dir_path, file_id);
+        let io_manager = new_io_manager(&full_path, io_type)?;
+        Ok(Self {
+            file_id,
+            io_manager,
+            file_len: Self::file_len(&full_path),
+        })
+    }
+
+    fn file_len(full_path: &str) -> usize {
+        fs::metadata(full_path).map_or(0, |m| m.len() as usize)
+    }
+
+    pub fn exists(dir_path: &str, file_id: u32) -> bool {
+        Path::new(&Self::get_file_full_path(dir_path, file_id)).is_file()
+    }
+
+    pub fn get_file_full_path(dir_path: &str, file_id: u32) -> String {
+        Path::new(dir_path)
+            .join(file_id.to_string() + HINT_FILE_SUFFIX)
+            .display()
+            .to_string()
+    }
+
+    pub fn file_id(&self) -> u32 {
+        self.file_id
+    }
+
+    pub fn write_hint(&self, k: Vec<u8>, meta_data: &MetaData, expire_at: u64) -> R<()> {
+        let hint_entry = HintEntry::new(k, meta_data, expire_at);
+        self.io_manager.append(&hint_entry.encode())?;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> R<()> {
+        self.io_manager.sync()
+    }
+
+    /// 读取 hint file 中的所有记录，hint file 是完整写入后才对外可见的，
+    /// 文件头不匹配、记录不完整或者 crc 校验失败都说明文件损坏，返回 DataCorrupted
+    pub fn get_all_hints(&self) -> R<Vec<HintEntry>> {
+        let corrupted = |offset: usize| DataCorrupted {
+            file_id: self.file_id,
+            offset,
+        };
+        let mut header = [0; HINT_FILE_HEADER_SIZE];
+        if self.io_manager.read(&mut header, 0)? < HINT_FILE_HEADER_SIZE
+            || &header[..HINT_FILE_MAGIC.len()] != HINT_FILE_MAGIC
+            || header[HINT_FILE_MAGIC.len()..] != HINT_FILE_VERSION.to_le_bytes()
+        {
+            return Err(corrupted(0));
+        }
+
+        let mut hints = Vec::new();
+        let mut header_buf = vec![0; HintEntry::HEADER_SIZE];
+        let mut pos = HINT_FILE_HEADER_SIZE;
+        while pos < self.file_len {
+            if self.io_manager.read(&mut header_buf, pos as u64)? < HintEntry::HEADER_SIZE {
+                return Err(corrupted(pos));
+            }
+
+            // 先检查 ksz 没有超出文件末尾，再分配 key 的空间
+            let ksz = u32::from_le_bytes(header_buf[12..16].try_into().unwrap()) as usize;
+            let hint_len = HintEntry::HEADER_SIZE + ksz;
+            if hint_len > self.file_len - pos {
+                return Err(corrupted(pos));
+            }
+            let mut buf = vec![0; hint_len];
+            if self.io_manager.read(&mut buf, pos as u64)? < hint_len {
+                return Err(corrupted(pos));
+            }
+            hints.push(HintEntry::decode(&buf).ok_or_else(|| corrupted(pos))?);
+            pos += hint_len;
+        }
+        Ok(hints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_dir_path(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("bitcask-rs-hint-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.display().to_string()
+    }
+
+    #[test]
+    fn test_hint_entry_encode() {
+        let meta_data = MetaData::new(3, 40, 128, 1000);
+        let hint_entry = HintEntry::new("key".as_bytes().to_vec(), &meta_data, 2000);
+        let encoded = hint_entry.encode();
+        assert_eq!(encoded.len(), HintEntry::HEADER_SIZE + 3);
+        assert_eq!(hint_entry.expire_at(), 2000);
+        assert_eq!(hint_entry.meta_data(3), meta_data);
+    }
+
+    #[test]
+    fn test_write_and_read_hints() {
+        let dir_path = tmp_dir_path("write-read");
+        assert!(!HintFile::exists(&dir_path, 7));
+
+        let hint_file = HintFile::new(&dir_path, 7).unwrap();
+        let m1 = MetaData::new(7, 40, 0, 1000);
+        let m2 = MetaData::new(7, 50, 40, 1001);
+        hint_file
+            .write_hint("hello".as_bytes().to_vec(), &m1, 0)
+            .unwrap();
+        hint_file
+            .write_hint("你好".as_bytes().to_vec(), &m2, 5000)
+            .unwrap();
+        hint_file.sync().unwrap();
+        assert!(HintFile::exists(&dir_path, 7));
+
+        for io_type in [IOType::StandardFIO, IOType::MemoryMap] {
+            let hint_file = HintFile::open(&dir_path, 7, io_type).unwrap();
+            let hints = hint_file.get_all_hints().unwrap();
+            assert_eq!(hints.len(), 2);
+            assert_eq!(hints[0].k(), "hello".as_bytes());
+            assert_eq!(hints[0].meta_data(hint_file.file_id()), m1);
+            assert_eq!(hints[1].k(), "你好".as_bytes());
+            assert_eq!(hints[1].meta_data(hint_file.file_id()), m2);
+            assert_eq!(hints[0].expire_at(), 0);
+            assert_eq!(hints[1].expire_at(), 5000);
+        }
+    }
+
+    #[test]
+    fn test_read_truncated_hints() {
+        let dir_path = tmp_dir_path("truncated");
+        let hint_file = HintFile::new(&dir_path, 1).unwrap();
+        hint_file
+            .write_hint(
+                "hello".as_bytes().to_vec(),
+                &MetaData::new(1, 40, 0, 1000),
+                0,
+            )
+            .unwrap();
+        let full_path = HintFile::get_file_full_path(&dir_path, 1);
+        let len = std::fs::metadata(&full_path).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&full_path)
+            .unwrap();
+        file.set_len(len - 2).unwrap();
+
+        let hint_file = HintFile::open(&dir_path, 1, IOType::StandardFIO).unwrap();
+        assert!(hint_file.get_all_hints().is_err());
+    }
+
+    #[test]
+    fn test_read_corrupted_hints() {
+        let dir_path = tmp_dir_path("corrupted");
+        let hint_file = HintFile::new(&dir_path, 1).unwrap();
+        hint_file
+            .write_hint(
+                "hello".as_bytes().to_vec(),
+                &MetaData::new(1, 40, 0, 1000),
+                0,
+            )
+            .unwrap();
+        drop(hint_file);
+        let full_path = HintFile::get_file_full_path(&dir_path, 1);
+        let bytes = std::fs::read(&full_path).unwrap();
+        assert_eq!(&bytes[..4], HINT_FILE_MAGIC);
+        assert_eq!(&bytes[4..8], &HINT_FILE_VERSION.to_le_bytes());
+
+        let read_hints = |bytes: &[u8]| {
+            std::fs::write(&full_path, bytes).unwrap();
+            HintFile::open(&dir_path, 1, IOType::StandardFIO)
+                .unwrap()
+                .get_all_hints()
+        };
+        assert_eq!(read_hints(&bytes).unwrap().len(), 1);
+
+        // 任意一个字节损坏都能被发现
+        for i in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x01;
+            assert!(read_hints(&corrupted).is_err());
+        }
+
+        // 超出文件末尾的 ksz 在分配空间之前被拒绝
+        let mut corrupted = bytes.clone();
+        let ksz_pos = HINT_FILE_HEADER_SIZE + 12;
+        corrupted[ksz_pos..ksz_pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read_hints(&corrupted),
+            Err(DataCorrupted { file_id: 1, offset }) if offset == HINT_FILE_HEADER_SIZE
+        ));
+
+        // 没有文件头的旧格式 hint file 不能使用
+        assert!(read_hints(&bytes[HINT_FILE_HEADER_SIZE..]).is_err());
+    }
+}
@@ -1,4 +1,6 @@
 pub mod datafile;
 pub mod entry;
 pub mod entry_with_meta_data;
+pub mod file_stat;
+pub mod hint_file;
 pub mod meta_data;
@@ -1,30 +1,79 @@
-use crate::data::datafile::{self, DataFile, DataFileType, DATA_FILE_SUFFIX};
+use crate::checkpoint::{Checkpoint, CheckpointWorker};
+use crate::data::datafile::{
+    DataFile, DataFileType, DATA_FILE_HEADER_SIZE, DATA_FILE_SUFFIX, DATA_FILE_VERSION,
+};
 use crate::data::entry::Entry;
+use crate::data::entry_with_meta_data::EntryWithMetaData;
+use crate::data::file_stat::{add_dead_bytes, add_total_bytes, FileStat};
+use crate::data::hint_file::HintFile;
 use crate::data::meta_data::MetaData;
 use crate::error::E::{
-    CouldNotOpenDataDir, DataCorrupted, DirPathIsEmpty, EmptyKey, EmptyValue, Failed2CreateDataDir,
-    Failed2ReadDBDir, Failed2UpdateMemIndex, KeyNotExist, Nil,
+    CouldNotOpenDataDir, DirPathIsEmpty, EmptyKey, EmptyValue, Failed2CreateDataDir,
+    Failed2OpenDataFile, Failed2ReadDBDir, Failed2UpdateMemIndex, Failed2Write2DataFile,
+    FileThresholdTooLarge, InvalidMergeRatio, KeyNotExist, KeyTooLarge, Nil, UnknownDataFileFormat,
 };
 use crate::error::{E, R};
-use crate::index::keydir::KeyDir;
-use crate::index::{self, Indexer};
-use crate::options::IndexType;
-use crate::options::Options;
+use crate::index::{self, Indexer, PersistedState};
+use crate::merge::{self, MergeWorker};
+use crate::options::{IOType, IndexType, Options};
+use crate::snapshot::Snapshots;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use log::{error, warn};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use std::collections::HashMap;
+use std::fs::OpenOptions;
 use std::fs::{self, create_dir_all};
-use std::ops::Index;
-use std::path::Path;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// Engine 的统计信息
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// key 的数量。BPlusTree 索引从保存的状态恢复时，已过期的 key 在 merge 之前仍然计入
+    pub key_num: usize,
+
+    /// data file 的数量，包括 active file
+    pub data_file_num: usize,
+
+    /// 所有 data file 的字节数
+    pub total_bytes: usize,
+
+    /// merge 可以回收的字节数
+    pub reclaimable_bytes: usize,
+
+    /// 内存索引占用的字节数，是估算的值
+    pub index_memory_bytes: usize,
+}
+
 pub struct Engine {
-    options: Arc<Options>,
-    mem_index: Arc<RwLock<Box<dyn Indexer>>>,
-    active_file: Arc<RwLock<DataFile>>,
-    older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
-    index_type: Box<dyn Indexer>,
+    pub(crate) options: Arc<Options>,
+    pub(crate) mem_index: Arc<RwLock<Box<dyn Indexer>>>,
+    pub(crate) active_file: Arc<RwLock<DataFile>>,
+    pub(crate) older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+
+    /// 同一时刻只允许一个 merge
+    pub(crate) merge_lock: Arc<Mutex<()>>,
+
+    /// 写入检查点与 merge 替换 older file 互斥
+    pub(crate) checkpoint_lock: Arc<Mutex<()>>,
+
+    /// 每个 data file 的总字节数和可回收的字节数
+    pub(crate) file_stats: Arc<RwLock<HashMap<u32, FileStat>>>,
+
+    /// 最近一次 WriteBatch 使用的序列号
+    pub(crate) batch_seq: Arc<AtomicU64>,
+
+    /// 存活的快照
+    pub(crate) snapshots: Arc<RwLock<Snapshots>>,
+
+    /// 后台 merge，只有 Engine::open 返回的 Engine 持有
+    merge_worker: Option<MergeWorker>,
+
+    /// 后台检查点，只有 Engine::open 返回的 Engine 持有
+    checkpoint_worker: Option<CheckpointWorker>,
 }
 
 impl Engine {
@@ -33,14 +82,36 @@ impl Engine {
         mem_index: Arc<RwLock<Box<dyn Indexer>>>,
         active_file: Arc<RwLock<DataFile>>,
         older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
-        index_type: Box<dyn Indexer>,
     ) -> Self {
         Self {
             options,
             mem_index,
             active_file,
             older_files,
-            index_type,
+            merge_lock: Arc::new(Mutex::new(())),
+            checkpoint_lock: Arc::new(Mutex::new(())),
+            file_stats: Arc::new(RwLock::new(HashMap::new())),
+            batch_seq: Arc::new(AtomicU64::new(0)),
+            snapshots: Arc::new(RwLock::new(Snapshots::default())),
+            merge_worker: None,
+            checkpoint_worker: None,
+        }
+    }
+
+    /// 与当前 Engine 共享所有状态的 Engine，供后台线程使用，drop 时不会触发关闭逻辑
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            options: self.options.clone(),
+            mem_index: self.mem_index.clone(),
+            active_file: self.active_file.clone(),
+            older_files: self.older_files.clone(),
+            merge_lock: self.merge_lock.clone(),
+            checkpoint_lock: self.checkpoint_lock.clone(),
+            file_stats: self.file_stats.clone(),
+            batch_seq: self.batch_seq.clone(),
+            snapshots: self.snapshots.clone(),
+            merge_worker: None,
+            checkpoint_worker: None,
         }
     }
 
@@ -68,163 +139,469 @@ impl Engine {
             }
         }
 
-        // 2. 读取所有的 Files 构建 DataFile(OlderFiles and active file)
-        // 3. 构建内存索引，当前默认内存是 hash 表
-        let mem_index: Box<dyn Indexer> = Box::new(KeyDir::new()) as Box<dyn Indexer>;
+        // 2. 完成上次崩溃时未完成的 merge，或者丢弃未写完的 merge 结果
+        merge::recover_merge(&dir_path)?;
+
+        // 3. 读取所有的 Files 构建 DataFile(OlderFiles and active file)
+        // 4. 构建内存索引，使用 Options 中配置的索引类型
+        let mem_index = index::new_indexer(&opts)?;
         let mut older_files: HashMap<u32, DataFile> = HashMap::new();
-        let mut data_files = load_data_files(dir_path)?;
-        data_files.reverse();
-        if data_files.len() > 1 {
-            for _ in 0..=data_files.len() - 2 {
-                let data_file = data_files.pop().unwrap();
-                Self::fill_mem_index(&mem_index, &data_file);
-                older_files.insert(data_file.file_id(), data_file);
+        let mut file_stats: HashMap<u32, FileStat> = HashMap::new();
+        let mut max_batch_seq = 0;
+        let mut data_files = load_data_files(dir_path.clone(), opts.io_type)?;
+
+        // 保存在磁盘上的索引与 data file 一致时直接使用，不需要扫描 data file
+        let checksum = data_files_checksum(data_files.iter())?;
+        let persisted_state = match mem_index.persisted_state() {
+            Some(state) if state.data_files_checksum == checksum => Some(state),
+            Some(_) => {
+                warn!("data files changed since the index was persisted, rebuild the index");
+                mem_index.reset()?;
+                None
             }
-        }
+            None => None,
+        };
+        let active_file = match persisted_state {
+            Some(state) => {
+                let mut active_file = data_files.pop().unwrap();
+                if opts.io_type != IOType::StandardFIO {
+                    active_file.set_io_manager(IOType::StandardFIO)?;
+                }
+                older_files.extend(data_files.into_iter().map(|f| (f.file_id(), f)));
+                file_stats.extend(state.file_stats.into_iter().map(|s| (s.file_id, s)));
+                max_batch_seq = state.max_batch_seq;
+                active_file
+            }
+            None => {
+                // 检查点覆盖的部分直接载入，只重放之后写入的数据，检查点不可用时扫描全部 data file
+                let mut covered: Option<(u32, usize)> = None;
+                match Checkpoint::load(&dir_path) {
+                    Some(checkpoint) if checkpoint.is_valid_for(&data_files) => {
+                        for (key, meta_data) in checkpoint.items {
+                            if !mem_index.put(key, meta_data) {
+                                return Err(Failed2UpdateMemIndex);
+                            }
+                        }
+                        file_stats
+                            .extend(checkpoint.file_stats.into_iter().map(|s| (s.file_id, s)));
+                        max_batch_seq = checkpoint.max_batch_seq;
+                        covered = Some((checkpoint.file_id, checkpoint.offset));
+                    }
+                    Some(_) => warn!("data files changed since the index checkpoint, ignore it"),
+                    None => {}
+                }
+                // 返回 data file 中已经被检查点覆盖的字节数，None 表示没有覆盖
+                let covered_bytes = |data_file: &DataFile| match covered {
+                    Some((file_id, _)) if data_file.file_id() < file_id => {
+                        Some(data_file.next_write_begin_pos())
+                    }
+                    Some((file_id, offset)) if data_file.file_id() == file_id => Some(offset),
+                    _ => None,
+                };
+
+                // older file 在线程池中并发扫描，按 file id 的顺序合并到索引中，新的版本覆盖旧的版本
+                let active_file = data_files.pop().unwrap();
+                Self::scan_in_order(
+                    &data_files,
+                    opts.index_rebuild_threads,
+                    |data_file| match covered_bytes(data_file) {
+                        Some(offset) => Ok(FileRecords::collect(
+                            data_file.file_id(),
+                            data_file.get_all_entries_with_metadata_from(offset)?,
+                        )),
+                        // merged data file 有对应的 hint file，直接读取 hint file 构建索引。
+                        // 旧版本 data file 的 hint file 格式不同，不使用
+                        None => match FileRecords::from_hint_file(
+                            &dir_path,
+                            data_file.file_id(),
+                            opts.io_type,
+                        ) {
+                            Some(records) if data_file.version() == DATA_FILE_VERSION => {
+                                Ok(records)
+                            }
+                            _ => Ok(FileRecords::collect(
+                                data_file.file_id(),
+                                data_file.get_all_entries_with_metadata()?,
+                            )),
+                        },
+                    },
+                    |data_file, records| {
+                        let counted = covered_bytes(data_file).unwrap_or(0);
+                        add_total_bytes(
+                            &mut file_stats,
+                            data_file.file_id(),
+                            data_file.next_write_begin_pos() - counted,
+                        );
+                        let batch_seq = records.apply(mem_index.as_ref(), &mut file_stats)?;
+                        max_batch_seq = max_batch_seq.max(batch_seq);
+                        Ok(())
+                    },
+                )?;
+                older_files.extend(data_files.into_iter().map(|f| (f.file_id(), f)));
+
+                // active file 可能以崩溃时写了一半的 entry 结尾, 截断到最后一个完好的 entry
+                let mut active_file = active_file;
+                let (start, counted) = match covered_bytes(&active_file) {
+                    Some(offset) => (offset, offset),
+                    None => (DATA_FILE_HEADER_SIZE, 0),
+                };
+                let (entries, valid_len) =
+                    active_file.get_valid_entries_with_metadata_from(start)?;
+                let batch_seq = Self::fill_mem_index(
+                    mem_index.as_ref(),
+                    &mut file_stats,
+                    active_file.file_id(),
+                    entries,
+                )?;
+                max_batch_seq = max_batch_seq.max(batch_seq);
+                // active file 需要追加写，构建完索引后切换回标准文件 IO
+                if opts.io_type != IOType::StandardFIO {
+                    active_file.set_io_manager(IOType::StandardFIO)?;
+                }
+                let file_len = active_file.next_write_begin_pos();
+                if valid_len < file_len {
+                    warn!(
+                        "active file {} has a torn tail, truncated {} bytes",
+                        active_file.file_id(),
+                        file_len - valid_len
+                    );
+                    active_file.truncate(valid_len)?;
+                }
+                add_total_bytes(&mut file_stats, active_file.file_id(), valid_len - counted);
+                active_file
+            }
+        };
 
-        let active_file = data_files.pop().unwrap();
-        Self::fill_mem_index(&mem_index, &active_file);
+        // 旧版本的 active file 不能追加当前格式的 entry，作为 older file 保留，新建 active file
+        let mut active_file = active_file;
+        if active_file.version() != DATA_FILE_VERSION {
+            let file_id = active_file.file_id();
+            active_file.set_filetype(DataFileType::OLD);
+            if opts.io_type != IOType::StandardFIO {
+                active_file.set_io_manager(opts.io_type)?;
+            }
+            older_files.insert(file_id, active_file);
+            active_file = DataFile::new(dir_path.clone(), file_id + 1)?;
+            add_total_bytes(
+                &mut file_stats,
+                file_id + 1,
+                active_file.next_write_begin_pos(),
+            );
+        }
 
-        // 4. 构建 Engine
+        // 5. 构建 Engine
         let options = Arc::new(opts.clone());
         let mem_index = Arc::new(RwLock::new(mem_index));
         let active_file = Arc::new(RwLock::new(active_file));
         let older_files = Arc::new(RwLock::new(older_files));
-        let index_type = index::new_indexer(opts.index_type);
-        let engine = Engine::new(options, mem_index, active_file, older_files, index_type);
+        let mut engine = Engine::new(options, mem_index, active_file, older_files);
+        *engine.file_stats.write() = file_stats;
+        engine.batch_seq.store(max_batch_seq, Ordering::SeqCst);
+        engine.merge_worker = Some(MergeWorker::start(&engine));
+        engine.checkpoint_worker = Some(CheckpointWorker::start(&engine));
         Ok(engine)
     }
 
-    fn fill_mem_index(mem_index: &Box<dyn Indexer>, data_file: &DataFile) {
-        let entry_with_metadatas = data_file.get_all_entries_with_metadata().unwrap();
-        for entry_with_metadata in entry_with_metadatas {
-            let entry = entry_with_metadata.entry;
-            if entry.is_tombstone() {
-                mem_index.delete(&entry.k().to_string());
-            } else {
-                let meta_data = entry_with_metadata.meta_data;
-                mem_index.put(String::from_str(entry.k()).unwrap(), meta_data);
+    /// 构建索引的同时统计每个文件中被覆盖或者删除的字节数，返回读到的最大 batch 序列号
+    fn fill_mem_index(
+        mem_index: &dyn Indexer,
+        file_stats: &mut HashMap<u32, FileStat>,
+        file_id: u32,
+        entry_with_metadatas: Vec<EntryWithMetaData>,
+    ) -> R<u64> {
+        FileRecords::collect(file_id, entry_with_metadatas).apply(mem_index, file_stats)
+    }
+
+    /// 最多用 threads 个线程对 data_files 执行 scan，结果按 data_files 的顺序交给 fold。
+    /// fold 在当前线程执行，与之后文件的扫描同时进行
+    fn scan_in_order<T: Send>(
+        data_files: &[DataFile],
+        threads: usize,
+        scan: impl Fn(&DataFile) -> R<T> + Sync,
+        mut fold: impl FnMut(&DataFile, T) -> R<()>,
+    ) -> R<()> {
+        if threads <= 1 || data_files.len() <= 1 {
+            for data_file in data_files {
+                fold(data_file, scan(data_file)?)?;
             }
+            return Ok(());
         }
+
+        let next_file = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|s| {
+            for _ in 0..threads.min(data_files.len()) {
+                let (sender, next_file, scan) = (sender.clone(), &next_file, &scan);
+                s.spawn(move || loop {
+                    let i = next_file.fetch_add(1, Ordering::Relaxed);
+                    // fold 失败后 receiver 被 drop，不再扫描剩下的文件
+                    if i >= data_files.len() || sender.send((i, scan(&data_files[i]))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+
+            // 先扫描完的文件在这里等待，直到之前的文件都已经合并
+            let mut finished = HashMap::new();
+            let mut next_fold = 0;
+            for (i, res) in receiver {
+                finished.insert(i, res);
+                while let Some(res) = finished.remove(&next_fold) {
+                    fold(&data_files[next_fold], res?)?;
+                    next_fold += 1;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// 把保存在磁盘上的索引与当前 data file 的状态一起写回，内存索引什么都不做
+    fn persist_index(&self) -> R<()> {
+        let active_file = self.active_file.write();
+        active_file.sync()?;
+        let older_files = self.older_files.read();
+        let mem_index = self.mem_index.read();
+        let file_stats = self.file_stats.read();
+        let mut state = PersistedState {
+            data_files_checksum: data_files_checksum(
+                std::iter::once(&*active_file).chain(older_files.values()),
+            )?,
+            max_batch_seq: self.batch_seq.load(Ordering::SeqCst),
+            file_stats: file_stats.values().copied().collect(),
+        };
+        state.file_stats.sort_by_key(|file_stat| file_stat.file_id);
+        mem_index.persist(&state)
+    }
+
+    /// key 数量、磁盘空间和内存索引的使用情况
+    pub fn stats(&self) -> Stats {
+        let data_file_num = self.older_files.read().len() + 1;
+        let mem_index = self.mem_index.read();
+        let key_num = mem_index.key_num();
+        let index_memory_bytes = mem_index.memory_size();
+        drop(mem_index);
+        let file_stats = self.file_stats.read();
+        Stats {
+            key_num,
+            data_file_num,
+            total_bytes: file_stats.values().map(|s| s.total_bytes).sum(),
+            reclaimable_bytes: file_stats.values().map(|s| s.dead_bytes).sum(),
+            index_memory_bytes,
+        }
+    }
+
+    /// 每个 data file 的空间使用情况，按 file id 排序
+    pub fn file_stats(&self) -> Vec<FileStat> {
+        let mut file_stats: Vec<FileStat> = self.file_stats.read().values().copied().collect();
+        file_stats.sort_by_key(|file_stat| file_stat.file_id);
+        file_stats
     }
 }
 
 impl Engine {
-    /// 存储 kv, k不能为空, v 也不能为空
-    pub fn put(&self, key: String, value: Vec<u8>) -> R<()> {
+    /// 存储 kv, k不能为空, v 也不能为空, k 可以是任意字节数组
+    pub fn put<K: AsRef<[u8]>>(&self, key: K, value: Vec<u8>) -> R<()> {
+        let key = key.as_ref();
         if key.is_empty() {
             return Err(EmptyKey);
         }
 
-        if value.len() <= 0 {
+        if value.is_empty() {
             return Err(EmptyValue);
         }
 
-        let mut entry = Entry::new(key, value).unwrap();
-        let _ = self.append_entry_to_active_file(&mut entry);
+        self.check_key_size(key)?;
+
+        let mut entry = Entry::new(key.to_vec(), value)?;
+        self.append_entry_to_active_file(&mut entry)?;
         Ok(())
     }
 
-    pub fn read(&self, key: String) -> R<Vec<u8>> {
+    /// 存储 kv，写入 ttl 之后过期，过期的 key 读不到，启动时不会加入索引，merge 时被回收
+    pub fn put_with_ttl<K: AsRef<[u8]>>(&self, key: K, value: Vec<u8>, ttl: Duration) -> R<()> {
+        let key = key.as_ref();
         if key.is_empty() {
             return Err(EmptyKey);
         }
 
-        // 1. 读 index
-        let mem_index_read_guard = self.mem_index.read();
-        let meta_data = mem_index_read_guard.as_ref().get(&key);
+        if value.is_empty() {
+            return Err(EmptyValue);
+        }
+
+        self.check_key_size(key)?;
+
+        let mut entry = Entry::new(key.to_vec(), value)?;
+        entry.set_ttl(ttl);
+        self.append_entry_to_active_file(&mut entry)?;
+        Ok(())
+    }
+
+    /// 把 key 的过期时间重新设置为现在之后 ttl，key 不存在或者已经过期时返回 KeyNotExist。
+    /// 读取和写入都在 active file 的可升级读锁内完成，期间不会有其他写入覆盖或者删除 key
+    pub fn expire<K: AsRef<[u8]>>(&self, key: K, ttl: Duration) -> R<()> {
+        let key = key.as_ref();
+        if key.is_empty() {
+            return Err(EmptyKey);
+        }
+
+        let mut active_file = self.active_file.upgradable_read();
+        let entry = {
+            let older_files = self.older_files.read();
+            let meta_data = self.mem_index.read().get(key)?.ok_or(KeyNotExist)?;
+            Self::read_entry_at(&active_file, &older_files, &meta_data)?
+        };
+        if entry.is_expired() {
+            return Err(KeyNotExist);
+        }
+        let mut entry = Entry::new(key.to_vec(), entry.v().to_owned())?;
+        entry.set_ttl(ttl);
+        self.append_entries(&mut active_file, std::slice::from_ref(&entry))?;
+        Ok(())
+    }
 
-        if meta_data.is_none() {
+    /// 过期的 key 返回 Nil
+    pub fn read<K: AsRef<[u8]>>(&self, key: K) -> R<Vec<u8>> {
+        let (_, entry) = self.read_with_meta_data(key.as_ref())?;
+        if entry.is_expired() {
             return Err(Nil);
         }
+        Ok((*entry.v()).to_owned())
+    }
 
-        let meta_data = meta_data.unwrap();
-        drop(mem_index_read_guard);
+    /// 读取 key 对应的 entry 以及读取时 index 中的 MetaData，不检查 entry 是否过期
+    pub(crate) fn read_with_meta_data(&self, key: &[u8]) -> R<(MetaData, Entry)> {
+        if key.is_empty() {
+            return Err(EmptyKey);
+        }
 
-        // 2. 读 file 中的 data
+        // 1. 读 index, 加锁顺序与写入一致: active file -> older files -> index
+        // 读完 data 之前一直持有 file 的读锁，避免 merge 替换掉 index 指向的 older file
         let active_file_read_guard = self.active_file.read();
-        let mut buf = vec![0; meta_data.entry_sz];
-        let data: Vec<u8> = if active_file_read_guard.file_id() == meta_data.file_id {
-            let _ = active_file_read_guard
-                .read_with_given_pos(meta_data.entry_start_pos, &mut buf)
-                .unwrap();
-            drop(active_file_read_guard);
-            buf
-        } else {
-            drop(active_file_read_guard);
-            let older_file_read_guard = self.older_files.read();
-            let target_old_file = older_file_read_guard.get(&meta_data.file_id).unwrap();
-            let _ = target_old_file
-                .read_with_given_pos(meta_data.entry_start_pos, &mut buf)
-                .unwrap();
-            drop(older_file_read_guard);
-            buf
-        };
+        let older_file_read_guard = self.older_files.read();
+        let mem_index_read_guard = self.mem_index.read();
+        let meta_data = mem_index_read_guard.as_ref().get(key)?.ok_or(Nil)?;
+        drop(mem_index_read_guard);
 
-        // 3. 校验 crc 并解析
-        let entry = Entry::decode(data);
-        let disk_checksum = entry.crc();
-        let calculated_checksum = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(entry.v());
-        if disk_checksum != calculated_checksum {
-            // crc 校验不一致
-            return Err(DataCorrupted);
+        // 2. 读 file 中的 entry 并校验 crc
+        let entry =
+            Self::read_entry_at(&active_file_read_guard, &older_file_read_guard, &meta_data)?;
+        drop(older_file_read_guard);
+        drop(active_file_read_guard);
+        Ok((meta_data, entry))
+    }
+
+    /// 读取 meta_data 指向的 entry, 调用方需要持有 active file 和 older files 的读锁
+    pub(crate) fn read_entry_at(
+        active_file: &DataFile,
+        older_files: &HashMap<u32, DataFile>,
+        meta_data: &MetaData,
+    ) -> R<Entry> {
+        if active_file.file_id() == meta_data.file_id {
+            active_file.read_entry(meta_data.entry_start_pos, meta_data.entry_sz)
+        } else {
+            let target_old_file = older_files.get(&meta_data.file_id).unwrap();
+            target_old_file.read_entry(meta_data.entry_start_pos, meta_data.entry_sz)
         }
-        return Ok((*entry.v()).to_owned());
     }
 
     /// 在 active file 写入一个 tomb。删除 keydir 对应的索引
     /// tombstone 就是 value_sz 是 0，value 是 len 为 0 的 vec
-    pub fn delete(&self, key: String) -> R<Vec<u8>> {
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> R<Vec<u8>> {
+        let key = key.as_ref();
+        self.check_key_size(key)?;
         // 先判断 key 是否存在
         let read_guard = self.mem_index.read();
-        if read_guard.get(&key).is_none() {
+        if read_guard.get(key)?.is_none() {
             return Err(KeyNotExist);
         }
         drop(read_guard);
 
-        let mut tombstone = Entry::get_tombstone_with_given_key(key.clone()).unwrap();
-        let res = self.read(key.clone()).unwrap();
-        let _ = self.append_entry_to_active_file(&mut tombstone);
-        let mem_index_write_guard = self.mem_index.write();
-        mem_index_write_guard.delete(&key);
+        // 已经过期的 key 视为不存在
+        let res = match self.read(key) {
+            Ok(res) => res,
+            Err(Nil) => return Err(KeyNotExist),
+            Err(e) => return Err(e),
+        };
+        let mut tombstone = Entry::get_tombstone_with_given_key(key.to_vec())?;
+        self.append_entry_to_active_file(&mut tombstone)?;
         Ok(res)
     }
 
     /// 将 key 的值更新为 new_value, 返回 old value
-    pub fn update(&self, key: String, new_value: Vec<u8>) -> R<Vec<u8>> {
-        let old_val = self.delete(key.clone());
-        let _ = self.put(key, new_value);
+    pub fn update<K: AsRef<[u8]>>(&self, key: K, new_value: Vec<u8>) -> R<Vec<u8>> {
+        let key = key.as_ref();
+        let old_val = self.delete(key);
+        self.put(key, new_value)?;
         old_val
     }
 
+    /// key 超过索引能保存的大小时返回 KeyTooLarge，必须在写入 data file 之前检查，
+    /// 否则写入的 entry 无法加入索引，之后重建索引时也会失败
+    pub(crate) fn check_key_size(&self, key: &[u8]) -> R<()> {
+        let max_key_size = self.mem_index.read().max_key_size();
+        if key.len() > max_key_size {
+            return Err(KeyTooLarge(max_key_size));
+        }
+        Ok(())
+    }
+
     fn append_entry_to_active_file(&self, entry: &mut Entry) -> R<MetaData> {
-        let dir_path = self.options.dir_path.clone();
-        let data: Vec<u8> = entry.encode();
-        let entry_sz = data.len();
+        let meta_datas = self.append_entries_to_active_file(std::slice::from_ref(entry))?;
+        Ok(meta_datas[0])
+    }
 
-        // 1. 获取 active file
-        let mut active_file = self.active_file.write();
+    /// 将多个 entry 作为一次写入追加到同一个 active file 中，返回每个 entry 的 MetaData
+    pub(crate) fn append_entries_to_active_file(&self, entries: &[Entry]) -> R<Vec<MetaData>> {
+        // 1. 获取 active file, 可升级的读锁同一时刻只有一个, 写入之间互斥, 但不阻塞读
+        self.append_entries(&mut self.active_file.upgradable_read(), entries)
+    }
+
+    /// 调用方已经持有 active file 的可升级读锁，用于在写入前需要检查 index 的场景。
+    /// 只有切换 active file 时才升级为写锁
+    pub(crate) fn append_entries(
+        &self,
+        active_file: &mut RwLockUpgradableReadGuard<'_, DataFile>,
+        entries: &[Entry],
+    ) -> R<Vec<MetaData>> {
+        let dir_path = self.options.dir_path.clone();
+        let mut data: Vec<u8> = Vec::new();
+        for entry in entries {
+            data.extend(entry.encode());
+        }
+        let data_sz = data.len();
 
         // 2. 如果超过阈值，关闭 active file，创建 new file
         let next_write_pos = active_file.next_write_begin_pos();
-        if next_write_pos + entry_sz > self.options.file_threshold {
+        if next_write_pos + data_sz > self.options.file_threshold {
             // 2.1 sync 当前的 active file，将 page cache 刷盘
             active_file.sync()?;
 
-            // 2.2 active file 加入到 older files 中
+            // 2.2 创建 new file 作为 active file
             let curr_active_file_id = active_file.file_id();
-            let mut write_guard = self.older_files.write();
-            write_guard.insert(
-                curr_active_file_id,
-                DataFile::new(dir_path.clone(), curr_active_file_id).unwrap(),
-            );
-
-            // 2.3 创建 new file 作为 active file
             let new_file = DataFile::new(dir_path.clone(), curr_active_file_id + 1)?;
-            *active_file = new_file;
+            active_file.with_upgraded(|active_file| -> R<()> {
+                let mut old_file = std::mem::replace(active_file, new_file);
+
+                // 2.3 原 active file 变为 immutable，加入到 older files 中
+                old_file.set_filetype(DataFileType::OLD);
+                if self.options.io_type != IOType::StandardFIO {
+                    old_file.set_io_manager(self.options.io_type)?;
+                }
+                let mut write_guard = self.older_files.write();
+                write_guard.insert(curr_active_file_id, old_file);
+                Ok(())
+            })?;
+
+            // 2.4 新文件的文件头也计入总字节数
+            add_total_bytes(
+                &mut self.file_stats.write(),
+                active_file.file_id(),
+                active_file.next_write_begin_pos(),
+            );
         }
 
-        let write_begin_pos = active_file.next_write_begin_pos();
+        let mut write_begin_pos = active_file.next_write_begin_pos();
 
         // 3. 写入 disk, 这里会修改 next_write_pos, 所以需要先保存下来
         active_file.append(data)?;
@@ -233,57 +610,267 @@ impl Engine {
             active_file.sync()?;
         }
 
-        // 4. 更新内存 index
-        let meta_data = MetaData::new(
-            active_file.file_id(),
-            entry.get_self_size(),
-            write_begin_pos,
-            entry.tstamp(),
-        );
-        let mem_index_write_guard = self.mem_index.write();
-        if !mem_index_write_guard.put((*entry.k()).parse().unwrap(), meta_data) {
-            return Err(Failed2UpdateMemIndex);
+        // 4. 更新内存 index, tombstone 删除对应的索引, 被覆盖的版本保存到存活的快照中
+        // 5. 更新文件统计, 被覆盖的旧 entry, tombstone 和 batch 提交标记都是可回收的
+        let file_id = active_file.file_id();
+        // index 内部加锁，这里只需要读锁，其他 key 的读不会被阻塞
+        let mem_index = self.mem_index.read();
+        let snapshots = self.snapshots.read();
+        let mut file_stats = self.file_stats.write();
+        let mut meta_datas = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let meta_data = MetaData::new(
+                file_id,
+                entry.get_self_size(),
+                write_begin_pos,
+                entry.tstamp(),
+            );
+            write_begin_pos += meta_data.entry_sz;
+            meta_datas.push(meta_data);
+            add_total_bytes(&mut file_stats, file_id, meta_data.entry_sz);
+            if entry.is_batch_finished() {
+                add_dead_bytes(&mut file_stats, file_id, meta_data.entry_sz);
+                continue;
+            }
+
+            let old_meta_data = mem_index.get(entry.k())?;
+            if !snapshots.is_empty() {
+                snapshots.retain_version(entry.k(), old_meta_data);
+            }
+            if entry.is_tombstone() {
+                mem_index.delete(entry.k())?;
+            } else if !mem_index.put(entry.k().to_vec(), meta_data) {
+                return Err(Failed2UpdateMemIndex);
+            }
+
+            if let Some(old_meta_data) = old_meta_data {
+                add_dead_bytes(
+                    &mut file_stats,
+                    old_meta_data.file_id,
+                    old_meta_data.entry_sz,
+                );
+            }
+            if entry.is_tombstone() {
+                add_dead_bytes(&mut file_stats, file_id, meta_data.entry_sz);
+            }
+        }
+        Ok(meta_datas)
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        // 只有 Engine::open 返回的 Engine 才会执行关闭逻辑
+        if let Some(mut merge_worker) = self.merge_worker.take() {
+            merge_worker.stop();
+            if let Some(mut checkpoint_worker) = self.checkpoint_worker.take() {
+                checkpoint_worker.stop();
+            }
+            if self.options.merge_on_close {
+                if let Err(e) = self.merge() {
+                    error!("failed to merge on close, {}", e);
+                }
+            }
+            if let Err(e) = self.persist_index() {
+                error!("failed to persist index on close, {}", e);
+            }
+            if self.options.checkpoint_on_close {
+                if let Err(e) = self.checkpoint() {
+                    error!("failed to write index checkpoint on close, {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// 扫描一个 data file 得到的索引更新，按生效的顺序排列
+struct FileRecords {
+    file_id: u32,
+
+    /// (key, MetaData, 是否是 tombstone 或者已经过期)
+    records: Vec<(Vec<u8>, MetaData, bool)>,
+
+    /// batch 提交标记和没有提交的 batch 占用的字节数
+    dead_bytes: usize,
+
+    /// 读到的最大 batch 序列号
+    max_batch_seq: u64,
+}
+
+impl FileRecords {
+    /// WriteBatch 中的 entry 只有读到对应的提交标记后才生效，没有提交标记的 entry 直接丢弃
+    fn collect(file_id: u32, entry_with_metadatas: Vec<EntryWithMetaData>) -> Self {
+        let mut file_records = Self {
+            file_id,
+            records: Vec::with_capacity(entry_with_metadatas.len()),
+            dead_bytes: 0,
+            max_batch_seq: 0,
+        };
+        let mut pending_batches: HashMap<u64, Vec<EntryWithMetaData>> = HashMap::new();
+        for entry_with_metadata in entry_with_metadatas {
+            let batch_seq = entry_with_metadata.entry.batch_seq();
+            file_records.max_batch_seq = file_records.max_batch_seq.max(batch_seq);
+            if batch_seq == 0 {
+                file_records.push(entry_with_metadata);
+            } else if entry_with_metadata.entry.is_batch_finished() {
+                file_records.dead_bytes += entry_with_metadata.meta_data.entry_sz;
+                for entry_with_metadata in pending_batches.remove(&batch_seq).unwrap_or_default() {
+                    file_records.push(entry_with_metadata);
+                }
+            } else {
+                pending_batches
+                    .entry(batch_seq)
+                    .or_default()
+                    .push(entry_with_metadata);
+            }
+        }
+
+        // 没有提交的 batch 是可回收的
+        for entry_with_metadata in pending_batches.into_values().flatten() {
+            file_records.dead_bytes += entry_with_metadata.meta_data.entry_sz;
+        }
+        file_records
+    }
+
+    /// 过期的 entry 与 tombstone 一样删除之前的版本
+    fn push(&mut self, entry_with_metadata: EntryWithMetaData) {
+        let is_tombstone =
+            entry_with_metadata.entry.is_tombstone() || entry_with_metadata.entry.is_expired();
+        let meta_data = entry_with_metadata.meta_data;
+        self.records
+            .push((entry_with_metadata.entry.into_k(), meta_data, is_tombstone));
+    }
+
+    /// 读取 hint file, hint file 不存在或者损坏时返回 None，由调用方退回到扫描 data file
+    fn from_hint_file(dir_path: &str, file_id: u32, io_type: IOType) -> Option<Self> {
+        if !HintFile::exists(dir_path, file_id) {
+            return None;
         }
-        Ok(meta_data)
+        let hints = match HintFile::open(dir_path, file_id, io_type).and_then(|h| h.get_all_hints())
+        {
+            Ok(hints) => hints,
+            Err(e) => {
+                warn!(
+                    "failed to read hint file {}, fall back to data file, {}",
+                    file_id, e
+                );
+                return None;
+            }
+        };
+        Some(Self {
+            file_id,
+            records: hints
+                .into_iter()
+                .map(|hint| {
+                    let meta_data = hint.meta_data(file_id);
+                    let is_expired = hint.is_expired();
+                    (hint.into_k(), meta_data, is_expired)
+                })
+                .collect(),
+            dead_bytes: 0,
+            max_batch_seq: 0,
+        })
     }
+
+    /// 更新索引的同时统计每个文件中被覆盖或者删除的字节数，返回读到的最大 batch 序列号
+    fn apply(self, mem_index: &dyn Indexer, file_stats: &mut HashMap<u32, FileStat>) -> R<u64> {
+        add_dead_bytes(file_stats, self.file_id, self.dead_bytes);
+        for (key, meta_data, is_tombstone) in self.records {
+            if let Some(old_meta_data) = mem_index.get(&key)? {
+                add_dead_bytes(file_stats, old_meta_data.file_id, old_meta_data.entry_sz);
+            }
+            if is_tombstone {
+                mem_index.delete(&key)?;
+                add_dead_bytes(file_stats, meta_data.file_id, meta_data.entry_sz);
+            } else if !mem_index.put(key, meta_data) {
+                return Err(Failed2UpdateMemIndex);
+            }
+        }
+        Ok(self.max_batch_seq)
+    }
+}
+
+/// 所有 data file 的 id、大小和末尾内容的校验和，用来判断保存在磁盘上的索引是否与 data file 一致
+pub(crate) fn data_files_checksum<'a>(data_files: impl Iterator<Item = &'a DataFile>) -> R<u32> {
+    let mut files: Vec<&DataFile> = data_files.collect();
+    files.sort_by_key(|f| f.file_id());
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let mut digest = crc.digest();
+    for file in files {
+        let len = file.next_write_begin_pos();
+        digest.update(&file.file_id().to_le_bytes());
+        digest.update(&(len as u64).to_le_bytes());
+        digest.update(&file.tail_checksum(len)?.to_le_bytes());
+    }
+    Ok(digest.finalize())
 }
 
-fn load_data_files(dir_path: String) -> R<Vec<DataFile>> {
+fn load_data_files(dir_path: String, io_type: IOType) -> R<Vec<DataFile>> {
     let res = fs::read_dir(Path::new(dir_path.as_str()));
     if res.is_err() {
         return Err(Failed2ReadDBDir);
     }
 
-    let mut data_files: Vec<DataFile> = Vec::new();
-    for file in res.unwrap() {
-        if let Ok(entry) = file {
-            // 判断是否是数据文件
-            if !entry
-                .file_name()
-                .into_string()
-                .unwrap()
-                .ends_with(DATA_FILE_SUFFIX)
-            {
-                continue;
-            }
-            let datafile = DataFile::create_from_full_path(
-                entry.path().display().to_string(),
-                DataFileType::OLD,
-            )?;
-            data_files.push(datafile);
+    let mut data_file_paths: Vec<(u32, PathBuf)> = Vec::new();
+    for entry in res.unwrap().flatten() {
+        // 判断是否是数据文件
+        let file_name = entry.file_name().into_string().unwrap_or_default();
+        if let Some(file_id) = file_name
+            .strip_suffix(DATA_FILE_SUFFIX)
+            .and_then(|file_id| file_id.parse::<u32>().ok())
+        {
+            data_file_paths.push((file_id, entry.path()));
         }
     }
 
-    // 从小到大排序, 找到最大 id 将类型更新为 active
-    let num: usize = data_files.len();
-    data_files.sort_by(|a, b| a.file_id().cmp(&b.file_id()));
-    data_files[num - 1].set_filetype(DataFileType::ACTIVE);
+    // 空目录，创建第一个 data file 作为 active file
+    if data_file_paths.is_empty() {
+        return Ok(vec![DataFile::new(dir_path, 0)?]);
+    }
+
+    // 从小到大排序, 最大 id 的文件是 active file
+    data_file_paths.sort();
+    let (_, active_file_path) = data_file_paths.pop().unwrap();
+    let mut data_files: Vec<DataFile> = Vec::new();
+    for (_, path) in data_file_paths {
+        data_files.push(DataFile::create_from_full_path(
+            path.display().to_string(),
+            DataFileType::OLD,
+            io_type,
+        )?);
+    }
+    let mut active_file = open_active_file(&active_file_path, io_type)?;
+    active_file.set_filetype(DataFileType::ACTIVE);
+    data_files.push(active_file);
     Ok(data_files)
 }
 
+/// 打开已存在的 active file。创建 active file 时崩溃会留下文件头不完整的文件，
+/// 其中还没有任何 entry，清空后重新写入文件头
+fn open_active_file(path: &Path, io_type: IOType) -> R<DataFile> {
+    let full_path = path.display().to_string();
+    match DataFile::create_from_full_path(full_path.clone(), DataFileType::OLD, io_type) {
+        Err(UnknownDataFileFormat)
+            if fs::metadata(path).is_ok_and(|m| (m.len() as usize) < DATA_FILE_HEADER_SIZE) =>
+        {
+            warn!("data file {} has a torn header, rewrite it", full_path);
+            let file = OpenOptions::new().write(true).open(path).map_err(|e| {
+                error!("failed to open data file {}, {}", full_path, e);
+                Failed2OpenDataFile
+            })?;
+            file.set_len(0).map_err(|e| {
+                error!("failed to truncate data file {}, {}", full_path, e);
+                Failed2Write2DataFile
+            })?;
+            DataFile::create_from_full_path(full_path, DataFileType::OLD, io_type)
+        }
+        res => res,
+    }
+}
+
 fn check_options(opts: &mut Options) -> Option<E> {
     let dir_path = opts.dir_path.clone();
-    if dir_path.len() == 0 {
+    if dir_path.is_empty() {
         return Some(DirPathIsEmpty);
     }
 
@@ -292,25 +879,39 @@ fn check_options(opts: &mut Options) -> Option<E> {
         opts.file_threshold = 200 * 1024;
     }
 
+    if opts.index_shards == 0 {
+        opts.index_shards = 16;
+    }
+
+    if !(0.0..=1.0).contains(&opts.merge_ratio_threshold) {
+        return Some(InvalidMergeRatio);
+    }
+
+    // entry 的位置不会超过 file_threshold，紧凑索引用 u32 保存
+    if matches!(opts.index_type, IndexType::CompactHash) && opts.file_threshold > u32::MAX as usize
+    {
+        return Some(FileThresholdTooLarge);
+    }
+
     None
 }
 
 #[cfg(test)]
-mod tests {
-    use index::keydir;
-
+pub(crate) mod tests {
     use super::*;
-    use crate::index::keydir::KeyDir;
+    use crate::batch::WriteBatch;
+    use crate::options::IteratorOptions;
     use std::fs::OpenOptions;
     use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn test_put_and_read() {
         let engine = get_engine();
         engine
-            .put("hello".to_string(), "world".to_string().into_bytes())
+            .put("hello", "world".to_string().into_bytes())
             .unwrap();
-        let vec = engine.read("hello".to_string()).unwrap();
+        let vec = engine.read("hello").unwrap();
         println!("{:?}", String::from_utf8(vec).unwrap());
     }
 
@@ -318,12 +919,12 @@ mod tests {
     fn test_delete() {
         let engine = get_engine();
         engine
-            .put("hello".to_string(), "world".to_string().into_bytes())
+            .put("hello", "world".to_string().into_bytes())
             .unwrap();
-        let vec = engine.read("hello".to_string()).unwrap();
+        let vec = engine.read("hello").unwrap();
         println!("{:?}", String::from_utf8(vec).unwrap());
-        let _vec = engine.delete("hello".to_string()).unwrap();
-        let vec = engine.read("hello".to_string()).unwrap_or_else(|e| {
+        let _vec = engine.delete("hello").unwrap();
+        let vec = engine.read("hello").unwrap_or_else(|e| {
             println!("{}", e);
             vec![]
         });
@@ -334,136 +935,1117 @@ mod tests {
     fn test_put_and_read_chinese() {
         let engine = get_engine();
         // 测试中文，将 你好-世界 改为 你好-中国
-        engine
-            .put("你好".to_string(), "世界".to_string().into_bytes())
-            .unwrap();
-        println!(
-            "{:?}",
-            String::from_utf8(engine.read("你好".to_string()).unwrap())
-        );
+        engine.put("你好", "世界".to_string().into_bytes()).unwrap();
+        println!("{:?}", String::from_utf8(engine.read("你好").unwrap()));
         let _ = engine
-            .update("你好".to_string(), "中国".to_string().into_bytes())
+            .update("你好", "中国".to_string().into_bytes())
             .unwrap();
-        println!(
-            "{:?}",
-            String::from_utf8(engine.read("你好".to_string()).unwrap())
-        );
+        println!("{:?}", String::from_utf8(engine.read("你好").unwrap()));
     }
 
     #[test]
     fn update() {
         let engine = get_engine();
-        engine
-            .put("hello".to_string(), "1".to_string().into_bytes())
-            .unwrap();
-        println!(
-            "{:?}",
-            String::from_utf8(engine.read("hello".to_string()).unwrap())
-        );
+        engine.put("hello", "1".to_string().into_bytes()).unwrap();
+        println!("{:?}", String::from_utf8(engine.read("hello").unwrap()));
         let _ = engine
-            .update("hello".to_string(), "wow".to_string().into_bytes())
+            .update("hello", "wow".to_string().into_bytes())
             .unwrap();
-        println!(
-            "{:?}",
-            String::from_utf8(engine.read("hello".to_string()).unwrap())
-        );
+        println!("{:?}", String::from_utf8(engine.read("hello").unwrap()));
     }
 
     #[test]
     fn test_bootstrap() {
-        let engine = open_engine();
+        let engine = get_engine();
+        let options = (*engine.options).clone();
+        engine.put("hello1", "1".to_string().into_bytes()).unwrap();
+        engine.put("hello2", "2".to_string().into_bytes()).unwrap();
+        engine.put("hello3", "三".to_string().into_bytes()).unwrap();
+        engine.put("hello4", "四".to_string().into_bytes()).unwrap();
+        engine
+            .put("hello5", "five".to_string().into_bytes())
+            .unwrap();
+        let _ = engine.delete("hello3");
+        let _ = engine.update("hello4", "④".to_string().into_bytes());
+        drop(engine);
 
-        let r1 = engine.read("hello1".to_string()).unwrap();
-        println!("{:?}", String::from_utf8(r1));
+        let engine = open_engine(options);
 
-        let r2 = engine.read("hello2".to_string()).unwrap();
-        println!("{:?}", String::from_utf8(r2));
+        let r1 = engine.read("hello1").unwrap();
+        assert_eq!(r1, "1".as_bytes());
 
-        let r3 = engine.read("hello3".to_string()).unwrap();
-        println!("{:?}", String::from_utf8(r3));
+        let r2 = engine.read("hello2").unwrap();
+        assert_eq!(r2, "2".as_bytes());
 
-        let r4 = engine.read("hello4".to_string()).unwrap();
-        println!("{:?}", String::from_utf8(r4));
+        let r3 = engine.read("hello3").unwrap_err();
+        println!("{}", r3);
 
-        let r5 = engine.read("hello5".to_string()).unwrap();
-        println!("{:?}", String::from_utf8(r5));
+        let r4 = engine.read("hello4").unwrap();
+        assert_eq!(r4, "④".as_bytes());
+
+        let r5 = engine.read("hello5").unwrap();
+        assert_eq!(r5, "five".as_bytes());
     }
 
     #[test]
-    fn test_multiple_put_and_read() {
-        let engine = get_engine();
+    fn test_bootstrap_across_multiple_files() {
+        let mut options = get_default_options();
+        options.file_threshold = 64;
+        let engine = open_engine(options.clone());
+        for i in 0..50 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(!engine.older_files.read().is_empty());
+        drop(engine);
+
+        let engine = open_engine(options);
+        for i in 0..50 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_every_index_type() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.file_threshold = 256;
+            options.index_type = index_type.clone();
+            let engine = open_engine(options.clone());
+            for i in 0..50 {
+                engine
+                    .put(format!("key-{:02}", i), format!("value-{}", i).into_bytes())
+                    .unwrap();
+            }
+            for i in (0..50).step_by(5) {
+                engine.delete(format!("key-{:02}", i)).unwrap();
+            }
+            for i in (1..50).step_by(5) {
+                engine
+                    .update(
+                        format!("key-{:02}", i),
+                        format!("new-value-{}", i).into_bytes(),
+                    )
+                    .unwrap();
+            }
+            assert!(!engine.older_files.read().is_empty());
+
+            let check = |engine: &Engine| {
+                for i in 0..50 {
+                    let v = engine.read(format!("key-{:02}", i));
+                    match i % 5 {
+                        0 => assert!(matches!(v, Err(Nil)), "{:?}", index_type),
+                        1 => assert_eq!(v.unwrap(), format!("new-value-{}", i).into_bytes()),
+                        _ => assert_eq!(v.unwrap(), format!("value-{}", i).into_bytes()),
+                    }
+                }
+                let keys: Vec<Vec<u8>> = engine
+                    .iter(Default::default())
+                    .map(|item| item.unwrap().0)
+                    .collect();
+                assert_eq!(keys.len(), 40, "{:?}", index_type);
+                assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            };
+            check(&engine);
+            drop(engine);
+
+            // 扫描 data file 重建索引
+            let engine = open_engine(options.clone());
+            check(&engine);
+
+            // merge 之后通过 hint file 重建索引
+            engine.merge().unwrap();
+            check(&engine);
+            drop(engine);
+            let engine = open_engine(options);
+            check(&engine);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_with_mmap() {
+        let mut options = get_default_options();
+        options.file_threshold = 64;
+        let engine = open_engine(options.clone());
+        for i in 0..50 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        drop(engine);
+
+        options.io_type = IOType::MemoryMap;
+        let engine = open_engine(options);
+        for i in 0..50 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+
+        // active file 切换回标准 IO 后可以继续写入，写满后转为 mmap 的 older file
+        for i in 50..100 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        for i in 0..100 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_with_hint_files() {
+        let mut options = get_default_options();
+        options.file_threshold = 64;
+        let engine = open_engine(options.clone());
+        for i in 0..50 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+
+        // 为 older file 生成 hint file，故意漏掉 key-0，用来验证启动时确实读取了 hint file
+        let older_file_ids: Vec<u32> = engine.older_files.read().keys().copied().collect();
+        for file_id in older_file_ids {
+            let hint_file = HintFile::new(&options.dir_path, file_id).unwrap();
+            for i in 1..50 {
+                let key = format!("key-{}", i).into_bytes();
+                let meta_data = engine.mem_index.read().get(&key).unwrap().unwrap();
+                if meta_data.file_id == file_id {
+                    hint_file.write_hint(key, &meta_data, 0).unwrap();
+                }
+            }
+            hint_file.sync().unwrap();
+        }
+        drop(engine);
+
+        let engine = open_engine(options.clone());
+        assert!(matches!(engine.read("key-0"), Err(Nil)));
+        for i in 1..50 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+        drop(engine);
+
+        // hint file 损坏时退回到扫描 data file
+        let first_hint = HintFile::get_file_full_path(&options.dir_path, 0);
+        fs::write(&first_hint, [1, 2, 3]).unwrap();
+        let engine = open_engine(options.clone());
+        for i in 0..50 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+        drop(engine);
+
+        // 记录中的位置被改写时 crc 校验失败，同样退回到扫描 data file
+        let second_hint = HintFile::get_file_full_path(&options.dir_path, 1);
+        let mut bytes = fs::read(&second_hint).unwrap();
+        bytes[crate::data::hint_file::HINT_FILE_HEADER_SIZE + 24] ^= 0x10;
+        fs::write(&second_hint, bytes).unwrap();
+        let engine = open_engine(options);
+        for i in 0..50 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_file_stats() {
+        let mut options = get_default_options();
+        options.file_threshold = 160;
+        let engine = open_engine(options.clone());
+        engine.put("hello", "1".to_string().into_bytes()).unwrap();
+        let stats = engine.file_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].dead_bytes, 0);
+        assert_eq!(
+            stats[0].total_bytes,
+            engine.active_file.read().next_write_begin_pos()
+        );
+
+        // 覆盖写: 旧 entry 可回收
+        let first_entry_sz = stats[0].total_bytes - DATA_FILE_HEADER_SIZE;
         engine
-            .put("hello1".to_string(), "1".to_string().into_bytes())
+            .update("hello", "2".to_string().into_bytes())
             .unwrap();
+        // 删除: 旧 entry 和 tombstone 都可回收
+        engine.delete("hello").unwrap();
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        let stats = engine.file_stats();
+        assert!(stats.len() > 1);
+        assert_eq!(stats[0].file_id, 0);
+        assert!(stats[0].dead_bytes > 2 * first_entry_sz);
+        let total_bytes: usize = stats.iter().map(|s| s.total_bytes).sum();
+        let live_bytes: usize = stats.iter().map(|s| s.live_bytes()).sum();
+        assert!(live_bytes < total_bytes);
+        drop(engine);
+
+        // 重启后重新统计的结果与重启前一致
+        let engine = open_engine(options);
+        assert_eq!(engine.file_stats(), stats);
+
+        engine.merge().unwrap();
+        let active_file_id = engine.active_file.read().file_id();
+        for stat in engine.file_stats() {
+            if stat.file_id != active_file_id {
+                assert_eq!(stat.dead_bytes, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.file_threshold = 256;
+            options.index_type = index_type;
+            let engine = open_engine(options);
+            assert_eq!(engine.stats().key_num, 0);
+            for i in 0..20 {
+                engine
+                    .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                    .unwrap();
+            }
+            engine.delete("key-0").unwrap();
+            let stats = engine.stats();
+            assert_eq!(stats.key_num, 19);
+            assert_eq!(stats.data_file_num, engine.older_files.read().len() + 1);
+            assert!(stats.data_file_num > 1);
+            let file_stats = engine.file_stats();
+            assert_eq!(
+                stats.total_bytes,
+                file_stats.iter().map(|s| s.total_bytes).sum::<usize>()
+            );
+            assert!(stats.reclaimable_bytes > 0);
+            assert!(stats.index_memory_bytes >= 19 * "key-0".len());
+        }
+    }
+
+    #[test]
+    fn test_bptree_index_restart() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        options.index_type = IndexType::BPlusTree;
+        options.index_cache_pages = 2;
+        let engine = open_engine(options.clone());
+        for i in 0..100 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        engine.delete("key-0").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("batch", vec![1]).unwrap();
+        engine.write_batch(batch).unwrap();
+        let file_stats = engine.file_stats();
+        drop(engine);
+
+        // 正常关闭之后直接使用索引文件，不需要扫描 data file
+        let engine = open_engine(options.clone());
+        assert!(engine.mem_index.read().persisted_state().is_some());
+        assert_eq!(engine.file_stats(), file_stats);
+        assert_eq!(engine.batch_seq.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.stats().key_num, 100);
+        assert!(matches!(engine.read("key-0"), Err(Nil)));
+        for i in 1..100 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+        drop(engine);
+
+        // 用其他索引写入之后 data file 发生了变化，重建索引
+        let mut hash_options = options.clone();
+        hash_options.index_type = IndexType::Hash;
+        let engine = open_engine(hash_options);
+        engine.put("key-0", "new".as_bytes().to_vec()).unwrap();
+        drop(engine);
+        let engine = open_engine(options);
+        assert!(engine.mem_index.read().persisted_state().is_none());
+        assert_eq!(engine.read("key-0").unwrap(), "new".as_bytes());
+        assert_eq!(engine.stats().key_num, 101);
+    }
+
+    #[test]
+    fn test_bptree_key_too_large() {
+        let mut options = get_default_options();
+        options.index_type = IndexType::BPlusTree;
+        let engine = open_engine(options.clone());
+        engine.put("small", vec![1]).unwrap();
+        let file_len = engine.active_file.read().next_write_begin_pos();
+
+        // 超过索引限制的 key 在写入 data file 之前被拒绝
+        let key = vec![b'k'; crate::index::bptree::BPTREE_MAX_KEY_SIZE + 1];
+        assert!(matches!(engine.put(&key, vec![1]), Err(KeyTooLarge(_))));
+        assert!(matches!(
+            engine.put_with_ttl(&key, vec![1], Duration::from_secs(1)),
+            Err(KeyTooLarge(_))
+        ));
+        assert!(matches!(engine.delete(&key), Err(KeyTooLarge(_))));
+        // put_with_ttl 与 put 按相同的顺序检查
+        let ttl = Duration::from_secs(1);
+        assert!(matches!(engine.put(&key, vec![]), Err(EmptyValue)));
+        assert!(matches!(
+            engine.put_with_ttl(&key, vec![], ttl),
+            Err(EmptyValue)
+        ));
+        assert!(matches!(engine.put("", vec![]), Err(EmptyKey)));
+        assert!(matches!(
+            engine.put_with_ttl("", vec![], ttl),
+            Err(EmptyKey)
+        ));
+        let mut batch = WriteBatch::new();
+        batch.put("other", vec![1]).unwrap();
+        batch.put(&key, vec![1]).unwrap();
+        assert!(matches!(engine.write_batch(batch), Err(KeyTooLarge(_))));
+        let mut txn = engine.begin_transaction();
+        assert!(matches!(txn.put(&key, vec![1]), Err(KeyTooLarge(_))));
+        assert!(matches!(txn.delete(&key), Err(KeyTooLarge(_))));
+        drop(txn);
+        assert_eq!(engine.active_file.read().next_write_begin_pos(), file_len);
+        assert!(matches!(engine.read("other"), Err(Nil)));
+        drop(engine);
+
+        // 重建索引时 data file 中没有无法加入索引的 entry
+        fs::remove_file(
+            Path::new(&options.dir_path).join(crate::index::bptree::BPTREE_INDEX_FILE_NAME),
+        )
+        .unwrap();
+        let engine = open_engine(options);
+        assert_eq!(engine.read("small").unwrap(), vec![1]);
+        assert_eq!(engine.stats().key_num, 1);
+    }
+
+    #[test]
+    fn test_bptree_index_read_error() {
+        let mut options = get_default_options();
+        options.index_type = IndexType::BPlusTree;
+        options.index_cache_pages = 1;
+        let engine = open_engine(options.clone());
+        for i in 0..500 {
+            engine.put(format!("key-{}", i), vec![1]).unwrap();
+        }
+
+        // 不在缓存中的页都读取失败，索引错误要返回给调用方，不能当作 key 不存在
+        let index_path =
+            Path::new(&options.dir_path).join(crate::index::bptree::BPTREE_INDEX_FILE_NAME);
+        let len = fs::metadata(&index_path).unwrap().len() as usize;
+        let mut bytes = fs::read(&index_path).unwrap();
+        bytes[4096..len].fill(0xff);
+        fs::write(&index_path, bytes).unwrap();
+        let file_len = engine.active_file.read().next_write_begin_pos();
+        assert!(matches!(engine.read("key-1"), Err(E::Failed2ReadIndexFile)));
+        assert!(matches!(
+            engine.delete("key-1"),
+            Err(E::Failed2ReadIndexFile)
+        ));
+        // 删除失败时没有写入 tombstone
+        assert_eq!(engine.active_file.read().next_write_begin_pos(), file_len);
+        assert!(matches!(
+            engine.put("key-1", vec![2]),
+            Err(E::Failed2ReadIndexFile)
+        ));
+    }
+
+    #[test]
+    fn test_compact_index_options() {
+        let mut options = get_default_options();
+        options.index_type = IndexType::CompactHash;
+        options.file_threshold = u32::MAX as usize + 1;
+        assert!(matches!(
+            Engine::open(options),
+            Err(E::FileThresholdTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let engine = open_engine(get_default_options());
         engine
-            .put("hello2".to_string(), "2".to_string().into_bytes())
+            .put_with_ttl(
+                "session",
+                "token".as_bytes().to_vec(),
+                Duration::from_millis(100),
+            )
             .unwrap();
         engine
-            .put("hello3".to_string(), "三".to_string().into_bytes())
+            .put_with_ttl(
+                "forever",
+                "1".as_bytes().to_vec(),
+                Duration::from_secs(3600),
+            )
             .unwrap();
+        engine.put("plain", "2".as_bytes().to_vec()).unwrap();
+        assert_eq!(engine.read("session").unwrap(), "token".as_bytes());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(engine.read("session"), Err(Nil)));
+        assert!(matches!(engine.delete("session"), Err(KeyNotExist)));
+        assert!(matches!(
+            engine.expire("session", Duration::from_secs(1)),
+            Err(KeyNotExist)
+        ));
+        assert_eq!(engine.read("forever").unwrap(), "1".as_bytes());
+        let keys: Vec<Vec<u8>> = engine
+            .iter(Default::default())
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec!["forever".as_bytes(), "plain".as_bytes()]);
+
+        // 重新设置过期时间，值不变
+        engine.expire("plain", Duration::ZERO).unwrap();
+        assert!(matches!(engine.read("plain"), Err(Nil)));
+        engine.expire("forever", Duration::ZERO).unwrap();
+        assert!(matches!(engine.read("forever"), Err(Nil)));
+        assert!(matches!(
+            engine.expire("missing", Duration::from_secs(1)),
+            Err(KeyNotExist)
+        ));
+
+        // 再次写入后不再过期
+        engine.put("session", "new".as_bytes().to_vec()).unwrap();
+        assert_eq!(engine.read("session").unwrap(), "new".as_bytes());
+    }
+
+    #[test]
+    fn test_ttl_restart_and_merge() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.file_threshold = 256;
+            options.index_type = index_type.clone();
+            let engine = open_engine(options.clone());
+            for i in 0..30 {
+                engine
+                    .put(format!("key-{:02}", i), format!("value-{}", i).into_bytes())
+                    .unwrap();
+                // 偶数 key 的新版本已经过期，旧版本也不可见
+                if i % 2 == 0 {
+                    engine
+                        .put_with_ttl(format!("key-{:02}", i), vec![0], Duration::ZERO)
+                        .unwrap();
+                }
+            }
+            let check = |engine: &Engine| {
+                for i in 0..30 {
+                    let v = engine.read(format!("key-{:02}", i));
+                    if i % 2 == 0 {
+                        assert!(matches!(v, Err(Nil)), "{:?}", index_type);
+                    } else {
+                        assert_eq!(v.unwrap(), format!("value-{}", i).into_bytes());
+                    }
+                }
+            };
+            check(&engine);
+            drop(engine);
+
+            // 启动时过期的 entry 不加入索引
+            let engine = open_engine(options.clone());
+            check(&engine);
+            // BPlusTree 的索引保存在磁盘上，过期的 key 要等到 merge 时才删除
+            if !matches!(index_type, IndexType::BPlusTree) {
+                assert_eq!(engine.stats().key_num, 15, "{:?}", index_type);
+            }
+
+            // merge 回收过期的 entry
+            let total_bytes = engine.stats().total_bytes;
+            engine.merge().unwrap();
+            check(&engine);
+            assert!(engine.stats().total_bytes < total_bytes);
+            drop(engine);
+
+            let engine = open_engine(options.clone());
+            check(&engine);
+            if !matches!(index_type, IndexType::BPlusTree) {
+                assert_eq!(engine.stats().key_num, 15, "{:?}", index_type);
+            }
+        }
+    }
+
+    #[test]
+    fn test_expire_concurrent_with_writes() {
+        let engine = open_engine(get_default_options());
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    match engine.expire("key", Duration::from_secs(3600)) {
+                        Ok(_) | Err(KeyNotExist) => {}
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            });
+            for i in 0..500u32 {
+                engine.put("key", i.to_le_bytes().to_vec()).unwrap();
+                // expire 不能用读到的旧值覆盖之后的写入
+                let v = engine.read("key").unwrap();
+                assert_eq!(u32::from_le_bytes(v.try_into().unwrap()), i);
+                engine.delete("key").unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        // 删除之后 expire 不能让 key 重新出现
+        assert!(matches!(engine.read("key"), Err(Nil)));
+    }
+
+    #[test]
+    fn test_merge_drops_expired_keys() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for i in 0..30 {
+            engine
+                .put_with_ttl(
+                    format!("key-{:02}", i),
+                    format!("value-{}", i).into_bytes(),
+                    Duration::from_millis(100),
+                )
+                .unwrap();
+        }
         engine
-            .put("hello4".to_string(), "四".to_string().into_bytes())
+            .put_with_ttl("kept", "1".as_bytes().to_vec(), Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(engine.stats().key_num, 31);
+        let snapshot = engine.snapshot();
+        engine.put("key-00", vec![1]).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        drop(snapshot);
+
+        // 过期的 key 不再重写，同时从 index 中删除
+        // active file 中的 entry 不参与 merge，过期的 key 仍然留在 index 中
+        engine.merge().unwrap();
+        assert!(engine.stats().key_num < 31);
+        assert_eq!(engine.read("key-00").unwrap(), vec![1]);
+        assert_eq!(engine.read("kept").unwrap(), "1".as_bytes());
+        assert!(matches!(engine.read("key-01"), Err(Nil)));
+
+        // merged data file 的 hint file 中也保存了过期时间
+        drop(engine);
+        let engine = open_engine(options);
+        assert_eq!(engine.read("kept").unwrap(), "1".as_bytes());
+        assert_eq!(engine.stats().key_num, 2);
+    }
+
+    #[test]
+    fn test_parallel_rebuild() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for i in 0..200 {
+            engine
+                .put(
+                    format!("key-{}", i % 70),
+                    format!("value-{}", i).into_bytes(),
+                )
+                .unwrap();
+            if i % 7 == 0 {
+                engine.delete(format!("key-{}", i % 70)).unwrap();
+            }
+            if i % 30 == 0 {
+                let mut batch = WriteBatch::new();
+                batch.put(format!("batch-{}", i), vec![1]).unwrap();
+                batch.delete(format!("key-{}", (i + 1) % 70)).unwrap();
+                engine.write_batch(batch).unwrap();
+            }
+        }
+        assert!(engine.older_files.read().len() > 10);
+        let expected: Vec<_> = engine
+            .iter(Default::default())
+            .map(|r| r.unwrap())
+            .collect();
+        let file_stats = engine.file_stats();
+        let batch_seq = engine.batch_seq.load(Ordering::SeqCst);
+        drop(engine);
+
+        // 不同的线程数得到相同的索引和文件统计
+        let check = |threads: usize, file_stats: &Vec<FileStat>| {
+            let mut options = options.clone();
+            options.index_rebuild_threads = threads;
+            let engine = open_engine(options);
+            let items: Vec<_> = engine
+                .iter(Default::default())
+                .map(|r| r.unwrap())
+                .collect();
+            assert_eq!(items, expected, "{} threads", threads);
+            assert_eq!(&engine.file_stats(), file_stats, "{} threads", threads);
+            engine
+        };
+        for threads in [0, 1, 4, 64] {
+            let engine = check(threads, &file_stats);
+            assert_eq!(engine.batch_seq.load(Ordering::SeqCst), batch_seq);
+        }
+
+        // merge 之后部分文件从 hint file 读取
+        let engine = check(4, &file_stats);
+        engine.merge().unwrap();
+        let file_stats = engine.file_stats();
+        drop(engine);
+        for threads in [1, 4] {
+            check(threads, &file_stats);
+        }
+    }
+
+    #[test]
+    fn test_scan_in_order() {
+        let dir_path = get_default_options().dir_path;
+        let data_files: Vec<DataFile> = (0..20)
+            .map(|file_id| DataFile::new(dir_path.clone(), file_id).unwrap())
+            .collect();
+        for threads in [1, 3, 8] {
+            let mut folded = Vec::new();
+            Engine::scan_in_order(
+                &data_files,
+                threads,
+                |data_file| {
+                    // 让后面的文件先扫描完
+                    std::thread::sleep(std::time::Duration::from_millis(
+                        (20 - data_file.file_id() as u64) % 4,
+                    ));
+                    Ok(data_file.file_id())
+                },
+                |data_file, file_id| {
+                    assert_eq!(data_file.file_id(), file_id);
+                    folded.push(file_id);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(folded, (0..20).collect::<Vec<_>>());
+        }
+
+        // 扫描失败时返回错误
+        let res = Engine::scan_in_order(
+            &data_files,
+            4,
+            |data_file| match data_file.file_id() {
+                5 => Err(E::DataCorrupted {
+                    file_id: 5,
+                    offset: 0,
+                }),
+                file_id => Ok(file_id),
+            },
+            |_, file_id| {
+                assert!(file_id < 5);
+                Ok(())
+            },
+        );
+        assert!(matches!(res, Err(E::DataCorrupted { file_id: 5, .. })));
+    }
+
+    #[test]
+    fn test_read_not_blocked_by_writer() {
+        let mut options = get_default_options();
+        options.index_type = IndexType::ShardedHash;
+        let engine = open_engine(options);
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+
+        // 模拟一个正在进行的写入，读仍然可以完成
+        let writer = engine.active_file.upgradable_read();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| tx.send(engine.read("a")).unwrap());
+            let value = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+            assert_eq!(value.unwrap(), "1".as_bytes());
+            drop(writer);
+        });
+    }
+
+    /// 与测试共享的索引，测试可以在 engine 之外持有索引内部的锁
+    struct SharedIndex<T>(Arc<T>);
+
+    impl<T: Indexer> Indexer for SharedIndex<T> {
+        fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+            self.0.put(key, meta_data)
+        }
+
+        fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+            self.0.get(key)
+        }
+
+        fn delete(&self, key: &[u8]) -> R<bool> {
+            self.0.delete(key)
+        }
+
+        fn iterator(&self, options: &IteratorOptions) -> Box<dyn index::IndexIterator> {
+            self.0.iterator(options)
+        }
+
+        fn key_num(&self) -> usize {
+            self.0.key_num()
+        }
+
+        fn memory_size(&self) -> usize {
+            self.0.memory_size()
+        }
+    }
+
+    #[test]
+    fn test_read_not_blocked_by_other_shard() {
+        use crate::index::sharded::ShardedKeyDir;
+
+        let mut options = get_default_options();
+        options.index_type = IndexType::ShardedHash;
+        let engine = open_engine(options);
+        let keydir = Arc::new(ShardedKeyDir::new(16));
+        *engine.mem_index.write() = Box::new(SharedIndex(keydir.clone()));
+        let blocked_key = "blocked".as_bytes().to_vec();
+        let free_key = (0..)
+            .map(|i| format!("free-{}", i).into_bytes())
+            .find(|key| !std::ptr::eq(keydir.shard(key), keydir.shard(&blocked_key)))
             .unwrap();
+        engine.put(&free_key, "1".as_bytes().to_vec()).unwrap();
+
+        // 模拟写入 blocked_key 时正在更新它所在的分片，其他分片上的 key 仍然可以读写
+        let shard_guard = keydir.shard(&blocked_key).write();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let value = engine.read(&free_key);
+                engine.put(&free_key, "2".as_bytes().to_vec()).unwrap();
+                tx.send(value).unwrap();
+            });
+            let value = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+            assert_eq!(value.unwrap(), "1".as_bytes());
+            drop(shard_guard);
+        });
+        assert_eq!(engine.read(&free_key).unwrap(), "2".as_bytes());
+    }
+
+    #[test]
+    fn test_open_with_unknown_file_format() {
+        let options = get_default_options();
+        // 旧版本的 data file 没有文件头
+        let entry = Entry::new("hello".as_bytes().to_vec(), vec![1, 2, 3]).unwrap();
+        fs::write(
+            Path::new(&options.dir_path).join("0".to_string() + DATA_FILE_SUFFIX),
+            entry.encode(),
+        )
+        .unwrap();
+        assert!(matches!(
+            Engine::open(options),
+            Err(E::UnknownDataFileFormat)
+        ));
+    }
+
+    #[test]
+    fn test_read_corrupted_entry() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".to_string().into_bytes()).unwrap();
+        engine.put("b", "2".to_string().into_bytes()).unwrap();
+        for i in 0..10 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(engine.active_file.read().file_id() > 0);
+
+        // 修改 older file 中 b 的 tstamp, 只校验 value 时无法发现
+        let meta_data = engine
+            .mem_index
+            .read()
+            .get("b".as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta_data.file_id, 0);
+        let full_path = DataFile::get_file_full_path(options.dir_path.clone(), "0".to_string());
+        let mut bytes = fs::read(&full_path).unwrap();
+        bytes[meta_data.entry_start_pos + 4] ^= 0xff;
+        fs::write(&full_path, bytes).unwrap();
+
+        assert_eq!(engine.read("a").unwrap(), "1".as_bytes());
+        assert!(matches!(
+            engine.read("b"),
+            Err(E::DataCorrupted { file_id: 0, offset }) if offset == meta_data.entry_start_pos
+        ));
+        drop(engine);
+
+        // 启动时扫描 older file 同样会校验 crc
+        assert!(matches!(
+            Engine::open(options),
+            Err(E::DataCorrupted { file_id: 0, offset }) if offset == meta_data.entry_start_pos
+        ));
+    }
+
+    #[test]
+    fn test_open_with_corrupted_entry_length() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".to_string().into_bytes()).unwrap();
+        engine.put("b", "2".to_string().into_bytes()).unwrap();
+        let meta_data = engine
+            .mem_index
+            .read()
+            .get("b".as_bytes())
+            .unwrap()
+            .unwrap();
+        engine.delete("a").unwrap();
+        for i in 0..10 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(engine.active_file.read().file_id() > 0);
+        drop(engine);
+
+        // 把 older file 中 b 的 value_sz 改大，不能把之后的 tombstone 当作文件末尾丢弃
+        let full_path = DataFile::get_file_full_path(options.dir_path.clone(), "0".to_string());
+        let mut bytes = fs::read(&full_path).unwrap();
+        assert!(bytes.len() > meta_data.entry_start_pos + meta_data.entry_sz);
+        bytes[meta_data.entry_start_pos + Entry::HEADER_SIZE - 2] = 0x01;
+        fs::write(&full_path, bytes).unwrap();
+
+        assert!(matches!(
+            Engine::open(options),
+            Err(E::DataCorrupted { file_id: 0, offset }) if offset == meta_data.entry_start_pos
+        ));
+    }
+
+    #[test]
+    fn test_open_old_version_data_files() {
+        let mut options = get_default_options();
+        options.index_type = IndexType::BTree;
+        create_dir_all(&options.dir_path).unwrap();
+        let write_old_file = |file_id: u32, version: u32, entries: &[Entry]| {
+            let mut bytes = crate::data::datafile::DATA_FILE_MAGIC.to_vec();
+            bytes.extend(&version.to_le_bytes());
+            for entry in entries {
+                bytes.extend(entry.encode_with_version(version));
+            }
+            let full_path =
+                DataFile::get_file_full_path(options.dir_path.clone(), file_id.to_string());
+            fs::write(full_path, bytes).unwrap();
+        };
+        let entry =
+            |k: &str, v: &str| Entry::new(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
+        write_old_file(0, 1, &[entry("a", "1"), entry("b", "2")]);
+        write_old_file(
+            1,
+            2,
+            &[
+                entry("a", "3"),
+                Entry::get_tombstone_with_given_key("b".as_bytes().to_vec()).unwrap(),
+            ],
+        );
+        write_old_file(2, 3, &[entry("c", "4")]);
+        // 旧版本的 hint file 格式不同，即使能解析也不使用
+        let hint_file = HintFile::new(&options.dir_path, 0).unwrap();
+        hint_file
+            .write_hint("b".as_bytes().to_vec(), &MetaData::new(0, 1, 2, 3), 0)
+            .unwrap();
+        drop(hint_file);
+
+        let check = |engine: &Engine| {
+            assert_eq!(engine.read("a").unwrap(), "3".as_bytes());
+            assert!(matches!(engine.read("b"), Err(Nil)));
+            assert_eq!(engine.read("c").unwrap(), "4".as_bytes());
+        };
+        let engine = open_engine(options.clone());
+        check(&engine);
+
+        // 旧版本的 active file 只读，写入新的 active file
+        assert_eq!(engine.active_file.read().file_id(), 3);
+        engine.put("d", "5".as_bytes().to_vec()).unwrap();
+        engine.expire("a", Duration::from_secs(3600)).unwrap();
+        drop(engine);
+
+        let engine = open_engine(options.clone());
+        check(&engine);
+        assert_eq!(engine.read("d").unwrap(), "5".as_bytes());
+
+        // merge 按当前格式重写旧版本的 entry
+        engine.merge().unwrap();
+        check(&engine);
+        assert!(engine
+            .older_files
+            .read()
+            .values()
+            .all(|f| f.version() == DATA_FILE_VERSION));
+        drop(engine);
+        let engine = open_engine(options);
+        check(&engine);
+        assert_eq!(engine.read("d").unwrap(), "5".as_bytes());
+    }
+
+    #[test]
+    fn test_truncate_torn_tail() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".to_string().into_bytes()).unwrap();
+        engine.put("b", "2".to_string().into_bytes()).unwrap();
+        let valid_len = engine.active_file.read().next_write_begin_pos();
+        drop(engine);
+
+        // 模拟崩溃: 最后一个 entry 只写入了一部分
+        let full_path = DataFile::get_file_full_path(options.dir_path.clone(), "0".to_string());
+        let torn = Entry::new("c".as_bytes().to_vec(), vec![3])
+            .unwrap()
+            .encode();
+        let mut file = OpenOptions::new().append(true).open(&full_path).unwrap();
+        file.write_all(&torn[..torn.len() - 1]).unwrap();
+        drop(file);
+
+        let engine = open_engine(options.clone());
+        assert_eq!(fs::metadata(&full_path).unwrap().len() as usize, valid_len);
+        assert_eq!(engine.read("b").unwrap(), "2".as_bytes());
+        assert!(matches!(engine.read("c"), Err(Nil)));
+        engine.put("c", "3".to_string().into_bytes()).unwrap();
+        drop(engine);
+
+        // 模拟崩溃: 最后一个 entry 长度完整但内容没有落盘
+        let mut file = OpenOptions::new().append(true).open(&full_path).unwrap();
+        file.write_all(&vec![0; torn.len()]).unwrap();
+        drop(file);
+
+        let engine = open_engine(options);
+        assert_eq!(engine.read("c").unwrap(), "3".as_bytes());
+        assert_eq!(
+            fs::metadata(&full_path).unwrap().len() as usize,
+            engine.active_file.read().next_write_begin_pos()
+        );
+        assert_eq!(
+            engine.file_stats()[0].total_bytes,
+            engine.active_file.read().next_write_begin_pos()
+        );
+    }
+
+    #[test]
+    fn test_open_with_torn_active_file_header() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".to_string().into_bytes()).unwrap();
+        drop(engine);
+
+        // 模拟崩溃: 新建 active file 时文件头只写入了一部分
+        for torn_len in [0, 3, DATA_FILE_HEADER_SIZE - 1] {
+            let file_id = fs::read_dir(&options.dir_path)
+                .unwrap()
+                .filter(|e| {
+                    let name = e.as_ref().unwrap().file_name();
+                    name.to_str().unwrap().ends_with(DATA_FILE_SUFFIX)
+                })
+                .count();
+            let full_path =
+                DataFile::get_file_full_path(options.dir_path.clone(), file_id.to_string());
+            fs::write(
+                &full_path,
+                &crate::data::datafile::DATA_FILE_MAGIC[..torn_len.min(4)],
+            )
+            .unwrap();
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&full_path)
+                .unwrap()
+                .set_len(torn_len as u64)
+                .unwrap();
+
+            let engine = open_engine(options.clone());
+            assert_eq!(engine.active_file.read().file_id() as usize, file_id);
+            assert_eq!(
+                fs::metadata(&full_path).unwrap().len() as usize,
+                DATA_FILE_HEADER_SIZE
+            );
+            assert_eq!(engine.read("a").unwrap(), "1".as_bytes());
+            engine.put("b", file_id.to_string().into_bytes()).unwrap();
+            drop(engine);
+
+            let engine = open_engine(options.clone());
+            assert_eq!(engine.read("b").unwrap(), file_id.to_string().as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let engine = get_engine();
+        let options = (*engine.options).clone();
+        let keys: Vec<Vec<u8>> = vec![
+            vec![0xff, 0xfe, 0x00, 0x01],
+            vec![0x00],
+            1024u64.to_be_bytes().to_vec(),
+            "你好".as_bytes()[..4].to_vec(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            engine.put(key, vec![i as u8 + 1]).unwrap();
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(engine.read(key).unwrap(), vec![i as u8 + 1]);
+        }
+        engine.delete(&keys[0]).unwrap();
+        assert!(engine.read(&keys[0]).is_err());
+        drop(engine);
+
+        let engine = open_engine(options);
+        assert!(engine.read(&keys[0]).is_err());
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            assert_eq!(engine.read(key).unwrap(), vec![i as u8 + 1]);
+        }
+    }
+
+    #[test]
+    fn test_multiple_put_and_read() {
+        let engine = get_engine();
+        engine.put("hello1", "1".to_string().into_bytes()).unwrap();
+        engine.put("hello2", "2".to_string().into_bytes()).unwrap();
+        engine.put("hello3", "三".to_string().into_bytes()).unwrap();
+        engine.put("hello4", "四".to_string().into_bytes()).unwrap();
         engine
-            .put("hello5".to_string(), "five".to_string().into_bytes())
+            .put("hello5", "five".to_string().into_bytes())
             .unwrap();
 
-        let r1 = engine.read("hello1".to_string()).unwrap();
+        let r1 = engine.read("hello1").unwrap();
         println!("{:?}", String::from_utf8(r1));
 
-        let r2 = engine.read("hello2".to_string()).unwrap();
+        let r2 = engine.read("hello2").unwrap();
         println!("{:?}", String::from_utf8(r2));
 
-        let r3 = engine.read("hello3".to_string()).unwrap();
+        let r3 = engine.read("hello3").unwrap();
         println!("{:?}", String::from_utf8(r3));
 
-        let r4 = engine.read("hello4".to_string()).unwrap();
+        let r4 = engine.read("hello4").unwrap();
         println!("{:?}", String::from_utf8(r4));
 
-        let r5 = engine.read("hello5".to_string()).unwrap();
+        let r5 = engine.read("hello5").unwrap();
         println!("{:?}", String::from_utf8(r5));
     }
 
     #[test]
     fn test_multiple_delete_and_update() {
         let engine = get_engine();
+        engine.put("hello1", "1".to_string().into_bytes()).unwrap();
+        engine.put("hello2", "2".to_string().into_bytes()).unwrap();
+        engine.put("hello3", "三".to_string().into_bytes()).unwrap();
+        engine.put("hello4", "四".to_string().into_bytes()).unwrap();
         engine
-            .put("hello1".to_string(), "1".to_string().into_bytes())
-            .unwrap();
-        engine
-            .put("hello2".to_string(), "2".to_string().into_bytes())
-            .unwrap();
-        engine
-            .put("hello3".to_string(), "三".to_string().into_bytes())
-            .unwrap();
-        engine
-            .put("hello4".to_string(), "四".to_string().into_bytes())
-            .unwrap();
-        engine
-            .put("hello5".to_string(), "five".to_string().into_bytes())
+            .put("hello5", "five".to_string().into_bytes())
             .unwrap();
 
-        let _ = engine.delete("hello1".to_string());
-        let _ = engine.delete("hello3".to_string());
-        let _ = engine.update("hello5".to_string(), "five-five".to_string().into_bytes());
+        let _ = engine.delete("hello1");
+        let _ = engine.delete("hello3");
+        let _ = engine.update("hello5", "five-five".to_string().into_bytes());
 
-        let r1 = engine.read("hello1".to_string()).unwrap_err();
-        println!("{}", r1.to_string());
+        let r1 = engine.read("hello1").unwrap_err();
+        println!("{}", r1);
 
-        let r2 = engine.read("hello2".to_string()).unwrap();
+        let r2 = engine.read("hello2").unwrap();
         println!("{:?}", String::from_utf8(r2));
 
-        let r3 = engine.read("hello3".to_string()).unwrap_err();
+        let r3 = engine.read("hello3").unwrap_err();
         println!("{:?}", r3.to_string());
 
-        let r4 = engine.read("hello4".to_string()).unwrap();
+        let r4 = engine.read("hello4").unwrap();
         println!("{:?}", String::from_utf8(r4));
 
-        let r5 = engine.read("hello5".to_string()).unwrap();
+        let r5 = engine.read("hello5").unwrap();
         println!("{:?}", String::from_utf8(r5));
 
-        let _ = engine.update("hello4".to_string(), "④".to_string().into_bytes());
-        let r4 = engine.read("hello4".to_string()).unwrap();
+        let _ = engine.update("hello4", "④".to_string().into_bytes());
+        let r4 = engine.read("hello4").unwrap();
         println!("{:?}", String::from_utf8(r4));
     }
 
@@ -471,14 +2053,12 @@ mod tests {
     pub fn test_delete_and_put() {
         let engine = get_engine();
         engine
-            .put("hello".to_string(), "世界".to_string().into_bytes())
+            .put("hello", "世界".to_string().into_bytes())
             .unwrap();
-        let old = engine.delete("hello".to_string()).unwrap();
+        let old = engine.delete("hello").unwrap();
         println!("{:?}", String::from_utf8(old));
-        engine
-            .put("hello".to_string(), "wow".to_string().into_bytes())
-            .unwrap();
-        let vec = engine.read("hello".to_string()).unwrap();
+        engine.put("hello", "wow".to_string().into_bytes()).unwrap();
+        let vec = engine.read("hello").unwrap();
         println!("{:?}", String::from_utf8(vec));
     }
 
@@ -488,7 +2068,7 @@ mod tests {
             .create(true)
             .read(true)
             .append(true)
-            .open("./test_data/1.bck".to_string());
+            .open(Path::new(&get_default_options().dir_path).join("1.bck"));
         let mut file = open_options.unwrap();
         let result = file.write("hello".as_ref());
         println!("{}", result.unwrap());
@@ -498,31 +2078,59 @@ mod tests {
         let option = get_default_options();
         let options = Arc::new(option);
 
-        let mem_index: Arc<RwLock<Box<dyn Indexer>>> =
-            Arc::new(RwLock::new(Box::new(KeyDir::new()) as Box<dyn Indexer>));
+        let mem_index = Arc::new(RwLock::new(index::new_indexer(&options).unwrap()));
 
         let active_file = Arc::new(RwLock::new(
             DataFile::new(options.clone().dir_path.clone(), 1).unwrap(),
         ));
 
         let older_files = Arc::new(RwLock::new(HashMap::<u32, DataFile>::new()));
-        let index_type = Box::new(keydir::KeyDir::new());
-        let engine = Engine::new(options, mem_index, active_file, older_files, index_type);
-        engine
+        Engine::new(options, mem_index, active_file, older_files)
     }
 
-    pub fn open_engine() -> Engine {
-        let options = get_default_options();
+    /// 所有的索引类型，Engine 的行为不应该依赖索引类型
+    pub fn index_types() -> Vec<IndexType> {
+        vec![
+            IndexType::Hash,
+            IndexType::BTree,
+            IndexType::SkipList,
+            IndexType::Art,
+            IndexType::CompactHash,
+            IndexType::ShardedHash,
+            IndexType::BPlusTree,
+        ]
+    }
+
+    pub fn open_engine(options: Options) -> Engine {
         Engine::open(options).unwrap()
     }
 
+    /// 每次调用都返回一个新的空目录，避免并行执行的测试互相干扰
     pub fn get_default_options() -> Options {
-        let dir_path = "./test_data".to_string();
+        static DIR_SEQ: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "bitcask-rs-test-{}-{}",
+            std::process::id(),
+            DIR_SEQ.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let dir_path = dir.display().to_string();
         Options {
             dir_path: dir_path.clone(),
             file_threshold: 5000,
             syn_after_each_write: false,
             index_type: IndexType::Hash,
+            io_type: IOType::StandardFIO,
+            merge_ratio_threshold: 0.5,
+            merge_min_older_file_bytes: 0,
+            merge_interval: None,
+            merge_on_close: false,
+            checkpoint_interval: None,
+            checkpoint_on_close: false,
+            index_cache_pages: 1024,
+            index_rebuild_threads: 2,
+            index_shards: 4,
         }
     }
 }
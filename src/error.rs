@@ -20,8 +20,8 @@ pub enum E {
     #[error("mem index update failed")]
     Failed2UpdateMemIndex,
 
-    #[error("data is corrupted")]
-    DataCorrupted,
+    #[error("data is corrupted, file id {file_id}, offset {offset}")]
+    DataCorrupted { file_id: u32, offset: usize },
 
     #[error("value is empty and it's illegal")]
     EmptyValue,
@@ -47,8 +47,47 @@ pub enum E {
     #[error("could not open data dir")]
     CouldNotOpenDataDir,
 
+    #[error("unknown data file format, magic mismatch")]
+    UnknownDataFileFormat,
+
+    #[error("unsupported data file version {0}")]
+    UnsupportedDataFileVersion(u32),
+
     #[error("could not read database datafile dir")]
     Failed2ReadDBDir,
+
+    #[error("merge is in progress, try again later")]
+    MergeInProgress,
+
+    #[error("failed to merge data files")]
+    Failed2Merge,
+
+    #[error("failed to repair data dir")]
+    Failed2Repair,
+
+    #[error("transaction conflict, keys read by the transaction were modified")]
+    TransactionConflict,
+
+    #[error("merge ratio threshold must be in [0, 1]")]
+    InvalidMergeRatio,
+
+    #[error("file threshold is too large for the index type")]
+    FileThresholdTooLarge,
+
+    #[error("failed to open index file")]
+    Failed2OpenIndexFile,
+
+    #[error("failed to write index file")]
+    Failed2WriteIndexFile,
+
+    #[error("failed to read index file")]
+    Failed2ReadIndexFile,
+
+    #[error("key is larger than {0} bytes")]
+    KeyTooLarge(usize),
+
+    #[error("value is larger than {0} bytes")]
+    ValueTooLarge(usize),
 }
 
 pub type R<T> = Result<T, E>;
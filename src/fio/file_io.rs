@@ -1,5 +1,8 @@
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
 use std::os::windows::fs::FileExt;
 use std::sync::Arc;
 
@@ -25,11 +28,10 @@ impl FileIO {
         }
     }
 
-    fn from(file_path: &str) -> R<Self> {
-        return match OpenOptions::new()
+    pub fn from(file_path: &str) -> R<Self> {
+        match OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
             .open(file_path)
         {
@@ -40,32 +42,32 @@ impl FileIO {
                 error!("failed to open data file: {}", e);
                 Err(Failed2OpenDataFile)
             }
-        };
+        }
     }
 }
 
 impl IOManager for FileIO {
     fn read(&self, buf: &mut [u8], offset: u64) -> R<usize> {
         let read_guard = self.fd.read();
-        return match read_guard.seek_read(buf, offset) {
+        match read_at(&read_guard, buf, offset) {
             Ok(n) => Ok(n),
             Err(e) => {
                 error!("read from data file err: {}", e);
                 Err(Failed2ReadFromDataFile)
             }
-        };
+        }
     }
 
     fn append(&self, buf: &[u8]) -> R<usize> {
         // open 时制定了是 append
         let mut write_guard = self.fd.write();
-        return match write_guard.write(buf) {
+        match write_guard.write(buf) {
             Ok(n) => Ok(n),
             Err(e) => {
                 error!("read from data file err: {}", e);
                 Err(Failed2Write2DataFile)
             }
-        };
+        }
     }
 
     fn sync(&self) -> R<()> {
@@ -78,13 +80,38 @@ impl IOManager for FileIO {
     }
 }
 
+/// 定位读（pread），不移动文件游标，读满 buf 或者读到文件末尾才返回，返回读取到的字节数
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        #[cfg(unix)]
+        let res = file.read_at(&mut buf[n..], offset + n as u64);
+        #[cfg(windows)]
+        let res = file.seek_read(&mut buf[n..], offset + n as u64);
+        match res {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tmp_file_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("bitcask-rs-fio-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.display().to_string()
+    }
+
     #[test]
     fn test_file_io_write() {
-        let fio_res = FileIO::from("./tmp.data");
+        let fio_res = FileIO::from(&tmp_file_path("write.data"));
         assert!(fio_res.is_ok());
 
         let fio = fio_res.ok().unwrap();
@@ -95,13 +122,20 @@ mod tests {
 
     #[test]
     fn test_file_io_read() {
-        let fio_res = FileIO::from("./tmp.data");
+        let fio_res = FileIO::from(&tmp_file_path("read.data"));
         assert!(fio_res.is_ok());
 
         let fio = fio_res.ok().unwrap();
-        let buf: &mut [u8] = &mut vec![0; 1];
+        fio.append("hello".as_bytes()).unwrap();
+        let buf: &mut [u8] = &mut [0; 1];
         let result = fio.read(buf, 3);
         println!("{:?}", buf);
         assert_eq!(result.unwrap(), 1);
+        assert_eq!(buf, "l".as_bytes());
+
+        // 读到文件末尾时返回实际读取的字节数
+        let buf: &mut [u8] = &mut [0; 4];
+        assert_eq!(fio.read(buf, 3).unwrap(), 2);
+        assert_eq!(&buf[..2], "lo".as_bytes());
     }
 }
@@ -0,0 +1,108 @@
+use std::fs::OpenOptions;
+
+use log::error;
+use memmap2::Mmap;
+
+use crate::error::E::{CanNotWriteOldFile, Failed2OpenDataFile};
+use crate::error::R;
+use crate::fio::IOManager;
+
+/// 内存映射 IO，只用于不可变的 older file 以及启动时构建索引，读不需要系统调用
+pub struct MMapIO {
+    map: Mmap,
+}
+
+impl MMapIO {
+    pub fn from(file_path: &str) -> R<Self> {
+        let file = match OpenOptions::new().read(true).open(file_path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("failed to open data file: {}", e);
+                return Err(Failed2OpenDataFile);
+            }
+        };
+
+        // 映射期间文件不会再被写入，older file 是 immutable 的
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => Ok(MMapIO { map }),
+            Err(e) => {
+                error!("failed to map data file: {}", e);
+                Err(Failed2OpenDataFile)
+            }
+        }
+    }
+}
+
+impl IOManager for MMapIO {
+    fn read(&self, buf: &mut [u8], offset: u64) -> R<usize> {
+        let map_len = self.map.len() as u64;
+        if offset >= map_len {
+            return Ok(0);
+        }
+
+        let begin = offset as usize;
+        let end = (offset + buf.len() as u64).min(map_len) as usize;
+        let n = end - begin;
+        buf[..n].copy_from_slice(&self.map[begin..end]);
+        Ok(n)
+    }
+
+    fn append(&self, _buf: &[u8]) -> R<usize> {
+        Err(CanNotWriteOldFile)
+    }
+
+    fn sync(&self) -> R<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fio::file_io::FileIO;
+
+    fn tmp_file_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("bitcask-rs-mmap-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test_mmap_read() {
+        let path = tmp_file_path("read.data");
+        let fio = FileIO::from(&path).unwrap();
+        fio.append("hello world".as_bytes()).unwrap();
+        fio.sync().unwrap();
+
+        let mmap_io = MMapIO::from(&path).unwrap();
+        let buf: &mut [u8] = &mut [0; 5];
+        assert_eq!(mmap_io.read(buf, 6).unwrap(), 5);
+        assert_eq!(buf, "world".as_bytes());
+
+        // 读到文件末尾时返回实际读取的字节数
+        let buf: &mut [u8] = &mut [0; 8];
+        assert_eq!(mmap_io.read(buf, 8).unwrap(), 3);
+        assert_eq!(&buf[..3], "rld".as_bytes());
+        assert_eq!(mmap_io.read(buf, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mmap_empty_file() {
+        let path = tmp_file_path("empty.data");
+        FileIO::from(&path).unwrap();
+
+        let mmap_io = MMapIO::from(&path).unwrap();
+        let buf: &mut [u8] = &mut [0; 4];
+        assert_eq!(mmap_io.read(buf, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mmap_append() {
+        let path = tmp_file_path("append.data");
+        FileIO::from(&path).unwrap();
+
+        let mmap_io = MMapIO::from(&path).unwrap();
+        assert!(mmap_io.append("hello".as_bytes()).is_err());
+    }
+}
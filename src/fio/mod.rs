@@ -1,6 +1,8 @@
 pub mod file_io;
+pub mod mmap;
 
 use crate::error::R;
+use crate::options::IOType;
 
 /// IO 层接口
 pub trait IOManager: Send + Sync {
@@ -13,3 +15,11 @@ pub trait IOManager: Send + Sync {
     /// 持久化数据
     fn sync(&self) -> R<()>;
 }
+
+/// 根据 IO 类型打开文件
+pub fn new_io_manager(file_path: &str, io_type: IOType) -> R<Box<dyn IOManager>> {
+    match io_type {
+        IOType::StandardFIO => Ok(Box::new(file_io::FileIO::from(file_path)?)),
+        IOType::MemoryMap => Ok(Box::new(mmap::MMapIO::from(file_path)?)),
+    }
+}
@@ -0,0 +1,903 @@
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::index::iterator::{CursorIterator, OrderedIndex};
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
+use parking_lot::RwLock;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// 自适应基数树 (adaptive radix tree)，key 的公共前缀只保存一次，
+/// 内部节点根据子节点数量在 Node4/16/48/256 之间切换，key 共享长前缀时比 BTreeMap 省内存
+pub struct Art {
+    tree: Arc<RwLock<Tree>>,
+}
+
+struct Tree {
+    root: Option<Node>,
+
+    /// key 的数量
+    len: usize,
+}
+
+enum Node {
+    Leaf(Box<Leaf>),
+    Inner(Box<Inner>),
+}
+
+/// 叶子节点只保存 key 在路径之后剩余的部分
+struct Leaf {
+    suffix: Box<[u8]>,
+    meta_data: MetaData,
+}
+
+/// 内部节点，prefix 是压缩的路径，value 是恰好在该节点结束的 key
+struct Inner {
+    prefix: Vec<u8>,
+    value: Option<MetaData>,
+    children: Children,
+}
+
+enum Children {
+    /// 最多 4 个子节点，按字节有序
+    Node4 { keys: Vec<u8>, nodes: Vec<Node> },
+
+    /// 最多 16 个子节点，按字节有序
+    Node16 { keys: Vec<u8>, nodes: Vec<Node> },
+
+    /// 最多 48 个子节点，index 中保存子节点下标 + 1，0 表示不存在
+    Node48 {
+        index: Box<[u8; 256]>,
+        nodes: Vec<Node>,
+    },
+
+    /// 每个字节一个槽位
+    Node256 {
+        nodes: Box<[Option<Node>; 256]>,
+        len: usize,
+    },
+}
+
+impl Children {
+    fn new() -> Self {
+        Children::Node4 {
+            keys: Vec::with_capacity(4),
+            nodes: Vec::with_capacity(4),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Children::Node4 { nodes, .. }
+            | Children::Node16 { nodes, .. }
+            | Children::Node48 { nodes, .. } => nodes.len(),
+            Children::Node256 { len, .. } => *len,
+        }
+    }
+
+    fn get(&self, byte: u8) -> Option<&Node> {
+        match self {
+            Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                keys.binary_search(&byte).ok().map(|i| &nodes[i])
+            }
+            Children::Node48 { index, nodes } => match index[byte as usize] {
+                0 => None,
+                i => Some(&nodes[i as usize - 1]),
+            },
+            Children::Node256 { nodes, .. } => nodes[byte as usize].as_ref(),
+        }
+    }
+
+    fn get_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        match self {
+            Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                keys.binary_search(&byte).ok().map(|i| &mut nodes[i])
+            }
+            Children::Node48 { index, nodes } => match index[byte as usize] {
+                0 => None,
+                i => Some(&mut nodes[i as usize - 1]),
+            },
+            Children::Node256 { nodes, .. } => nodes[byte as usize].as_mut(),
+        }
+    }
+
+    /// 按字节顺序遍历子节点
+    fn iter(&self) -> Box<dyn DoubleEndedIterator<Item = (u8, &Node)> + '_> {
+        match self {
+            Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                Box::new(keys.iter().copied().zip(nodes.iter()))
+            }
+            Children::Node48 { index, nodes } => {
+                Box::new((0..=255u8).filter_map(move |b| match index[b as usize] {
+                    0 => None,
+                    i => Some((b, &nodes[i as usize - 1])),
+                }))
+            }
+            Children::Node256 { nodes, .. } => Box::new(
+                (0..=255u8).filter_map(move |b| nodes[b as usize].as_ref().map(|n| (b, n))),
+            ),
+        }
+    }
+
+    /// 插入一个不存在的子节点，放不下时扩容为更大的节点
+    fn insert(&mut self, byte: u8, node: Node) {
+        let full = match self {
+            Children::Node4 { nodes, .. } => nodes.len() == 4,
+            Children::Node16 { nodes, .. } => nodes.len() == 16,
+            Children::Node48 { nodes, .. } => nodes.len() == 48,
+            Children::Node256 { .. } => false,
+        };
+        if full {
+            self.grow();
+        }
+
+        match self {
+            Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                let i = keys.binary_search(&byte).unwrap_err();
+                keys.insert(i, byte);
+                nodes.insert(i, node);
+            }
+            Children::Node48 { index, nodes } => {
+                nodes.push(node);
+                index[byte as usize] = nodes.len() as u8;
+            }
+            Children::Node256 { nodes, len } => {
+                nodes[byte as usize] = Some(node);
+                *len += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let removed = match self {
+            Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                let i = keys.binary_search(&byte).ok()?;
+                keys.remove(i);
+                Some(nodes.remove(i))
+            }
+            Children::Node48 { index, nodes } => {
+                let i = match index[byte as usize] {
+                    0 => return None,
+                    i => i as usize - 1,
+                };
+                index[byte as usize] = 0;
+                let removed = nodes.swap_remove(i);
+                // 最后一个子节点被移动到了 i
+                if i < nodes.len() {
+                    let moved = index.iter().position(|&j| j as usize == nodes.len() + 1);
+                    index[moved.unwrap()] = i as u8 + 1;
+                }
+                Some(removed)
+            }
+            Children::Node256 { nodes, len } => {
+                let removed = nodes[byte as usize].take()?;
+                *len -= 1;
+                Some(removed)
+            }
+        };
+
+        let sparse = match self {
+            Children::Node4 { .. } => false,
+            Children::Node16 { nodes, .. } => nodes.len() <= 3,
+            Children::Node48 { nodes, .. } => nodes.len() <= 12,
+            Children::Node256 { len, .. } => *len <= 40,
+        };
+        if sparse {
+            self.shrink();
+        }
+        removed
+    }
+
+    fn grow(&mut self) {
+        let children = mem::replace(self, Children::new());
+        *self = match children {
+            Children::Node4 {
+                mut keys,
+                mut nodes,
+            } => {
+                keys.reserve_exact(12);
+                nodes.reserve_exact(12);
+                Children::Node16 { keys, nodes }
+            }
+            Children::Node16 { keys, mut nodes } => {
+                let mut index = Box::new([0u8; 256]);
+                for (i, b) in keys.into_iter().enumerate() {
+                    index[b as usize] = i as u8 + 1;
+                }
+                nodes.reserve_exact(32);
+                Children::Node48 { index, nodes }
+            }
+            Children::Node48 { index, nodes } => {
+                let mut slots: Box<[Option<Node>; 256]> = Box::new(std::array::from_fn(|_| None));
+                let len = nodes.len();
+                let mut nodes: Vec<Option<Node>> = nodes.into_iter().map(Some).collect();
+                for (b, &i) in index.iter().enumerate() {
+                    if i != 0 {
+                        slots[b] = nodes[i as usize - 1].take();
+                    }
+                }
+                Children::Node256 { nodes: slots, len }
+            }
+            children @ Children::Node256 { .. } => children,
+        };
+    }
+
+    fn shrink(&mut self) {
+        let children = mem::replace(self, Children::new());
+        *self = match children {
+            Children::Node16 {
+                mut keys,
+                mut nodes,
+            } => {
+                keys.shrink_to(4);
+                nodes.shrink_to(4);
+                Children::Node4 { keys, nodes }
+            }
+            Children::Node48 { index, nodes } => {
+                let mut nodes: Vec<Option<Node>> = nodes.into_iter().map(Some).collect();
+                let mut keys = Vec::with_capacity(16);
+                let mut sorted = Vec::with_capacity(16);
+                for (b, &i) in index.iter().enumerate() {
+                    if i != 0 {
+                        keys.push(b as u8);
+                        sorted.push(nodes[i as usize - 1].take().unwrap());
+                    }
+                }
+                Children::Node16 {
+                    keys,
+                    nodes: sorted,
+                }
+            }
+            Children::Node256 { nodes, .. } => {
+                let mut index = Box::new([0u8; 256]);
+                let mut children = Vec::with_capacity(48);
+                for (b, node) in nodes.into_iter().enumerate() {
+                    if let Some(node) = node {
+                        children.push(node);
+                        index[b] = children.len() as u8;
+                    }
+                }
+                Children::Node48 {
+                    index,
+                    nodes: children,
+                }
+            }
+            children @ Children::Node4 { .. } => children,
+        };
+    }
+}
+
+impl Node {
+    fn leaf(suffix: &[u8], meta_data: MetaData) -> Self {
+        Node::Leaf(Box::new(Leaf {
+            suffix: suffix.into(),
+            meta_data,
+        }))
+    }
+
+    /// 节点及其子树占用的堆内存
+    fn memory_size(&self) -> usize {
+        match self {
+            Node::Leaf(leaf) => mem::size_of::<Leaf>() + leaf.suffix.len(),
+            Node::Inner(inner) => {
+                let children = match &inner.children {
+                    Children::Node4 { keys, nodes } | Children::Node16 { keys, nodes } => {
+                        keys.capacity() + nodes.capacity() * mem::size_of::<Node>()
+                    }
+                    Children::Node48 { nodes, .. } => {
+                        256 + nodes.capacity() * mem::size_of::<Node>()
+                    }
+                    Children::Node256 { .. } => 256 * mem::size_of::<Option<Node>>(),
+                };
+                let subtree: usize = inner
+                    .children
+                    .iter()
+                    .map(|(_, child)| child.memory_size())
+                    .sum();
+                mem::size_of::<Inner>() + inner.prefix.capacity() + children + subtree
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn new(prefix: &[u8]) -> Self {
+        Self {
+            prefix: prefix.to_vec(),
+            value: None,
+            children: Children::new(),
+        }
+    }
+
+    /// 在该节点下新增一个 key，key 是 prefix 之后剩余的部分
+    fn add_leaf(&mut self, key: &[u8], meta_data: MetaData) {
+        match key.split_first() {
+            None => self.value = Some(meta_data),
+            Some((&b, rest)) => self.children.insert(b, Node::leaf(rest, meta_data)),
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// key 是路径之后剩余的部分，新增 key 时返回 true，覆盖已有的 key 时返回 false
+fn insert(node: &mut Node, key: &[u8], meta_data: MetaData) -> bool {
+    match node {
+        Node::Leaf(leaf) => {
+            if *leaf.suffix == *key {
+                leaf.meta_data = meta_data;
+                return false;
+            }
+            // 分裂为公共前缀的内部节点，原来的叶子和新的 key 成为它的子节点
+            let c = common_prefix_len(&leaf.suffix, key);
+            let mut inner = Inner::new(&key[..c]);
+            inner.add_leaf(&leaf.suffix[c..], leaf.meta_data);
+            inner.add_leaf(&key[c..], meta_data);
+            *node = Node::Inner(Box::new(inner));
+            true
+        }
+        Node::Inner(inner) => {
+            let c = common_prefix_len(&inner.prefix, key);
+            if c < inner.prefix.len() {
+                // 压缩路径不匹配，在分叉处分裂
+                let mut split = Inner::new(&key[..c]);
+                let b = inner.prefix[c];
+                inner.prefix.drain(..=c);
+                split.add_leaf(&key[c..], meta_data);
+                let old = mem::replace(node, Node::Inner(Box::new(split)));
+                if let Node::Inner(split) = node {
+                    split.children.insert(b, old);
+                }
+                return true;
+            }
+
+            let key = &key[c..];
+            match key.split_first() {
+                None => inner.value.replace(meta_data).is_none(),
+                Some((&b, rest)) => match inner.children.get_mut(b) {
+                    Some(child) => insert(child, rest, meta_data),
+                    None => {
+                        inner.children.insert(b, Node::leaf(rest, meta_data));
+                        true
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// 从内部节点中删除 key，删除后节点可能被压缩为叶子或者与唯一的子节点合并
+fn get(mut node: &Node, mut key: &[u8]) -> Option<MetaData> {
+    loop {
+        match node {
+            Node::Leaf(leaf) => {
+                return (*leaf.suffix == *key).then_some(leaf.meta_data);
+            }
+            Node::Inner(inner) => {
+                key = key.strip_prefix(inner.prefix.as_slice())?;
+                let Some((&b, rest)) = key.split_first() else {
+                    return inner.value;
+                };
+                node = inner.children.get(b)?;
+                key = rest;
+            }
+        }
+    }
+}
+
+fn delete(node: &mut Node, key: &[u8]) -> Option<MetaData> {
+    let Node::Inner(inner) = node else {
+        return None;
+    };
+    let key = key.strip_prefix(inner.prefix.as_slice())?;
+    let removed = match key.split_first() {
+        None => inner.value.take(),
+        Some((&b, rest)) => match inner.children.get_mut(b)? {
+            Node::Leaf(leaf) => {
+                if *leaf.suffix != *rest {
+                    return None;
+                }
+                let meta_data = leaf.meta_data;
+                inner.children.remove(b);
+                Some(meta_data)
+            }
+            child => delete(child, rest),
+        },
+    };
+    if removed.is_some() {
+        compact(node);
+    }
+    removed
+}
+
+fn compact(node: &mut Node) {
+    let Node::Inner(inner) = node else {
+        return;
+    };
+    let replacement = match (inner.children.len(), inner.value) {
+        (0, Some(meta_data)) => Node::leaf(&inner.prefix, meta_data),
+        (1, None) => {
+            let (b, _) = inner.children.iter().next().unwrap();
+            let child = inner.children.remove(b).unwrap();
+            let mut prefix = mem::take(&mut inner.prefix);
+            prefix.push(b);
+            match child {
+                Node::Leaf(mut leaf) => {
+                    prefix.extend_from_slice(&leaf.suffix);
+                    leaf.suffix = prefix.into();
+                    Node::Leaf(leaf)
+                }
+                Node::Inner(mut child) => {
+                    prefix.extend_from_slice(&child.prefix);
+                    child.prefix = prefix;
+                    Node::Inner(child)
+                }
+            }
+        }
+        _ => return,
+    };
+    *node = replacement;
+}
+
+/// 子树中最小的 key，path 是到达 node 之前的路径
+fn first(mut node: &Node, path: &mut Vec<u8>) -> Option<(Vec<u8>, MetaData)> {
+    let len = path.len();
+    let res = loop {
+        match node {
+            Node::Leaf(leaf) => {
+                path.extend_from_slice(&leaf.suffix);
+                break Some((path.clone(), leaf.meta_data));
+            }
+            Node::Inner(inner) => {
+                path.extend_from_slice(&inner.prefix);
+                if let Some(meta_data) = inner.value {
+                    break Some((path.clone(), meta_data));
+                }
+                let Some((b, child)) = inner.children.iter().next() else {
+                    break None;
+                };
+                path.push(b);
+                node = child;
+            }
+        }
+    };
+    path.truncate(len);
+    res
+}
+
+/// 子树中最大的 key
+fn last(mut node: &Node, path: &mut Vec<u8>) -> Option<(Vec<u8>, MetaData)> {
+    let len = path.len();
+    let res = loop {
+        match node {
+            Node::Leaf(leaf) => {
+                path.extend_from_slice(&leaf.suffix);
+                break Some((path.clone(), leaf.meta_data));
+            }
+            Node::Inner(inner) => {
+                path.extend_from_slice(&inner.prefix);
+                match inner.children.iter().next_back() {
+                    Some((b, child)) => {
+                        path.push(b);
+                        node = child;
+                    }
+                    None => break inner.value.map(|meta_data| (path.clone(), meta_data)),
+                }
+            }
+        }
+    };
+    path.truncate(len);
+    res
+}
+
+/// 子树中满足下界的最小的 key。只沿着下界所在的路径向下，
+/// 路径两侧的子树要么全部小于下界被跳过，要么全部满足下界，直接取其中最小的 key
+fn seek_first(node: &Node, path: &mut Vec<u8>, lower: Bound<&[u8]>) -> Option<(Vec<u8>, MetaData)> {
+    let (lower_key, inclusive) = match lower {
+        Bound::Unbounded => return first(node, path),
+        Bound::Included(key) => (key, true),
+        Bound::Excluded(key) => (key, false),
+    };
+    let len = path.len();
+    let res = match node {
+        Node::Leaf(leaf) => {
+            path.extend_from_slice(&leaf.suffix);
+            let key = path.as_slice();
+            (key > lower_key || inclusive && key == lower_key)
+                .then(|| (path.clone(), leaf.meta_data))
+        }
+        Node::Inner(inner) => {
+            path.extend_from_slice(&inner.prefix);
+            if !lower_key.starts_with(path) {
+                let after = path.as_slice() > lower_key;
+                path.truncate(len);
+                return if after { first(node, path) } else { None };
+            }
+            match lower_key.get(path.len()) {
+                // 下界恰好在该节点结束，子节点中的 key 都大于下界
+                None => match inner.value {
+                    Some(meta_data) if inclusive => Some((path.clone(), meta_data)),
+                    _ => inner.children.iter().next().and_then(|(b, child)| {
+                        path.push(b);
+                        first(child, path)
+                    }),
+                },
+                Some(&next) => {
+                    let mut found = None;
+                    for (b, child) in inner.children.iter().skip_while(|(b, _)| *b < next) {
+                        path.push(b);
+                        found = match b == next {
+                            true => seek_first(child, path, lower),
+                            false => first(child, path),
+                        };
+                        path.pop();
+                        if found.is_some() {
+                            break;
+                        }
+                    }
+                    found
+                }
+            }
+        }
+    };
+    path.truncate(len);
+    res
+}
+
+/// 子树中满足上界的最大的 key，与 seek_first 对称
+fn seek_last(node: &Node, path: &mut Vec<u8>, upper: Bound<&[u8]>) -> Option<(Vec<u8>, MetaData)> {
+    let (upper_key, inclusive) = match upper {
+        Bound::Unbounded => return last(node, path),
+        Bound::Included(key) => (key, true),
+        Bound::Excluded(key) => (key, false),
+    };
+    let len = path.len();
+    let res = match node {
+        Node::Leaf(leaf) => {
+            path.extend_from_slice(&leaf.suffix);
+            let key = path.as_slice();
+            (key < upper_key || inclusive && key == upper_key)
+                .then(|| (path.clone(), leaf.meta_data))
+        }
+        Node::Inner(inner) => {
+            path.extend_from_slice(&inner.prefix);
+            if !upper_key.starts_with(path) {
+                let before = path.as_slice() < upper_key;
+                path.truncate(len);
+                return if before { last(node, path) } else { None };
+            }
+            match upper_key.get(path.len()) {
+                // 上界恰好在该节点结束，子节点中的 key 都大于上界
+                None => inner
+                    .value
+                    .filter(|_| inclusive)
+                    .map(|meta_data| (path.clone(), meta_data)),
+                Some(&next) => {
+                    let mut found = None;
+                    for (b, child) in inner.children.iter().rev().skip_while(|(b, _)| *b > next) {
+                        path.push(b);
+                        found = match b == next {
+                            true => seek_last(child, path, upper),
+                            false => last(child, path),
+                        };
+                        path.pop();
+                        if found.is_some() {
+                            break;
+                        }
+                    }
+                    // 在该节点结束的 key 是上界的前缀，小于上界
+                    found.or_else(|| inner.value.map(|meta_data| (path.clone(), meta_data)))
+                }
+            }
+        }
+    };
+    path.truncate(len);
+    res
+}
+
+impl Default for Art {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Art {
+    pub fn new() -> Self {
+        Self {
+            tree: Arc::new(RwLock::new(Tree { root: None, len: 0 })),
+        }
+    }
+}
+
+impl Indexer for Art {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+        let mut tree = self.tree.write();
+        let inserted = match tree.root.as_mut() {
+            Some(root) => insert(root, &key, meta_data),
+            None => {
+                tree.root = Some(Node::leaf(&key, meta_data));
+                true
+            }
+        };
+        if inserted {
+            tree.len += 1;
+        }
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+        let tree = self.tree.read();
+        Ok(tree.root.as_ref().and_then(|root| get(root, key)))
+    }
+
+    fn delete(&self, key: &[u8]) -> R<bool> {
+        let mut tree = self.tree.write();
+        let deleted = match tree.root.as_mut() {
+            Some(Node::Leaf(leaf)) if *leaf.suffix == *key => {
+                tree.root = None;
+                true
+            }
+            Some(root) => delete(root, key).is_some(),
+            None => false,
+        };
+        if deleted {
+            tree.len -= 1;
+        }
+        Ok(deleted)
+    }
+
+    /// 游标每次沿着上一个 key 所在的路径定位下一个 key，不复制范围内的 key
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(CursorIterator::new(self.tree.clone(), options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.tree.read().len
+    }
+
+    fn memory_size(&self) -> usize {
+        let tree = self.tree.read();
+        mem::size_of::<Option<Node>>() + tree.root.as_ref().map_or(0, Node::memory_size)
+    }
+}
+
+impl OrderedIndex for RwLock<Tree> {
+    fn first_in(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Option<(Vec<u8>, MetaData)> {
+        let tree = self.read();
+        let root = tree.root.as_ref()?;
+        let mut path = Vec::new();
+        let (key, meta_data) = if reverse {
+            seek_last(root, &mut path, upper)?
+        } else {
+            seek_first(root, &mut path, lower)?
+        };
+        let in_range = match (lower, upper) {
+            (_, Bound::Included(u)) if key.as_slice() > u => false,
+            (_, Bound::Excluded(u)) if key.as_slice() >= u => false,
+            (Bound::Included(l), _) if key.as_slice() < l => false,
+            (Bound::Excluded(l), _) if key.as_slice() <= l => false,
+            _ => true,
+        };
+        in_range.then_some((key, meta_data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn keys(art: &Art, options: &IteratorOptions) -> Vec<Vec<u8>> {
+        let mut iter = art.iterator(options);
+        let mut keys = Vec::new();
+        while let Some(item) = iter.next() {
+            keys.push(item.unwrap().0.to_vec());
+        }
+        keys
+    }
+
+    #[test]
+    fn test_art_put_get_delete() {
+        let art = Art::new();
+        let fake_meta_data = MetaData::new(0, 1, 2, 3);
+        // key 互为前缀
+        for k in ["tenant/a/1", "tenant/a", "tenant/a/10", "tenant/b", "t"] {
+            assert!(art.put(k.as_bytes().to_vec(), fake_meta_data));
+        }
+        for k in ["tenant/a/1", "tenant/a", "tenant/a/10", "tenant/b", "t"] {
+            assert_eq!(art.get(k.as_bytes()).unwrap().unwrap(), fake_meta_data);
+        }
+        for k in [
+            "tenant",
+            "tenant/",
+            "tenant/a/",
+            "tenant/c",
+            "tenant/a/100",
+            "",
+        ] {
+            assert_eq!(art.get(k.as_bytes()).unwrap(), None);
+        }
+
+        let new_meta_data = MetaData::new(1, 1, 2, 4);
+        art.put("tenant/a".as_bytes().to_vec(), new_meta_data);
+        assert_eq!(
+            art.get("tenant/a".as_bytes()).unwrap().unwrap(),
+            new_meta_data
+        );
+
+        assert!(art.delete("tenant/a".as_bytes()).unwrap());
+        assert!(!art.delete("tenant/a".as_bytes()).unwrap());
+        assert!(!art.delete("tenant/".as_bytes()).unwrap());
+        assert_eq!(art.get("tenant/a".as_bytes()).unwrap(), None);
+        assert_eq!(
+            art.get("tenant/a/1".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+
+        for k in ["tenant/a/1", "tenant/a/10", "tenant/b", "t"] {
+            assert!(art.delete(k.as_bytes()).unwrap());
+        }
+        assert!(art.tree.read().root.is_none());
+        assert_eq!(art.key_num(), 0);
+    }
+
+    #[test]
+    fn test_art_iterator() {
+        let art = Art::new();
+        for k in ["b", "d", "a", "c", "ca", "cab"] {
+            art.put(k.as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        }
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            upper_bound: Some("d".as_bytes().to_vec()),
+            reverse: true,
+        };
+        let expected: Vec<Vec<u8>> = ["cab", "ca", "c", "b"]
+            .iter()
+            .map(|k| k.as_bytes().to_vec())
+            .collect();
+        assert_eq!(keys(&art, &options), expected);
+
+        let options = IteratorOptions::prefix("ca".as_bytes());
+        assert_eq!(
+            keys(&art, &options),
+            vec!["ca".as_bytes().to_vec(), "cab".as_bytes().to_vec()]
+        );
+
+        let options = IteratorOptions {
+            lower_bound: Some("d".as_bytes().to_vec()),
+            upper_bound: Some("a".as_bytes().to_vec()),
+            reverse: false,
+        };
+        assert!(keys(&art, &options).is_empty());
+    }
+
+    #[test]
+    fn test_art_cursor() {
+        let art = Art::new();
+        for k in ["a", "c", "e"] {
+            art.put(k.as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        }
+
+        // 遍历期间不持有树的锁，从上一个 key 之后继续，期间写入的 key 也可能被遍历到
+        let mut iter = art.iterator(&IteratorOptions::default());
+        assert_eq!(iter.next().unwrap().unwrap().0, "a".as_bytes());
+        art.put("b".as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        assert!(art.delete("c".as_bytes()).unwrap());
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "e".as_bytes());
+        assert!(iter.next().is_none());
+
+        iter.rewind();
+        assert_eq!(iter.next().unwrap().unwrap().0, "a".as_bytes());
+        iter.seek("c".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "e".as_bytes());
+
+        let options = IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        };
+        let mut iter = art.iterator(&options);
+        assert_eq!(iter.next().unwrap().unwrap().0, "e".as_bytes());
+        art.delete("b".as_bytes()).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().0, "a".as_bytes());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_art_against_btree_map() {
+        // 覆盖 Node4/16/48/256 之间的扩容和收缩
+        let art = Art::new();
+        let mut expected = BTreeMap::new();
+        let mut seed: u64 = 42;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        for i in 0..20000 {
+            let mut key = b"shared/prefix/".to_vec();
+            for _ in 0..next() % 3 {
+                key.push((next() % 256) as u8);
+            }
+            if next() % 3 == 0 {
+                assert_eq!(art.delete(&key).unwrap(), expected.remove(&key).is_some());
+            } else {
+                let meta_data = MetaData::new(0, 1, i, 3);
+                art.put(key.clone(), meta_data);
+                expected.insert(key, meta_data);
+            }
+        }
+
+        assert_eq!(art.key_num(), expected.len());
+        for (key, meta_data) in &expected {
+            assert_eq!(art.get(key).unwrap(), Some(*meta_data));
+        }
+        let all: Vec<Vec<u8>> = expected.keys().cloned().collect();
+        assert_eq!(keys(&art, &IteratorOptions::default()), all);
+
+        let options = IteratorOptions {
+            lower_bound: Some(b"shared/prefix/\x40".to_vec()),
+            upper_bound: Some(b"shared/prefix/\x80\x10".to_vec()),
+            reverse: false,
+        };
+        let range: Vec<Vec<u8>> = all
+            .iter()
+            .filter(|k| options.contains(k))
+            .cloned()
+            .collect();
+        assert_eq!(keys(&art, &options), range);
+
+        // 随机的范围和 seek 位置，顺序和逆序遍历
+        let random_key = |next: &mut dyn FnMut() -> usize| {
+            let mut key = b"shared/prefix/".to_vec();
+            key.truncate(next() % (key.len() + 1));
+            for _ in 0..next() % 3 {
+                key.push((next() % 256) as u8);
+            }
+            key
+        };
+        for _ in 0..200 {
+            let (a, b) = (random_key(&mut next), random_key(&mut next));
+            let options = IteratorOptions {
+                lower_bound: Some(a.clone().min(b.clone())),
+                upper_bound: Some(a.max(b)),
+                reverse: next() % 2 == 0,
+            };
+            let mut range: Vec<Vec<u8>> = all
+                .iter()
+                .filter(|k| options.contains(k))
+                .cloned()
+                .collect();
+            if options.reverse {
+                range.reverse();
+            }
+            assert_eq!(keys(&art, &options), range);
+
+            let seek_key = random_key(&mut next);
+            let mut iter = art.iterator(&options);
+            iter.seek(&seek_key);
+            let expected = range.iter().find(|k| match options.reverse {
+                true => k.as_slice() <= seek_key.as_slice(),
+                false => k.as_slice() >= seek_key.as_slice(),
+            });
+            let actual = iter.next().map(|item| item.unwrap().0.to_vec());
+            assert_eq!(actual.as_ref(), expected);
+        }
+
+        for key in &all {
+            assert!(art.delete(key).unwrap());
+        }
+        assert!(art.tree.read().root.is_none());
+        assert_eq!(art.key_num(), 0);
+    }
+}
@@ -0,0 +1,1226 @@
+use crate::data::file_stat::FileStat;
+use crate::data::meta_data::MetaData;
+use crate::error::E::{
+    Failed2OpenIndexFile, Failed2ReadIndexFile, Failed2UpdateMemIndex, Failed2WriteIndexFile,
+};
+use crate::error::R;
+use crate::index::{IndexIterator, Indexer, PersistedState};
+use crate::options::IteratorOptions;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use log::error;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// B+ 树索引文件名
+pub const BPTREE_INDEX_FILE_NAME: &str = "index.bpt";
+
+/// key 的最大字节数，保证每个页至少能放下 4 个 key
+pub const BPTREE_MAX_KEY_SIZE: usize = 512;
+
+const PAGE_SIZE: usize = 4096;
+const MAGIC: &[u8; 4] = b"BPTI";
+const VERSION: u32 = 2;
+
+/// 页类型 u8 + key 数量 u16 + next leaf 或者第一个子节点 u32
+const PAGE_HEADER_SIZE: usize = 7;
+
+/// 每页最后 4 个字节是前面所有字节的 crc，其余的空间保存节点
+const PAGE_CAPACITY: usize = PAGE_SIZE - 4;
+
+/// file_id u32 + entry_sz u64 + entry_start_pos u64 + tstamp u64
+const META_DATA_SIZE: usize = 28;
+
+/// 保存在磁盘上的 B+ 树索引，key 超出内存大小时使用。
+/// 第 0 页是文件头，其余每页是一个节点，只有最近使用的 cache_pages 个页保存在内存中。
+/// 删除 key 时不合并节点，空出的空间在重建索引时回收。
+/// 只有正常关闭 (persist) 之后的索引文件才会在下次启动时直接使用，否则清空后重建
+pub struct BPlusTree {
+    tree: Arc<Mutex<Tree>>,
+}
+
+/// 文件头
+#[derive(Clone, Copy, Default)]
+struct Header {
+    /// 是否正常关闭
+    clean: bool,
+    root: u32,
+    page_num: u32,
+    key_num: u64,
+
+    /// PersistedState 保存在从 state_page 开始的 state_pages 个连续页中
+    state_page: u32,
+    state_pages: u32,
+    state_len: u32,
+}
+
+const HEADER_SIZE: usize = 4 + 4 + 1 + 4 + 4 + 8 + 4 + 4 + 4 + 4;
+
+enum Page {
+    /// next 是下一个叶子节点，0 表示没有
+    Leaf {
+        keys: Vec<Vec<u8>>,
+        values: Vec<MetaData>,
+        next: u32,
+    },
+
+    /// keys[i] 是 children[i + 1] 中最小的 key
+    Inner {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u32>,
+    },
+}
+
+/// 缓存的页按最近使用的顺序串成双向链表，prev 是更近使用的页，0 表示没有 (第 0 页是文件头，不会被缓存)
+struct CachedPage {
+    page: Page,
+    dirty: bool,
+    prev: u32,
+    next: u32,
+}
+
+/// 按页读写索引文件，缓存最近使用的页
+struct Pager {
+    file: File,
+    header: Header,
+    cache: HashMap<u32, CachedPage>,
+    cache_pages: usize,
+
+    /// 最近使用和最久没有使用的页，缓存为空时都是 0
+    head: u32,
+    tail: u32,
+}
+
+struct Tree {
+    pager: Pager,
+
+    /// 打开时读到的上次正常关闭时保存的状态
+    persisted_state: Option<PersistedState>,
+
+    /// 每次修改加 1，游标据此判断记录的叶子节点位置是否仍然有效
+    version: u64,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// 从字节数组中依次读取定长小端序整数
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + n;
+        if end > self.buf.len() {
+            return Err(invalid_data("index page is truncated"));
+        }
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn key(&mut self) -> io::Result<Vec<u8>> {
+        let ksz = self.u16()? as usize;
+        Ok(self.bytes(ksz)?.to_vec())
+    }
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.push(self.clean as u8);
+        buf.extend_from_slice(&self.root.to_le_bytes());
+        buf.extend_from_slice(&self.page_num.to_le_bytes());
+        buf.extend_from_slice(&self.key_num.to_le_bytes());
+        buf.extend_from_slice(&self.state_page.to_le_bytes());
+        buf.extend_from_slice(&self.state_pages.to_le_bytes());
+        buf.extend_from_slice(&self.state_len.to_le_bytes());
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// 文件头不完整或者校验失败时返回 None
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..HEADER_SIZE)?;
+        let (content, crc) = buf.split_at(HEADER_SIZE - 4);
+        if Crc::<u32>::new(&CRC_32_ISO_HDLC)
+            .checksum(content)
+            .to_le_bytes()
+            != crc
+        {
+            return None;
+        }
+        let mut r = Reader {
+            buf: content,
+            pos: 0,
+        };
+        if r.bytes(4).ok()? != MAGIC || r.u32().ok()? != VERSION {
+            return None;
+        }
+        Some(Self {
+            clean: r.u8().ok()? == 1,
+            root: r.u32().ok()?,
+            page_num: r.u32().ok()?,
+            key_num: r.u64().ok()?,
+            state_page: r.u32().ok()?,
+            state_pages: r.u32().ok()?,
+            state_len: r.u32().ok()?,
+        })
+    }
+}
+
+impl Page {
+    fn empty_leaf() -> Self {
+        Page::Leaf {
+            keys: Vec::new(),
+            values: Vec::new(),
+            next: 0,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Page::Leaf { keys, .. } => {
+                PAGE_HEADER_SIZE
+                    + keys
+                        .iter()
+                        .map(|k| 2 + k.len() + META_DATA_SIZE)
+                        .sum::<usize>()
+            }
+            Page::Inner { keys, .. } => {
+                PAGE_HEADER_SIZE + keys.iter().map(|k| 2 + k.len() + 4).sum::<usize>()
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Page::Leaf { keys, values, next } => {
+                buf.push(1);
+                buf.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&next.to_le_bytes());
+                for (key, meta_data) in keys.iter().zip(values) {
+                    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(&meta_data.file_id.to_le_bytes());
+                    buf.extend_from_slice(&(meta_data.entry_sz as u64).to_le_bytes());
+                    buf.extend_from_slice(&(meta_data.entry_start_pos as u64).to_le_bytes());
+                    buf.extend_from_slice(&meta_data.tstamp.to_le_bytes());
+                }
+            }
+            Page::Inner { keys, children } => {
+                buf.push(0);
+                buf.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        buf.resize(PAGE_CAPACITY, 0);
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let (buf, crc) = buf.split_at(PAGE_CAPACITY);
+        if Crc::<u32>::new(&CRC_32_ISO_HDLC)
+            .checksum(buf)
+            .to_le_bytes()
+            != crc
+        {
+            return Err(invalid_data("index page checksum mismatch"));
+        }
+        let mut r = Reader { buf, pos: 0 };
+        let page_type = r.u8()?;
+        let count = r.u16()? as usize;
+        let first = r.u32()?;
+        let mut keys = Vec::with_capacity(count);
+        match page_type {
+            1 => {
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    keys.push(r.key()?);
+                    values.push(MetaData::new(
+                        r.u32()?,
+                        r.u64()? as usize,
+                        r.u64()? as usize,
+                        r.u64()?,
+                    ));
+                }
+                Ok(Page::Leaf {
+                    keys,
+                    values,
+                    next: first,
+                })
+            }
+            0 => {
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(first);
+                for _ in 0..count {
+                    keys.push(r.key()?);
+                    children.push(r.u32()?);
+                }
+                Ok(Page::Inner { keys, children })
+            }
+            _ => Err(invalid_data("unknown index page type")),
+        }
+    }
+}
+
+/// 按字节数把 keys 分成两半，返回右半部分的起始下标
+fn split_point(sizes: impl Iterator<Item = usize>, total: usize, len: usize) -> usize {
+    let mut acc = 0;
+    for (i, size) in sizes.enumerate() {
+        acc += size;
+        if acc * 2 >= total {
+            return (i + 1).clamp(1, len - 1);
+        }
+    }
+    len / 2
+}
+
+impl Pager {
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        #[cfg(unix)]
+        return self.file.write_all_at(buf, offset);
+        #[cfg(windows)]
+        {
+            let mut n = 0;
+            while n < buf.len() {
+                n += self.file.seek_write(&buf[n..], offset + n as u64)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        #[cfg(unix)]
+        return self.file.read_exact_at(buf, offset);
+        #[cfg(windows)]
+        {
+            let mut n = 0;
+            while n < buf.len() {
+                match self.file.seek_read(&mut buf[n..], offset + n as u64)? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    m => n += m,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn write_header(&self) -> io::Result<()> {
+        self.write_at(&self.header.encode(), 0)
+    }
+
+    /// 把缓存中的页从链表中摘下
+    fn unlink(&mut self, id: u32) {
+        let cached = &self.cache[&id];
+        let (prev, next) = (cached.prev, cached.next);
+        match prev {
+            0 => self.head = next,
+            _ => self.cache.get_mut(&prev).unwrap().next = next,
+        }
+        match next {
+            0 => self.tail = prev,
+            _ => self.cache.get_mut(&next).unwrap().prev = prev,
+        }
+    }
+
+    /// 把缓存中的页放到链表头，成为最近使用的页
+    fn push_front(&mut self, id: u32) {
+        let head = self.head;
+        let cached = self.cache.get_mut(&id).unwrap();
+        cached.prev = 0;
+        cached.next = head;
+        match head {
+            0 => self.tail = id,
+            _ => self.cache.get_mut(&head).unwrap().prev = id,
+        }
+        self.head = id;
+    }
+
+    fn insert(&mut self, id: u32, page: Page, dirty: bool) {
+        let cached = CachedPage {
+            page,
+            dirty,
+            prev: 0,
+            next: 0,
+        };
+        self.cache.insert(id, cached);
+        self.push_front(id);
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.head = 0;
+        self.tail = 0;
+    }
+
+    /// 确保页在缓存中并返回
+    fn load(&mut self, id: u32) -> io::Result<&mut CachedPage> {
+        if self.cache.contains_key(&id) {
+            if self.head != id {
+                self.unlink(id);
+                self.push_front(id);
+            }
+        } else {
+            if id == 0 || id >= self.header.page_num {
+                return Err(invalid_data("index page id out of range"));
+            }
+            let mut buf = vec![0; PAGE_SIZE];
+            self.read_at(&mut buf, id as u64 * PAGE_SIZE as u64)?;
+            let page = Page::decode(&buf)?;
+            self.insert(id, page, false);
+        }
+        Ok(self.cache.get_mut(&id).unwrap())
+    }
+
+    fn page(&mut self, id: u32) -> io::Result<&Page> {
+        Ok(&self.load(id)?.page)
+    }
+
+    fn page_mut(&mut self, id: u32) -> io::Result<&mut Page> {
+        let cached = self.load(id)?;
+        cached.dirty = true;
+        Ok(&mut cached.page)
+    }
+
+    fn allocate(&mut self, page: Page) -> u32 {
+        let id = self.header.page_num;
+        self.header.page_num += 1;
+        self.insert(id, page, true);
+        id
+    }
+
+    /// 缓存超出上限时换出最久没有使用的页，脏页先写回文件。
+    /// 每次操作结束后调用，操作过程中用到的页不会被换出
+    fn evict(&mut self) -> io::Result<()> {
+        while self.cache.len() > self.cache_pages {
+            let id = self.tail;
+            self.unlink(id);
+            let cached = self.cache.remove(&id).unwrap();
+            if cached.dirty {
+                self.write_at(&cached.page.encode(), id as u64 * PAGE_SIZE as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (&id, cached) in self.cache.iter() {
+            if cached.dirty {
+                self.write_at(&cached.page.encode(), id as u64 * PAGE_SIZE as u64)?;
+            }
+        }
+        for cached in self.cache.values_mut() {
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Tree {
+    /// 清空索引，只保留一个空的根节点
+    fn reset(&mut self) -> io::Result<()> {
+        self.version += 1;
+        self.pager.file.set_len(0)?;
+        self.pager.clear_cache();
+        self.pager.header = Header {
+            page_num: 1,
+            ..Default::default()
+        };
+        self.pager.header.root = self.pager.allocate(Page::empty_leaf());
+        self.pager.write_header()?;
+        self.pager.flush()?;
+        self.pager.file.sync_all()
+    }
+
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<MetaData>> {
+        let mut id = self.pager.header.root;
+        loop {
+            match self.pager.page(id)? {
+                Page::Inner { keys, children } => {
+                    id = children[keys.partition_point(|k| k.as_slice() <= key)];
+                }
+                Page::Leaf { keys, values, .. } => {
+                    return Ok(keys
+                        .binary_search_by(|k| k.as_slice().cmp(key))
+                        .ok()
+                        .map(|i| values[i]));
+                }
+            }
+        }
+    }
+
+    /// 从根节点到 key 所在叶子节点的路径，以及每个内部节点中选择的子节点下标
+    fn path(&mut self, key: &[u8]) -> io::Result<(Vec<(u32, usize)>, u32)> {
+        let mut path = Vec::new();
+        let mut id = self.pager.header.root;
+        while let Page::Inner { keys, children } = self.pager.page(id)? {
+            let i = keys.partition_point(|k| k.as_slice() <= key);
+            path.push((id, i));
+            id = children[i];
+        }
+        Ok((path, id))
+    }
+
+    fn put(&mut self, key: Vec<u8>, meta_data: MetaData) -> io::Result<()> {
+        self.version += 1;
+        let (path, leaf_id) = self.path(&key)?;
+        let Page::Leaf { keys, values, .. } = self.pager.page_mut(leaf_id)? else {
+            unreachable!()
+        };
+        match keys.binary_search(&key) {
+            Ok(i) => {
+                values[i] = meta_data;
+                return Ok(());
+            }
+            Err(i) => {
+                keys.insert(i, key);
+                values.insert(i, meta_data);
+            }
+        }
+        self.pager.header.key_num += 1;
+
+        // 叶子节点放不下时分裂，分隔 key 插入父节点，可能逐层向上分裂
+        let mut split = self.split_leaf(leaf_id)?;
+        for (parent_id, i) in path.into_iter().rev() {
+            let Some((separator, right_id)) = split else {
+                break;
+            };
+            let Page::Inner { keys, children } = self.pager.page_mut(parent_id)? else {
+                unreachable!()
+            };
+            keys.insert(i, separator);
+            children.insert(i + 1, right_id);
+            split = self.split_inner(parent_id)?;
+        }
+        if let Some((separator, right_id)) = split {
+            let root = self.pager.header.root;
+            self.pager.header.root = self.pager.allocate(Page::Inner {
+                keys: vec![separator],
+                children: vec![root, right_id],
+            });
+        }
+        Ok(())
+    }
+
+    fn split_leaf(&mut self, id: u32) -> io::Result<Option<(Vec<u8>, u32)>> {
+        let page = self.pager.page_mut(id)?;
+        let total = page.size();
+        if total <= PAGE_CAPACITY {
+            return Ok(None);
+        }
+        let Page::Leaf { keys, values, next } = page else {
+            unreachable!()
+        };
+        let sizes = keys.iter().map(|k| 2 + k.len() + META_DATA_SIZE);
+        let m = split_point(sizes, total, keys.len());
+        let right = Page::Leaf {
+            keys: keys.split_off(m),
+            values: values.split_off(m),
+            next: *next,
+        };
+        let separator = match &right {
+            Page::Leaf { keys, .. } => keys[0].clone(),
+            Page::Inner { .. } => unreachable!(),
+        };
+        let right_id = self.pager.allocate(right);
+        if let Page::Leaf { next, .. } = self.pager.page_mut(id)? {
+            *next = right_id;
+        }
+        Ok(Some((separator, right_id)))
+    }
+
+    fn split_inner(&mut self, id: u32) -> io::Result<Option<(Vec<u8>, u32)>> {
+        let page = self.pager.page_mut(id)?;
+        let total = page.size();
+        if total <= PAGE_CAPACITY {
+            return Ok(None);
+        }
+        let Page::Inner { keys, children } = page else {
+            unreachable!()
+        };
+        let sizes = keys.iter().map(|k| 2 + k.len() + 4);
+        let m = split_point(sizes, total, keys.len());
+        // keys[m] 上移到父节点
+        let mut right_keys = keys.split_off(m);
+        let separator = right_keys.remove(0);
+        let right = Page::Inner {
+            keys: right_keys,
+            children: children.split_off(m + 1),
+        };
+        Ok(Some((separator, self.pager.allocate(right))))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<bool> {
+        self.version += 1;
+        let (_, leaf_id) = self.path(key)?;
+        let Page::Leaf { keys, values, .. } = self.pager.page_mut(leaf_id)? else {
+            unreachable!()
+        };
+        match keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+            Ok(i) => {
+                keys.remove(i);
+                values.remove(i);
+                self.pager.header.key_num -= 1;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// 从叶子节点 id 的第 i 个 key 开始沿着 next 找到第一个 key 的位置，跳过删空的叶子节点
+    fn first_from(&mut self, mut id: u32, mut i: usize) -> io::Result<Option<(u32, usize)>> {
+        while id != 0 {
+            let Page::Leaf { keys, next, .. } = self.pager.page(id)? else {
+                return Err(invalid_data("index leaf links to an inner page"));
+            };
+            if i < keys.len() {
+                return Ok(Some((id, i)));
+            }
+            id = *next;
+            i = 0;
+        }
+        Ok(None)
+    }
+
+    /// 第一个大于等于 key 的位置，inclusive 为 false 时是第一个大于 key 的位置
+    fn seek_first(&mut self, key: &[u8], inclusive: bool) -> io::Result<Option<(u32, usize)>> {
+        let (_, id) = self.path(key)?;
+        let Page::Leaf { keys, .. } = self.pager.page(id)? else {
+            unreachable!()
+        };
+        let i = match inclusive {
+            true => keys.partition_point(|k| k.as_slice() < key),
+            false => keys.partition_point(|k| k.as_slice() <= key),
+        };
+        self.first_from(id, i)
+    }
+
+    /// 节点 id 的子树中最后一个小于等于 key 的位置，inclusive 为 false 时是最后一个小于 key 的位置。
+    /// key 为 None 时是子树中最后一个 key。叶子节点没有指向前一个节点的指针，从父节点向左查找
+    fn seek_last(
+        &mut self,
+        id: u32,
+        key: Option<&[u8]>,
+        inclusive: bool,
+    ) -> io::Result<Option<(u32, usize)>> {
+        let children = match self.pager.page(id)? {
+            Page::Leaf { keys, .. } => {
+                let n = match key {
+                    Some(key) if inclusive => keys.partition_point(|k| k.as_slice() <= key),
+                    Some(key) => keys.partition_point(|k| k.as_slice() < key),
+                    None => keys.len(),
+                };
+                return Ok(n.checked_sub(1).map(|i| (id, i)));
+            }
+            Page::Inner { keys, children } => {
+                let n = match key {
+                    Some(key) => keys.partition_point(|k| k.as_slice() <= key) + 1,
+                    None => children.len(),
+                };
+                children[..n].to_vec()
+            }
+        };
+        // 除了最后一个子节点，其余子节点中的 key 都小于 key
+        for (j, &child) in children.iter().enumerate().rev() {
+            let key = if j + 1 == children.len() { key } else { None };
+            if let Some(pos) = self.seek_last(child, key, inclusive)? {
+                return Ok(Some(pos));
+            }
+        }
+        Ok(None)
+    }
+
+    fn item_at(&mut self, id: u32, i: usize) -> io::Result<(Vec<u8>, MetaData)> {
+        let Page::Leaf { keys, values, .. } = self.pager.page(id)? else {
+            return Err(invalid_data("index leaf links to an inner page"));
+        };
+        Ok((keys[i].clone(), values[i]))
+    }
+
+    fn read_state(&mut self) -> io::Result<PersistedState> {
+        let header = self.pager.header;
+        let mut buf = vec![0; header.state_len as usize];
+        self.pager
+            .read_at(&mut buf, header.state_page as u64 * PAGE_SIZE as u64)?;
+        decode_state(&buf).ok_or_else(|| invalid_data("index state is corrupted"))
+    }
+
+    /// 写回所有脏页和状态，最后写入 clean 标记
+    fn persist(&mut self, state: &PersistedState) -> io::Result<()> {
+        let buf = encode_state(state);
+        let pages = buf.len().div_ceil(PAGE_SIZE) as u32;
+        let header = &mut self.pager.header;
+        if pages > header.state_pages {
+            header.state_page = header.page_num;
+            header.state_pages = pages;
+            header.page_num += pages;
+        }
+        header.state_len = buf.len() as u32;
+        let offset = header.state_page as u64 * PAGE_SIZE as u64;
+        self.pager.write_at(&buf, offset)?;
+        self.pager.flush()?;
+        self.pager.file.sync_all()?;
+
+        self.pager.header.clean = true;
+        self.pager.write_header()?;
+        self.pager.file.sync_all()?;
+        self.persisted_state = Some(state.clone());
+        Ok(())
+    }
+}
+
+fn encode_state(state: &PersistedState) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&state.data_files_checksum.to_le_bytes());
+    buf.extend_from_slice(&state.max_batch_seq.to_le_bytes());
+    buf.extend_from_slice(&(state.file_stats.len() as u32).to_le_bytes());
+    for file_stat in &state.file_stats {
+        buf.extend_from_slice(&file_stat.file_id.to_le_bytes());
+        buf.extend_from_slice(&(file_stat.total_bytes as u64).to_le_bytes());
+        buf.extend_from_slice(&(file_stat.dead_bytes as u64).to_le_bytes());
+    }
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn decode_state(buf: &[u8]) -> Option<PersistedState> {
+    let (content, crc) = buf.split_at_checked(buf.len().checked_sub(4)?)?;
+    if Crc::<u32>::new(&CRC_32_ISO_HDLC)
+        .checksum(content)
+        .to_le_bytes()
+        != crc
+    {
+        return None;
+    }
+    let mut r = Reader {
+        buf: content,
+        pos: 0,
+    };
+    let data_files_checksum = r.u32().ok()?;
+    let max_batch_seq = r.u64().ok()?;
+    let n = r.u32().ok()?;
+    let mut file_stats = Vec::new();
+    for _ in 0..n {
+        file_stats.push(FileStat {
+            file_id: r.u32().ok()?,
+            total_bytes: r.u64().ok()? as usize,
+            dead_bytes: r.u64().ok()? as usize,
+        });
+    }
+    Some(PersistedState {
+        data_files_checksum,
+        max_batch_seq,
+        file_stats,
+    })
+}
+
+impl BPlusTree {
+    /// 打开数据目录中的索引文件，最多缓存 cache_pages 个页
+    pub fn open(dir_path: &str, cache_pages: usize) -> R<Self> {
+        let file_path = Path::new(dir_path).join(BPTREE_INDEX_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .map_err(|e| {
+                error!("failed to open index file {:?}, {}", file_path, e);
+                Failed2OpenIndexFile
+            })?;
+
+        let mut header_buf = vec![0; HEADER_SIZE];
+        let mut tree = Tree {
+            pager: Pager {
+                file,
+                header: Header::default(),
+                cache: HashMap::new(),
+                cache_pages: cache_pages.max(1),
+                head: 0,
+                tail: 0,
+            },
+            persisted_state: None,
+            version: 0,
+        };
+        let header = match tree.pager.read_at(&mut header_buf, 0) {
+            Ok(_) => Header::decode(&header_buf),
+            Err(_) => None,
+        };
+        let opened = match header {
+            Some(header) if header.clean => {
+                tree.pager.header = header;
+                tree.read_state()
+                    .map(|state| tree.persisted_state = Some(state))
+            }
+            _ => Err(invalid_data("index file was not closed cleanly")),
+        };
+        let res = match opened {
+            // 之后的修改不会立即写回，在下一次 persist 之前崩溃时索引文件不可用
+            Ok(_) => {
+                tree.pager.header.clean = false;
+                tree.pager
+                    .write_header()
+                    .and_then(|_| tree.pager.file.sync_all())
+            }
+            Err(_) => tree.reset(),
+        };
+        res.map_err(|e| {
+            error!("failed to open index file {:?}, {}", file_path, e);
+            Failed2OpenIndexFile
+        })?;
+        Ok(Self {
+            tree: Arc::new(Mutex::new(tree)),
+        })
+    }
+}
+
+/// B+ 树的游标，沿着叶子节点遍历，页通过有界的页缓存读取，不会把范围内的 key 都读到内存中。
+/// 遍历期间不持有树的锁，树没有被修改时从上次的叶子节点继续，否则按上一个 key 重新定位
+pub struct BPlusTreeIterator {
+    tree: Arc<Mutex<Tree>>,
+    options: IteratorOptions,
+
+    /// 下一次从这个 key 开始定位，bool 表示是否包括这个 key，None 表示从头开始
+    position: Option<(Vec<u8>, bool)>,
+
+    /// 上一个 key 所在的叶子节点和下标，以及当时树的版本
+    leaf: Option<(u64, u32, usize)>,
+    current: Option<(Vec<u8>, MetaData)>,
+}
+
+impl BPlusTreeIterator {
+    /// 定位下一个 key 的位置，超出范围时返回 None
+    fn advance(&self, tree: &mut Tree) -> io::Result<Option<(u32, usize)>> {
+        let reverse = self.options.reverse;
+        let pos = match self.leaf {
+            Some((version, id, i)) if version == tree.version && !reverse => {
+                tree.first_from(id, i + 1)?
+            }
+            Some((version, id, i)) if version == tree.version && i > 0 => Some((id, i - 1)),
+            _ if reverse => {
+                let upper = self.options.upper_bound.as_deref();
+                let (key, inclusive) = match (&self.position, upper) {
+                    (Some((p, _)), Some(u)) if p.as_slice() >= u => (Some(u), false),
+                    (Some((p, inclusive)), _) => (Some(p.as_slice()), *inclusive),
+                    (None, upper) => (upper, false),
+                };
+                tree.seek_last(tree.pager.header.root, key, inclusive)?
+            }
+            _ => {
+                let lower = self.options.lower_bound.as_deref().unwrap_or_default();
+                match &self.position {
+                    Some((p, inclusive)) if p.as_slice() >= lower => {
+                        tree.seek_first(p, *inclusive)?
+                    }
+                    _ => tree.seek_first(lower, true)?,
+                }
+            }
+        };
+        Ok(pos)
+    }
+}
+
+impl IndexIterator for BPlusTreeIterator {
+    fn rewind(&mut self) {
+        self.position = None;
+        self.leaf = None;
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.position = Some((key.to_vec(), true));
+        self.leaf = None;
+    }
+
+    fn next(&mut self) -> Option<R<(&[u8], &MetaData)>> {
+        let tree = self.tree.clone();
+        let mut tree = tree.lock();
+        let res = self
+            .advance(&mut tree)
+            .and_then(|pos| match pos {
+                Some((id, i)) => Ok(Some((id, i, tree.item_at(id, i)?))),
+                None => Ok(None),
+            })
+            .and_then(|res| tree.pager.evict().map(|_| res));
+        let (id, i, (key, meta_data)) = match res {
+            Ok(Some(item)) => item,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("failed to read index file, {}", e);
+                return Some(Err(Failed2ReadIndexFile));
+            }
+        };
+        if !self.options.contains(&key) {
+            return None;
+        }
+        self.leaf = Some((tree.version, id, i));
+        self.position = Some((key.clone(), false));
+        drop(tree);
+
+        let (key, meta_data) = self.current.insert((key, meta_data));
+        Some(Ok((key, meta_data)))
+    }
+}
+
+impl Indexer for BPlusTree {
+    /// key 超过 BPTREE_MAX_KEY_SIZE 或者写索引文件失败时返回 false
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+        if key.len() > BPTREE_MAX_KEY_SIZE {
+            return false;
+        }
+        let mut tree = self.tree.lock();
+        match tree.put(key, meta_data).and_then(|_| tree.pager.evict()) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("failed to update index file, {}", e);
+                false
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+        let mut tree = self.tree.lock();
+        tree.get(key)
+            .and_then(|res| tree.pager.evict().map(|_| res))
+            .map_err(|e| {
+                error!("failed to read index file, {}", e);
+                Failed2ReadIndexFile
+            })
+    }
+
+    fn delete(&self, key: &[u8]) -> R<bool> {
+        let mut tree = self.tree.lock();
+        tree.delete(key)
+            .and_then(|res| tree.pager.evict().map(|_| res))
+            .map_err(|e| {
+                error!("failed to update index file, {}", e);
+                Failed2UpdateMemIndex
+            })
+    }
+
+    /// 遍历时才读取叶子节点，读取失败时由迭代器返回错误
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(BPlusTreeIterator {
+            tree: self.tree.clone(),
+            options: options.clone(),
+            position: None,
+            leaf: None,
+            current: None,
+        })
+    }
+
+    fn key_num(&self) -> usize {
+        self.tree.lock().pager.header.key_num as usize
+    }
+
+    /// 只计算缓存的页
+    fn memory_size(&self) -> usize {
+        self.tree.lock().pager.cache.len() * PAGE_SIZE
+    }
+
+    fn max_key_size(&self) -> usize {
+        BPTREE_MAX_KEY_SIZE
+    }
+
+    fn persisted_state(&self) -> Option<PersistedState> {
+        self.tree.lock().persisted_state.clone()
+    }
+
+    fn reset(&self) -> R<()> {
+        let mut tree = self.tree.lock();
+        tree.persisted_state = None;
+        tree.reset().map_err(|e| {
+            error!("failed to reset index file, {}", e);
+            Failed2WriteIndexFile
+        })
+    }
+
+    fn persist(&self, state: &PersistedState) -> R<()> {
+        self.tree.lock().persist(state).map_err(|e| {
+            error!("failed to persist index file, {}", e);
+            Failed2WriteIndexFile
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn tmp_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("bitcask-rs-bptree-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.display().to_string()
+    }
+
+    fn keys(tree: &BPlusTree, options: &IteratorOptions) -> Vec<Vec<u8>> {
+        let mut iter = tree.iterator(options);
+        let mut keys = Vec::new();
+        while let Some(item) = iter.next() {
+            keys.push(item.unwrap().0.to_vec());
+        }
+        keys
+    }
+
+    #[test]
+    fn test_bptree_put_get_delete() {
+        let tree = BPlusTree::open(&tmp_dir("basic"), 4).unwrap();
+        assert!(tree.persisted_state().is_none());
+        let fake_meta_data = MetaData::new(0, 1, 2, 3);
+        assert!(tree.put("hello".as_bytes().to_vec(), fake_meta_data));
+        assert_eq!(
+            tree.get("hello".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+
+        let new_meta_data = MetaData::new(1, 1, 2, 4);
+        assert!(tree.put("hello".as_bytes().to_vec(), new_meta_data));
+        assert_eq!(
+            tree.get("hello".as_bytes()).unwrap().unwrap(),
+            new_meta_data
+        );
+        assert_eq!(tree.key_num(), 1);
+
+        assert!(tree.delete("hello".as_bytes()).unwrap());
+        assert!(!tree.delete("hello".as_bytes()).unwrap());
+        assert_eq!(tree.get("hello".as_bytes()).unwrap(), None);
+        assert_eq!(tree.key_num(), 0);
+
+        assert!(!tree.put(vec![1; BPTREE_MAX_KEY_SIZE + 1], fake_meta_data));
+    }
+
+    #[test]
+    fn test_bptree_against_btree_map() {
+        // 缓存很小，大部分页都需要从文件中读取
+        let tree = BPlusTree::open(&tmp_dir("random"), 8).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..20000usize {
+            let key = format!("key-{:08}", i.wrapping_mul(7919) % 20011).into_bytes();
+            if i % 4 == 0 {
+                assert_eq!(tree.delete(&key).unwrap(), expected.remove(&key).is_some());
+            } else {
+                let meta_data = MetaData::new(i as u32, i, i * 2, i as u64);
+                assert!(tree.put(key.clone(), meta_data));
+                expected.insert(key, meta_data);
+            }
+        }
+        assert!(tree.tree.lock().pager.cache.len() <= 8);
+        assert_eq!(tree.key_num(), expected.len());
+        for (key, meta_data) in &expected {
+            assert_eq!(tree.get(key).unwrap(), Some(*meta_data));
+        }
+
+        let all: Vec<Vec<u8>> = expected.keys().cloned().collect();
+        assert_eq!(keys(&tree, &IteratorOptions::default()), all);
+        let options = IteratorOptions {
+            lower_bound: Some("key-00005000".as_bytes().to_vec()),
+            upper_bound: Some("key-00006000".as_bytes().to_vec()),
+            reverse: true,
+        };
+        let mut range: Vec<Vec<u8>> = all
+            .iter()
+            .filter(|k| options.contains(k))
+            .cloned()
+            .collect();
+        range.reverse();
+        assert_eq!(keys(&tree, &options), range);
+    }
+
+    #[test]
+    fn test_bptree_cursor() {
+        // 遍历全部 key 时只通过页缓存读取叶子节点
+        let tree = BPlusTree::open(&tmp_dir("cursor"), 4).unwrap();
+        for i in 0..5000usize {
+            tree.put(
+                format!("key-{:05}", i).into_bytes(),
+                MetaData::new(0, i, i, 0),
+            );
+        }
+        let mut iter = tree.iterator(&IteratorOptions::default());
+        let mut count = 0;
+        while let Some(item) = iter.next() {
+            assert_eq!(item.unwrap().1.entry_sz, count);
+            assert!(tree.tree.lock().pager.cache.len() <= 4);
+            count += 1;
+        }
+        assert_eq!(count, 5000);
+
+        // 遍历期间修改索引，游标按上一个 key 重新定位
+        let mut iter = tree.iterator(&IteratorOptions::default());
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-00000".as_bytes());
+        tree.delete("key-00001".as_bytes()).unwrap();
+        tree.put("key-00000a".as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-00000a".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-00002".as_bytes());
+
+        let options = IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        };
+        let mut iter = tree.iterator(&options);
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-04999".as_bytes());
+        iter.seek("key-00001".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-00000a".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "key-00000".as_bytes());
+        assert!(iter.next().is_none());
+
+        // 读取叶子节点失败时返回错误，而不是当作遍历结束
+        tree.persist(&PersistedState::default()).unwrap();
+        let leaf_id = tree.tree.lock().path("key-02500".as_bytes()).unwrap().1;
+        tree.tree
+            .lock()
+            .pager
+            .write_at(&[0xff], leaf_id as u64 * PAGE_SIZE as u64)
+            .unwrap();
+        tree.tree.lock().pager.clear_cache();
+        let mut iter = tree.iterator(&IteratorOptions::default());
+        let res: Vec<R<Vec<u8>>> =
+            std::iter::from_fn(|| iter.next().map(|item| item.map(|(key, _)| key.to_vec())))
+                .take(5000)
+                .collect();
+        assert!(matches!(res.last(), Some(Err(Failed2ReadIndexFile))));
+    }
+
+    #[test]
+    fn test_bptree_page_cache_lru() {
+        let tree = BPlusTree::open(&tmp_dir("lru"), 3).unwrap();
+        for i in 0..2000 {
+            assert!(tree.put(
+                format!("key-{:05}", i).into_bytes(),
+                MetaData::new(0, 1, 2, 3)
+            ));
+        }
+        let mut tree = tree.tree.lock();
+        let pager = &mut tree.pager;
+        assert!(pager.header.page_num > 6);
+        let lru_order = |pager: &Pager| {
+            let mut ids = Vec::new();
+            let mut id = pager.head;
+            while id != 0 {
+                ids.push(id);
+                id = pager.cache[&id].next;
+            }
+            assert_eq!(ids.last().copied().unwrap_or(0), pager.tail);
+            ids
+        };
+
+        for id in 1..pager.header.page_num {
+            pager.page(id).unwrap();
+            pager.evict().unwrap();
+        }
+        let n = pager.header.page_num;
+        assert_eq!(lru_order(pager), vec![n - 1, n - 2, n - 3]);
+
+        // 再次使用的页移到链表头，换出的是最久没有使用的页
+        pager.page(n - 3).unwrap();
+        pager.page(1).unwrap();
+        pager.evict().unwrap();
+        assert_eq!(lru_order(pager), vec![1, n - 3, n - 1]);
+    }
+
+    #[test]
+    fn test_bptree_page_checksum() {
+        let tree = BPlusTree::open(&tmp_dir("checksum"), 8).unwrap();
+        for i in 0..5000 {
+            assert!(tree.put(
+                format!("key-{:05}", i).into_bytes(),
+                MetaData::new(0, 1, 2, 3)
+            ));
+        }
+        tree.persist(&PersistedState::default()).unwrap();
+
+        // 页中任意一个字节损坏都能发现，包括没有使用的空间
+        let leaf_id = tree.tree.lock().path("key-02500".as_bytes()).unwrap().1;
+        tree.tree
+            .lock()
+            .pager
+            .write_at(
+                &[0xff],
+                (leaf_id as usize * PAGE_SIZE + PAGE_CAPACITY - 1) as u64,
+            )
+            .unwrap();
+        tree.tree.lock().pager.clear_cache();
+        assert!(matches!(
+            tree.get("key-02500".as_bytes()),
+            Err(Failed2ReadIndexFile)
+        ));
+        assert!(tree.get("key-00000".as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_bptree_persist() {
+        let dir = tmp_dir("persist");
+        let state = PersistedState {
+            data_files_checksum: 42,
+            max_batch_seq: 7,
+            file_stats: vec![FileStat {
+                file_id: 0,
+                total_bytes: 100,
+                dead_bytes: 10,
+            }],
+        };
+        let tree = BPlusTree::open(&dir, 4).unwrap();
+        for i in 0..1000usize {
+            tree.put(format!("key-{}", i).into_bytes(), MetaData::new(0, i, i, 0));
+        }
+        tree.persist(&state).unwrap();
+        drop(tree);
+
+        let tree = BPlusTree::open(&dir, 4).unwrap();
+        assert_eq!(tree.persisted_state(), Some(state.clone()));
+        assert_eq!(tree.key_num(), 1000);
+        for i in 0..1000usize {
+            let meta_data = tree.get(format!("key-{}", i).as_bytes()).unwrap().unwrap();
+            assert_eq!(meta_data.entry_sz, i);
+        }
+        tree.put("new".as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        drop(tree);
+
+        // 没有正常关闭的索引文件被清空
+        let tree = BPlusTree::open(&dir, 4).unwrap();
+        assert!(tree.persisted_state().is_none());
+        assert_eq!(tree.key_num(), 0);
+        assert_eq!(tree.get("key-1".as_bytes()).unwrap(), None);
+    }
+}
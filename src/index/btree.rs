@@ -1,12 +1,23 @@
 use crate::data::meta_data::MetaData;
-use crate::index::Indexer;
+use crate::error::R;
+use crate::index::iterator::{CursorIterator, OrderedIndex};
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
 use parking_lot::RwLock;
 use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Bound;
 use std::sync::Arc;
 
 /// 主要封装了标准库的 BTreeMap
 pub struct BTree {
-    tree: Arc<RwLock<BTreeMap<String, MetaData>>>,
+    tree: Arc<RwLock<BTreeMap<Vec<u8>, MetaData>>>,
+}
+
+impl Default for BTree {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BTree {
@@ -18,21 +29,55 @@ impl BTree {
 }
 
 impl Indexer for BTree {
-    fn put(&self, key: String, meta_data: MetaData) -> bool {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
         let mut write_guard = self.tree.write();
         write_guard.insert(key, meta_data);
         true
     }
 
-    fn get(&self, key: &String) -> Option<MetaData> {
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
         let read_guard = self.tree.read();
-        read_guard.get(key).copied()
+        Ok(read_guard.get(key).copied())
     }
 
-    fn delete(&self, key: &String) -> bool {
+    fn delete(&self, key: &[u8]) -> R<bool> {
         let mut write_guard = self.tree.write();
         let remove_res = write_guard.remove(key);
-        remove_res.is_some()
+        Ok(remove_res.is_some())
+    }
+
+    /// BTreeMap 本身有序，遍历时逐个定位范围内的 key，不复制整个范围
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(CursorIterator::new(self.tree.clone(), options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.tree.read().len()
+    }
+
+    /// 不包括 B 树节点自身的开销
+    fn memory_size(&self) -> usize {
+        let read_guard = self.tree.read();
+        read_guard.len() * mem::size_of::<(Vec<u8>, MetaData)>()
+            + read_guard.keys().map(Vec::capacity).sum::<usize>()
+    }
+}
+
+impl OrderedIndex for RwLock<BTreeMap<Vec<u8>, MetaData>> {
+    fn first_in(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Option<(Vec<u8>, MetaData)> {
+        let read_guard = self.read();
+        let mut range = read_guard.range::<[u8], _>((lower, upper));
+        let (k, v) = if reverse {
+            range.next_back()?
+        } else {
+            range.next()?
+        };
+        Some((k.clone(), *v))
     }
 }
 
@@ -44,19 +89,19 @@ mod tests {
     fn test_btree_put() {
         let tree = BTree::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let x = tree.put("hello".to_string(), fake_meta_data);
-        assert_eq!(x, true);
+        let x = tree.put("hello".as_bytes().to_vec(), fake_meta_data);
+        assert!(x);
     }
 
     #[test]
     fn test_btree_get() {
         let tree = BTree::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let k = "hello".to_string();
+        let k = "hello".as_bytes().to_vec();
         let x = tree.put(k, fake_meta_data);
-        assert_eq!(x, true);
-        let k = "hello".to_string();
-        let get_res = tree.get(&k);
+        assert!(x);
+        let k = "hello".as_bytes().to_vec();
+        let get_res = tree.get(&k).unwrap();
         assert_eq!(get_res.unwrap(), fake_meta_data);
     }
 
@@ -64,15 +109,39 @@ mod tests {
     fn test_btree_delete() {
         let tree = BTree::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let k = "hello".to_string();
+        let k = "hello".as_bytes().to_vec();
         let x = tree.put(k, fake_meta_data);
-        assert_eq!(x, true);
-        let k = "hello".to_string();
-        let get_res = tree.get(&k);
+        assert!(x);
+        let k = "hello".as_bytes().to_vec();
+        let get_res = tree.get(&k).unwrap();
         assert_eq!(get_res.unwrap(), fake_meta_data);
-        let removed_data = tree.delete(&k);
-        assert_eq!(removed_data, true);
-        let get_res = tree.get(&k);
+        let removed_data = tree.delete(&k).unwrap();
+        assert!(removed_data);
+        let get_res = tree.get(&k).unwrap();
         assert_eq!(get_res, None);
     }
+
+    #[test]
+    fn test_btree_iterator() {
+        let tree = BTree::new();
+        for k in ["b", "d", "a", "c"] {
+            tree.put(k.as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        }
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            upper_bound: Some("d".as_bytes().to_vec()),
+            reverse: true,
+        };
+        let mut iter = tree.iterator(&options);
+        assert_eq!(iter.next().unwrap().unwrap().0, "c".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+        assert!(iter.next().is_none());
+
+        let options = IteratorOptions {
+            lower_bound: Some("d".as_bytes().to_vec()),
+            upper_bound: Some("a".as_bytes().to_vec()),
+            reverse: false,
+        };
+        assert!(tree.iterator(&options).next().is_none());
+    }
 }
@@ -0,0 +1,350 @@
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::index::iterator::SortedIterator;
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
+use parking_lot::RwLock;
+use std::hash::{BuildHasher, RandomState};
+use std::mem;
+
+/// 紧凑的 hash 索引，所有 key 连续保存在 arena 中，MetaData 压缩为定长的窄整数，
+/// 每个 key 只占用一个 32 字节的槽位加上 key 本身的字节数。
+/// entry 的大小和位置必须能用 u32 表示，否则 put 返回 false
+pub struct CompactKeyDir {
+    table: RwLock<Table>,
+}
+
+/// 开放寻址 (线性探测) 的 hash 表
+struct Table {
+    slots: Vec<Slot>,
+    arena: Vec<u8>,
+
+    /// 存活的 key 数量
+    len: usize,
+
+    /// 被删除的槽位数量
+    tombstones: usize,
+
+    /// arena 中被删除的 key 占用的字节数
+    dead_key_bytes: usize,
+
+    hasher: RandomState,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    key_off: u64,
+    key_len: u32,
+    meta_data: PackedMetaData,
+}
+
+/// 空槽位和被删除的槽位的 key_off
+const EMPTY: u64 = u64::MAX;
+const TOMBSTONE: u64 = u64::MAX - 1;
+
+const EMPTY_SLOT: Slot = Slot {
+    key_off: EMPTY,
+    key_len: 0,
+    meta_data: PackedMetaData {
+        file_id: 0,
+        entry_sz: 0,
+        entry_start_pos: 0,
+        tstamp: [0, 0],
+    },
+};
+
+/// 4 字节对齐，tstamp 拆成两个 u32，整个结构 20 字节
+#[derive(Clone, Copy)]
+struct PackedMetaData {
+    file_id: u32,
+    entry_sz: u32,
+    entry_start_pos: u32,
+    tstamp: [u32; 2],
+}
+
+impl PackedMetaData {
+    fn pack(meta_data: MetaData) -> Option<Self> {
+        Some(Self {
+            file_id: meta_data.file_id,
+            entry_sz: meta_data.entry_sz.try_into().ok()?,
+            entry_start_pos: meta_data.entry_start_pos.try_into().ok()?,
+            tstamp: [meta_data.tstamp as u32, (meta_data.tstamp >> 32) as u32],
+        })
+    }
+
+    fn unpack(&self) -> MetaData {
+        MetaData::new(
+            self.file_id,
+            self.entry_sz as usize,
+            self.entry_start_pos as usize,
+            self.tstamp[0] as u64 | (self.tstamp[1] as u64) << 32,
+        )
+    }
+}
+
+impl Slot {
+    fn is_live(&self) -> bool {
+        self.key_off < TOMBSTONE
+    }
+}
+
+impl Table {
+    fn key(&self, slot: &Slot) -> &[u8] {
+        let off = slot.key_off as usize;
+        &self.arena[off..off + slot.key_len as usize]
+    }
+
+    /// 找到 key 时返回 Ok(槽位)，否则返回 Err(可以插入的槽位)
+    fn find(&self, key: &[u8]) -> Result<usize, usize> {
+        let mask = self.slots.len() - 1;
+        let mut i = self.hasher.hash_one(key) as usize & mask;
+        let mut first_tombstone = None;
+        loop {
+            let slot = &self.slots[i];
+            match slot.key_off {
+                EMPTY => return Err(first_tombstone.unwrap_or(i)),
+                TOMBSTONE => {
+                    first_tombstone.get_or_insert(i);
+                }
+                _ => {
+                    if slot.key_len as usize == key.len() && self.key(slot) == key {
+                        return Ok(i);
+                    }
+                }
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<MetaData> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        self.find(key)
+            .ok()
+            .map(|i| self.slots[i].meta_data.unpack())
+    }
+
+    fn put(&mut self, key: &[u8], meta_data: PackedMetaData) -> bool {
+        let Ok(key_len) = u32::try_from(key.len()) else {
+            return false;
+        };
+        // 包括被删除的槽位在内最多使用 7/8
+        if (self.len + self.tombstones + 1) * 8 > self.slots.len() * 7 {
+            let capacity = if (self.len + 1) * 2 > self.slots.len() {
+                (self.slots.len() * 2).max(8)
+            } else {
+                self.slots.len()
+            };
+            self.rebuild(capacity);
+        }
+
+        match self.find(key) {
+            Ok(i) => self.slots[i].meta_data = meta_data,
+            Err(i) => {
+                if self.slots[i].key_off == TOMBSTONE {
+                    self.tombstones -= 1;
+                }
+                self.slots[i] = Slot {
+                    key_off: self.arena.len() as u64,
+                    key_len,
+                    meta_data,
+                };
+                self.arena.extend_from_slice(key);
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    fn delete(&mut self, key: &[u8]) -> bool {
+        if self.slots.is_empty() {
+            return false;
+        }
+        let Ok(i) = self.find(key) else {
+            return false;
+        };
+        self.dead_key_bytes += self.slots[i].key_len as usize;
+        self.slots[i].key_off = TOMBSTONE;
+        self.len -= 1;
+        self.tombstones += 1;
+
+        // 一半以上的 arena 是被删除的 key 时回收
+        if self.dead_key_bytes * 2 > self.arena.len() {
+            self.rebuild(self.slots.len());
+        }
+        true
+    }
+
+    /// 重新构建 hash 表和 arena，丢弃被删除的槽位和 key
+    fn rebuild(&mut self, capacity: usize) {
+        let live_key_bytes = self.arena.len() - self.dead_key_bytes;
+        let old_slots = mem::replace(&mut self.slots, vec![EMPTY_SLOT; capacity]);
+        let old_arena = mem::replace(&mut self.arena, Vec::with_capacity(live_key_bytes));
+        self.tombstones = 0;
+        self.dead_key_bytes = 0;
+        let mask = capacity - 1;
+        for slot in old_slots.into_iter().filter(Slot::is_live) {
+            let off = slot.key_off as usize;
+            let key = &old_arena[off..off + slot.key_len as usize];
+            let mut i = self.hasher.hash_one(key) as usize & mask;
+            while self.slots[i].key_off != EMPTY {
+                i = (i + 1) & mask;
+            }
+            self.slots[i] = Slot {
+                key_off: self.arena.len() as u64,
+                ..slot
+            };
+            self.arena.extend_from_slice(key);
+        }
+    }
+}
+
+impl Default for CompactKeyDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompactKeyDir {
+    pub fn new() -> Self {
+        Self {
+            table: RwLock::new(Table {
+                slots: Vec::new(),
+                arena: Vec::new(),
+                len: 0,
+                tombstones: 0,
+                dead_key_bytes: 0,
+                hasher: RandomState::new(),
+            }),
+        }
+    }
+}
+
+impl Indexer for CompactKeyDir {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+        match PackedMetaData::pack(meta_data) {
+            Some(meta_data) => self.table.write().put(&key, meta_data),
+            None => false,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+        Ok(self.table.read().get(key))
+    }
+
+    fn delete(&self, key: &[u8]) -> R<bool> {
+        Ok(self.table.write().delete(key))
+    }
+
+    /// 与 KeyDir 一样没有顺序，需要扫描全部 key 再排序
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        let table = self.table.read();
+        let items = table
+            .slots
+            .iter()
+            .filter(|slot| slot.is_live())
+            .map(|slot| (table.key(slot).to_vec(), slot.meta_data.unpack()));
+        Box::new(SortedIterator::from_unsorted(items, options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.table.read().len
+    }
+
+    fn memory_size(&self) -> usize {
+        let table = self.table.read();
+        table.slots.capacity() * mem::size_of::<Slot>() + table.arena.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::keydir::KeyDir;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_compact_keydir_put_get_delete() {
+        let keydir = CompactKeyDir::new();
+        assert_eq!(keydir.get("hello".as_bytes()).unwrap(), None);
+        assert!(!keydir.delete("hello".as_bytes()).unwrap());
+
+        let fake_meta_data = MetaData::new(7, 1, 2, u64::MAX - 3);
+        assert!(keydir.put("hello".as_bytes().to_vec(), fake_meta_data));
+        assert_eq!(
+            keydir.get("hello".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+
+        let new_meta_data = MetaData::new(8, 10, 20, 30);
+        assert!(keydir.put("hello".as_bytes().to_vec(), new_meta_data));
+        assert_eq!(
+            keydir.get("hello".as_bytes()).unwrap().unwrap(),
+            new_meta_data
+        );
+        assert_eq!(keydir.key_num(), 1);
+
+        assert!(keydir.delete("hello".as_bytes()).unwrap());
+        assert!(!keydir.delete("hello".as_bytes()).unwrap());
+        assert_eq!(keydir.get("hello".as_bytes()).unwrap(), None);
+        assert_eq!(keydir.key_num(), 0);
+
+        // 不能用 u32 表示的位置放不进紧凑索引
+        let too_large = MetaData::new(0, 1, u32::MAX as usize + 1, 0);
+        assert!(!keydir.put("hello".as_bytes().to_vec(), too_large));
+        assert_eq!(keydir.get("hello".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_compact_keydir_against_hash_map() {
+        let keydir = CompactKeyDir::new();
+        let mut expected = HashMap::new();
+        for round in 0..3u32 {
+            for i in 0..5000usize {
+                let key = format!("key-{}", i).into_bytes();
+                if (i + round as usize).is_multiple_of(3) {
+                    assert_eq!(
+                        keydir.delete(&key).unwrap(),
+                        expected.remove(&key).is_some()
+                    );
+                } else {
+                    let meta_data = MetaData::new(round, i, i * 2, i as u64);
+                    assert!(keydir.put(key.clone(), meta_data));
+                    expected.insert(key, meta_data);
+                }
+            }
+        }
+        assert_eq!(keydir.key_num(), expected.len());
+        for (key, meta_data) in &expected {
+            assert_eq!(keydir.get(key).unwrap(), Some(*meta_data));
+        }
+        // 被删除的 key 已经从 arena 中回收
+        let table = keydir.table.read();
+        assert!(table.dead_key_bytes * 2 <= table.arena.len());
+        drop(table);
+
+        let mut iter = keydir.iterator(&IteratorOptions::default());
+        let mut count = 0;
+        while let Some(item) = iter.next() {
+            let (key, meta_data) = item.unwrap();
+            assert_eq!(expected.get(key), Some(meta_data));
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+    }
+
+    #[test]
+    fn test_compact_keydir_memory_size() {
+        let keydir = KeyDir::new();
+        let compact = CompactKeyDir::new();
+        for i in 0..10000 {
+            let key = format!("tenant/entity/{}", i).into_bytes();
+            let meta_data = MetaData::new(0, 64, i * 64, i as u64);
+            keydir.put(key.clone(), meta_data);
+            compact.put(key, meta_data);
+        }
+        assert_eq!(compact.key_num(), keydir.key_num());
+        assert!(compact.memory_size() < keydir.memory_size());
+    }
+}
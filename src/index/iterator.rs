@@ -0,0 +1,277 @@
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::index::IndexIterator;
+use crate::options::IteratorOptions;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// 创建迭代器时把范围内的 key 复制出来并排好序，之后的遍历不需要持有索引的锁。
+/// 无序的索引（例如 hash 表）也可以基于它提供有序遍历
+pub struct SortedIterator {
+    /// 按遍历顺序排列，逆序遍历时从大到小
+    items: Vec<(Vec<u8>, MetaData)>,
+    pos: usize,
+    reverse: bool,
+}
+
+impl SortedIterator {
+    /// items 需要已经按 key 从小到大排序
+    pub fn new(mut items: Vec<(Vec<u8>, MetaData)>, reverse: bool) -> Self {
+        if reverse {
+            items.reverse();
+        }
+        Self {
+            items,
+            pos: 0,
+            reverse,
+        }
+    }
+
+    /// 从无序的 items 中筛选出范围内的 key 并排序
+    pub fn from_unsorted<I>(items: I, options: &IteratorOptions) -> Self
+    where
+        I: IntoIterator<Item = (Vec<u8>, MetaData)>,
+    {
+        let mut items: Vec<(Vec<u8>, MetaData)> = items
+            .into_iter()
+            .filter(|(key, _)| options.contains(key))
+            .collect();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::new(items, options.reverse)
+    }
+}
+
+impl IndexIterator for SortedIterator {
+    fn rewind(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.pos = if self.reverse {
+            self.items.partition_point(|(k, _)| k.as_slice() > key)
+        } else {
+            self.items.partition_point(|(k, _)| k.as_slice() < key)
+        };
+    }
+
+    fn next(&mut self) -> Option<R<(&[u8], &MetaData)>> {
+        let (key, meta_data) = self.items.get(self.pos)?;
+        self.pos += 1;
+        Some(Ok((key, meta_data)))
+    }
+}
+
+/// 本身有序的索引，可以在 (lower, upper) 范围内定位第一个或者最后一个 key
+pub trait OrderedIndex: Send + Sync {
+    /// 返回范围内最小的 key，reverse 时返回最大的 key
+    fn first_in(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Option<(Vec<u8>, MetaData)>;
+}
+
+/// 有序索引的游标，每次 next 从索引中定位上一个 key 之后的 key，不复制范围内的 key。
+/// 遍历期间不持有索引的锁，之后写入的 key 也可能被遍历到
+pub struct CursorIterator<T: OrderedIndex + ?Sized> {
+    index: Arc<T>,
+    options: IteratorOptions,
+
+    /// 下一次从这个 key 开始定位，bool 表示是否包括这个 key，None 表示从头开始
+    position: Option<(Vec<u8>, bool)>,
+    current: Option<(Vec<u8>, MetaData)>,
+}
+
+impl<T: OrderedIndex + ?Sized> CursorIterator<T> {
+    pub fn new(index: Arc<T>, options: &IteratorOptions) -> Self {
+        Self {
+            index,
+            options: options.clone(),
+            position: None,
+            current: None,
+        }
+    }
+}
+
+/// 范围为空时 BTreeMap::range 会 panic，调用之前先检查
+fn is_empty_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+            l >= u
+        }
+        _ => false,
+    }
+}
+
+impl<T: OrderedIndex + ?Sized> IndexIterator for CursorIterator<T> {
+    fn rewind(&mut self) {
+        self.position = None;
+    }
+
+    /// 超出 options 范围的 key 等同于 rewind
+    fn seek(&mut self, key: &[u8]) {
+        self.position = Some((key.to_vec(), true));
+    }
+
+    fn next(&mut self) -> Option<R<(&[u8], &MetaData)>> {
+        let lower_bound = self.options.lower_bound.as_deref();
+        let upper_bound = self.options.upper_bound.as_deref();
+        let position = self
+            .position
+            .as_ref()
+            .map(|(key, inclusive)| match inclusive {
+                true => Bound::Included(key.as_slice()),
+                false => Bound::Excluded(key.as_slice()),
+            });
+        // position 只在遍历方向上收紧范围
+        let (lower, upper) = if self.options.reverse {
+            let upper = match (position, upper_bound) {
+                (Some(Bound::Included(p) | Bound::Excluded(p)), Some(u)) if p >= u => {
+                    Bound::Excluded(u)
+                }
+                (Some(p), _) => p,
+                (None, Some(u)) => Bound::Excluded(u),
+                (None, None) => Bound::Unbounded,
+            };
+            (lower_bound.map_or(Bound::Unbounded, Bound::Included), upper)
+        } else {
+            let lower = match (position, lower_bound) {
+                (Some(Bound::Included(p) | Bound::Excluded(p)), Some(l)) if p < l => {
+                    Bound::Included(l)
+                }
+                (Some(p), _) => p,
+                (None, Some(l)) => Bound::Included(l),
+                (None, None) => Bound::Unbounded,
+            };
+            (lower, upper_bound.map_or(Bound::Unbounded, Bound::Excluded))
+        };
+
+        self.current = if is_empty_range(lower, upper) {
+            None
+        } else {
+            self.index.first_in(lower, upper, self.options.reverse)
+        };
+        let (key, meta_data) = self.current.as_ref()?;
+        self.position = Some((key.clone(), false));
+        Some(Ok((key, meta_data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(iter: &mut dyn IndexIterator) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        while let Some(item) = iter.next() {
+            keys.push(item.unwrap().0.to_vec());
+        }
+        keys
+    }
+
+    fn items() -> Vec<(Vec<u8>, MetaData)> {
+        ["c", "a", "e", "b", "d"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.as_bytes().to_vec(), MetaData::new(0, 1, i, 0)))
+            .collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<Vec<u8>> {
+        keys.iter().map(|k| k.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_sorted_iterator() {
+        let mut iter = SortedIterator::from_unsorted(items(), &IteratorOptions::default());
+        assert_eq!(collect(&mut iter), keys(&["a", "b", "c", "d", "e"]));
+        assert!(iter.next().is_none());
+
+        iter.rewind();
+        assert_eq!(iter.next().unwrap().unwrap().0, "a".as_bytes());
+        iter.seek("c".as_bytes());
+        assert_eq!(collect(&mut iter), keys(&["c", "d", "e"]));
+        iter.seek("bb".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "c".as_bytes());
+        iter.seek("f".as_bytes());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_sorted_iterator_reverse() {
+        let options = IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        };
+        let mut iter = SortedIterator::from_unsorted(items(), &options);
+        assert_eq!(collect(&mut iter), keys(&["e", "d", "c", "b", "a"]));
+
+        iter.seek("c".as_bytes());
+        assert_eq!(collect(&mut iter), keys(&["c", "b", "a"]));
+        iter.seek("bb".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+    }
+
+    #[test]
+    fn test_cursor_iterator() {
+        let map = Arc::new(parking_lot::RwLock::new(std::collections::BTreeMap::new()));
+        for (key, meta_data) in items() {
+            map.write().insert(key, meta_data);
+        }
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            upper_bound: Some("e".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        let mut iter = CursorIterator::new(map.clone(), &options);
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+
+        // 遍历期间写入的 key 在游标之后时可以遍历到
+        map.write()
+            .insert("bb".as_bytes().to_vec(), MetaData::new(0, 1, 9, 0));
+        map.write().remove("c".as_bytes());
+        assert_eq!(collect(&mut iter), keys(&["bb", "d"]));
+        assert!(iter.next().is_none());
+
+        iter.rewind();
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+        iter.seek("a".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+        iter.seek("bc".as_bytes());
+        assert_eq!(collect(&mut iter), keys(&["d"]));
+        iter.seek("e".as_bytes());
+        assert!(iter.next().is_none());
+
+        let options = IteratorOptions {
+            reverse: true,
+            ..options
+        };
+        let mut iter = CursorIterator::new(map.clone(), &options);
+        assert_eq!(collect(&mut iter), keys(&["d", "bb", "b"]));
+        iter.seek("z".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "d".as_bytes());
+        iter.seek("bc".as_bytes());
+        assert_eq!(collect(&mut iter), keys(&["bb", "b"]));
+
+        // 上下界相等或者颠倒时是空的范围
+        let options = IteratorOptions {
+            lower_bound: Some("d".as_bytes().to_vec()),
+            upper_bound: Some("b".as_bytes().to_vec()),
+            reverse: false,
+        };
+        assert!(CursorIterator::new(map, &options).next().is_none());
+    }
+
+    #[test]
+    fn test_sorted_iterator_range() {
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            upper_bound: Some("d".as_bytes().to_vec()),
+            ..Default::default()
+        };
+        let mut iter = SortedIterator::from_unsorted(items(), &options);
+        assert_eq!(collect(&mut iter), keys(&["b", "c"]));
+    }
+}
@@ -1,13 +1,23 @@
 use std::collections::HashMap;
+use std::mem;
 use std::sync::Arc;
 
 use parking_lot::RwLock;
 
 use crate::data::meta_data::MetaData;
-use crate::index::Indexer;
+use crate::error::R;
+use crate::index::iterator::SortedIterator;
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
 
 pub struct KeyDir {
-    hash_table: Arc<RwLock<HashMap<String, MetaData>>>,
+    hash_table: Arc<RwLock<HashMap<Vec<u8>, MetaData>>>,
+}
+
+impl Default for KeyDir {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KeyDir {
@@ -19,26 +29,39 @@ impl KeyDir {
 }
 
 impl Indexer for KeyDir {
-    fn put(&self, key: String, meta_data: MetaData) -> bool {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
         let mut write_guard = self.hash_table.write();
         write_guard.insert(key, meta_data);
         true
     }
 
-    fn get(&self, key: &String) -> Option<MetaData> {
-        let table_read = self.hash_table.read();
-        for (k, v) in table_read.iter() {
-             println!("Key: {}, Value: {:?}", k, v);
-        }
-
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
         let read_guard = self.hash_table.read();
-        read_guard.get(key).copied()
+        Ok(read_guard.get(key).copied())
     }
 
-    fn delete(&self, key: &String) -> bool {
+    fn delete(&self, key: &[u8]) -> R<bool> {
         let mut write_guard = self.hash_table.write();
         let remove_res = write_guard.remove(key);
-        remove_res.is_some()
+        Ok(remove_res.is_some())
+    }
+
+    /// hash 表没有顺序，范围遍历 (包括前缀遍历) 也要扫描全部 key，过滤后再排序
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        let read_guard = self.hash_table.read();
+        let items = read_guard.iter().map(|(k, v)| (k.clone(), *v));
+        Box::new(SortedIterator::from_unsorted(items, options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.hash_table.read().len()
+    }
+
+    /// 槽位按 hash 表的容量计算，每个槽位还有 1 字节的控制位
+    fn memory_size(&self) -> usize {
+        let read_guard = self.hash_table.read();
+        let slot_size = mem::size_of::<(Vec<u8>, MetaData)>() + 1;
+        read_guard.capacity() * slot_size + read_guard.keys().map(Vec::capacity).sum::<usize>()
     }
 }
 
@@ -52,19 +75,19 @@ mod tests {
     fn test_btree_put() {
         let keydir = KeyDir::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let x = keydir.put("hello".to_string(), fake_meta_data);
-        assert_eq!(x, true);
+        let x = keydir.put("hello".as_bytes().to_vec(), fake_meta_data);
+        assert!(x);
     }
 
     #[test]
     fn test_btree_get() {
         let keydir = KeyDir::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let k = "hello".to_string();
+        let k = "hello".as_bytes().to_vec();
         let x = keydir.put(k, fake_meta_data);
-        assert_eq!(x, true);
-        let k = "hello".to_string();
-        let get_res = keydir.get(&k);
+        assert!(x);
+        let k = "hello".as_bytes().to_vec();
+        let get_res = keydir.get(&k).unwrap();
         assert_eq!(get_res.unwrap(), fake_meta_data);
     }
 
@@ -72,15 +95,28 @@ mod tests {
     fn test_btree_delete() {
         let keydir = KeyDir::new();
         let fake_meta_data = MetaData::new(0, 1, 2, 3);
-        let k = "hello".to_string();
+        let k = "hello".as_bytes().to_vec();
         let x = keydir.put(k, fake_meta_data);
-        assert_eq!(x, true);
-        let k = "hello".to_string();
-        let get_res = keydir.get(&k);
+        assert!(x);
+        let k = "hello".as_bytes().to_vec();
+        let get_res = keydir.get(&k).unwrap();
         assert_eq!(get_res.unwrap(), fake_meta_data);
-        let removed_data = keydir.delete(&k);
-        assert_eq!(removed_data, true);
-        let get_res = keydir.get(&k);
+        let removed_data = keydir.delete(&k).unwrap();
+        assert!(removed_data);
+        let get_res = keydir.get(&k).unwrap();
         assert_eq!(get_res, None);
     }
+
+    #[test]
+    fn test_keydir_iterator() {
+        let keydir = KeyDir::new();
+        for k in ["b", "d", "a", "c"] {
+            keydir.put(k.as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        }
+        let mut iter = keydir.iterator(&IteratorOptions::default());
+        for k in ["a", "b", "c", "d"] {
+            assert_eq!(iter.next().unwrap().unwrap().0, k.as_bytes());
+        }
+        assert!(iter.next().is_none());
+    }
 }
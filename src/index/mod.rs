@@ -1,27 +1,95 @@
+pub mod art;
+pub mod bptree;
 pub mod btree;
+pub mod compact;
+pub mod iterator;
 pub mod keydir;
+pub mod sharded;
+pub mod skiplist;
 
-use core::panic;
-
-use crate::{data::meta_data::MetaData, options::IndexType};
+use crate::data::file_stat::FileStat;
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::options::{IndexType, IteratorOptions, Options};
 
 // 内存中索引接口
 pub trait Indexer: Send + Sync {
     /// 内存索引新增一个 metadata，对于 hashtable 而言，k 就是用户存储的 k，value 在 disk 文件上的位置被封装成了 MetaData
-    fn put(&self, key: String, meta_data: MetaData) -> bool;
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool;
+
+    /// 根据 key 取出 metadata，metadata 根据不同的内存索引含义不同，hashtable（keydir）就是在文件中的位置的封装。
+    /// 读取磁盘上的索引失败时返回 Failed2ReadIndexFile，不能当作 key 不存在
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>>;
+
+    /// 根据 key 删除 metadata，返回 key 是否存在，写磁盘上的索引失败时返回 Failed2UpdateMemIndex
+    fn delete(&self, key: &[u8]) -> R<bool>;
+
+    /// 按 key 的字节序遍历 options 范围内的索引
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator>;
+
+    /// 索引中 key 的数量
+    fn key_num(&self) -> usize;
+
+    /// 索引占用的内存字节数，是根据容量估算的值
+    fn memory_size(&self) -> usize;
 
-    /// 根据 key 取出 metadata，metadata 根据不同的内存索引含义不同，hashtable（keydir）就是在文件中的位置的封装
-    fn get(&self, key: &String) -> Option<MetaData>;
+    /// 索引能保存的最大 key 字节数，写入之前检查，超过时不能写入 data file
+    fn max_key_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// 保存在磁盘上的索引上次正常关闭时记录的状态，内存索引返回 None
+    fn persisted_state(&self) -> Option<PersistedState> {
+        None
+    }
+
+    /// 清空保存在磁盘上的索引，之后通过扫描 data file 重建
+    fn reset(&self) -> R<()> {
+        Ok(())
+    }
 
-    /// 根据 key 删除 metadata
-    fn delete(&self, key: &String) -> bool;
+    /// 把索引写回磁盘并记录对应的状态，下次启动时状态一致就不需要重建索引
+    fn persist(&self, _state: &PersistedState) -> R<()> {
+        Ok(())
+    }
+}
+
+/// 索引迭代器
+pub trait IndexIterator: Send + Sync {
+    /// 回到第一个 key，逆序遍历时为最后一个 key
+    fn rewind(&mut self);
+
+    /// 定位到第一个大于等于 key 的位置，逆序遍历时为第一个小于等于 key 的位置
+    fn seek(&mut self, key: &[u8]);
+
+    /// 返回下一个 key 和 metadata，遍历结束时返回 None，读取磁盘上的索引失败时返回错误
+    fn next(&mut self) -> Option<R<(&[u8], &MetaData)>>;
 }
 
-pub fn new_indexer(index_type: IndexType) -> Box<dyn Indexer> {
-    match index_type {
+/// 索引保存到磁盘时对应的 data file 状态
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PersistedState {
+    /// 所有 data file 的 id 和大小的校验和
+    pub data_files_checksum: u32,
+
+    /// 最近一次 WriteBatch 使用的序列号
+    pub max_batch_seq: u64,
+
+    /// 每个 data file 的空间使用情况
+    pub file_stats: Vec<FileStat>,
+}
+
+pub fn new_indexer(opts: &Options) -> R<Box<dyn Indexer>> {
+    Ok(match opts.index_type {
         IndexType::BTree => Box::new(btree::BTree::new()),
         IndexType::Hash => Box::new(keydir::KeyDir::new()),
-        IndexType::SkipList => todo!(),
-        _ => panic!("index type not supported now!"),
-    }
+        IndexType::SkipList => Box::new(skiplist::SkipList::new()),
+        IndexType::Art => Box::new(art::Art::new()),
+        IndexType::CompactHash => Box::new(compact::CompactKeyDir::new()),
+        IndexType::ShardedHash => Box::new(sharded::ShardedKeyDir::new(opts.index_shards)),
+        IndexType::BPlusTree => Box::new(bptree::BPlusTree::open(
+            &opts.dir_path,
+            opts.index_cache_pages,
+        )?),
+    })
 }
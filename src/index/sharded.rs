@@ -0,0 +1,164 @@
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::index::iterator::SortedIterator;
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::mem;
+
+/// 按 key 的 hash 分成多个分片的 hash 索引，每个分片有独立的读写锁，
+/// 写入只锁住 key 所在的分片，不会阻塞其他分片上的读
+pub struct ShardedKeyDir {
+    shards: Vec<RwLock<HashMap<Vec<u8>, MetaData>>>,
+    hasher: RandomState,
+}
+
+impl ShardedKeyDir {
+    /// shard_num 为 0 时使用一个分片
+    pub fn new(shard_num: usize) -> Self {
+        Self {
+            shards: (0..shard_num.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn shard(&self, key: &[u8]) -> &RwLock<HashMap<Vec<u8>, MetaData>> {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[i]
+    }
+}
+
+impl Indexer for ShardedKeyDir {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+        self.shard(&key).write().insert(key, meta_data);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+        Ok(self.shard(key).read().get(key).copied())
+    }
+
+    fn delete(&self, key: &[u8]) -> R<bool> {
+        Ok(self.shard(key).write().remove(key).is_some())
+    }
+
+    /// 依次复制每个分片中的 key 再排序，不同分片不是同一时刻的状态
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        let mut items = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read();
+            items.extend(shard.iter().map(|(k, v)| (k.clone(), *v)));
+        }
+        Box::new(SortedIterator::from_unsorted(items, options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// 与 KeyDir 的估算方式相同，按每个分片的容量计算
+    fn memory_size(&self) -> usize {
+        let slot_size = mem::size_of::<(Vec<u8>, MetaData)>() + 1;
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.read();
+                shard.capacity() * slot_size + shard.keys().map(Vec::capacity).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sharded_keydir_put_get_delete() {
+        let keydir = ShardedKeyDir::new(4);
+        let fake_meta_data = MetaData::new(0, 1, 2, 3);
+        assert!(keydir.put("hello".as_bytes().to_vec(), fake_meta_data));
+        assert_eq!(
+            keydir.get("hello".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+
+        let new_meta_data = MetaData::new(1, 1, 2, 4);
+        assert!(keydir.put("hello".as_bytes().to_vec(), new_meta_data));
+        assert_eq!(
+            keydir.get("hello".as_bytes()).unwrap().unwrap(),
+            new_meta_data
+        );
+        assert_eq!(keydir.key_num(), 1);
+
+        assert!(keydir.delete("hello".as_bytes()).unwrap());
+        assert!(!keydir.delete("hello".as_bytes()).unwrap());
+        assert_eq!(keydir.get("hello".as_bytes()).unwrap(), None);
+        assert_eq!(keydir.key_num(), 0);
+
+        // 分片数为 0 时退化为一个分片
+        let keydir = ShardedKeyDir::new(0);
+        assert!(keydir.put("hello".as_bytes().to_vec(), fake_meta_data));
+        assert_eq!(
+            keydir.get("hello".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+    }
+
+    #[test]
+    fn test_sharded_keydir_iterator() {
+        let keydir = ShardedKeyDir::new(8);
+        for i in 0..100u32 {
+            let key = format!("key-{:03}", i).into_bytes();
+            keydir.put(key, MetaData::new(i, 1, 2, 3));
+        }
+        let options = IteratorOptions {
+            lower_bound: Some("key-010".as_bytes().to_vec()),
+            upper_bound: Some("key-020".as_bytes().to_vec()),
+            reverse: true,
+        };
+        let mut iter = keydir.iterator(&options);
+        for i in (10..20u32).rev() {
+            let (key, meta_data) = iter.next().unwrap().unwrap();
+            assert_eq!(key, format!("key-{:03}", i).as_bytes());
+            assert_eq!(meta_data.file_id, i);
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_sharded_keydir_writer_does_not_block_other_shards() {
+        let keydir = ShardedKeyDir::new(16);
+        let (blocked_key, free_key) = {
+            let blocked_key = "blocked".as_bytes().to_vec();
+            let free_key = (0..)
+                .map(|i| format!("free-{}", i).into_bytes())
+                .find(|key| !std::ptr::eq(keydir.shard(key), keydir.shard(&blocked_key)))
+                .unwrap();
+            (blocked_key, free_key)
+        };
+        keydir.put(free_key.clone(), MetaData::new(0, 1, 2, 3));
+
+        // 持有一个分片的写锁期间，其他分片上的 key 仍然可以读写
+        let write_guard = keydir.shard(&blocked_key).write();
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let meta_data = keydir.get(&free_key).unwrap();
+                keydir.put(free_key.clone(), MetaData::new(1, 1, 2, 3));
+                tx.send(meta_data).unwrap();
+            });
+            let meta_data = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(meta_data, Some(MetaData::new(0, 1, 2, 3)));
+            drop(write_guard);
+        });
+        assert_eq!(keydir.get(&free_key).unwrap().unwrap().file_id, 1);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::data::meta_data::MetaData;
+use crate::error::R;
+use crate::index::iterator::{CursorIterator, OrderedIndex};
+use crate::index::{IndexIterator, Indexer};
+use crate::options::IteratorOptions;
+use crossbeam_skiplist::SkipMap;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// 封装了 crossbeam 的并发跳表，读写都不需要加锁，并发读之间不会互相竞争
+pub struct SkipList {
+    skl: Arc<SkipMap<Vec<u8>, MetaData>>,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        Self {
+            skl: Arc::new(SkipMap::new()),
+        }
+    }
+}
+
+impl Indexer for SkipList {
+    fn put(&self, key: Vec<u8>, meta_data: MetaData) -> bool {
+        self.skl.insert(key, meta_data);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> R<Option<MetaData>> {
+        Ok(self.skl.get(key).map(|entry| *entry.value()))
+    }
+
+    fn delete(&self, key: &[u8]) -> R<bool> {
+        Ok(self.skl.remove(key).is_some())
+    }
+
+    /// 跳表本身有序，遍历时逐个定位范围内的 key，不复制整个范围
+    fn iterator(&self, options: &IteratorOptions) -> Box<dyn IndexIterator> {
+        Box::new(CursorIterator::new(self.skl.clone(), options))
+    }
+
+    fn key_num(&self) -> usize {
+        self.skl.len()
+    }
+
+    /// 不包括跳表节点中指针塔的开销
+    fn memory_size(&self) -> usize {
+        self.skl.len() * mem::size_of::<(Vec<u8>, MetaData)>()
+            + self
+                .skl
+                .iter()
+                .map(|entry| entry.key().capacity())
+                .sum::<usize>()
+    }
+}
+
+impl OrderedIndex for SkipMap<Vec<u8>, MetaData> {
+    fn first_in(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Option<(Vec<u8>, MetaData)> {
+        let mut range = self.range::<[u8], _>((lower, upper));
+        let entry = if reverse {
+            range.next_back()?
+        } else {
+            range.next()?
+        };
+        Some((entry.key().clone(), *entry.value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_skiplist_put_get_delete() {
+        let skl = SkipList::new();
+        let fake_meta_data = MetaData::new(0, 1, 2, 3);
+        assert!(skl.put("hello".as_bytes().to_vec(), fake_meta_data));
+        assert_eq!(
+            skl.get("hello".as_bytes()).unwrap().unwrap(),
+            fake_meta_data
+        );
+
+        let new_meta_data = MetaData::new(1, 1, 2, 4);
+        assert!(skl.put("hello".as_bytes().to_vec(), new_meta_data));
+        assert_eq!(skl.get("hello".as_bytes()).unwrap().unwrap(), new_meta_data);
+
+        assert!(skl.delete("hello".as_bytes()).unwrap());
+        assert!(!skl.delete("hello".as_bytes()).unwrap());
+        assert_eq!(skl.get("hello".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_skiplist_iterator() {
+        let skl = SkipList::new();
+        for k in ["b", "d", "a", "c"] {
+            skl.put(k.as_bytes().to_vec(), MetaData::new(0, 1, 2, 3));
+        }
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            upper_bound: Some("d".as_bytes().to_vec()),
+            reverse: true,
+        };
+        let mut iter = skl.iterator(&options);
+        assert_eq!(iter.next().unwrap().unwrap().0, "c".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "b".as_bytes());
+        assert!(iter.next().is_none());
+
+        let mut iter = skl.iterator(&IteratorOptions::default());
+        iter.seek("c".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "c".as_bytes());
+        assert_eq!(iter.next().unwrap().unwrap().0, "d".as_bytes());
+        assert!(iter.next().is_none());
+
+        let options = IteratorOptions {
+            lower_bound: Some("d".as_bytes().to_vec()),
+            upper_bound: Some("a".as_bytes().to_vec()),
+            reverse: false,
+        };
+        assert!(skl.iterator(&options).next().is_none());
+    }
+
+    #[test]
+    fn test_skiplist_concurrent() {
+        let skl = SkipList::new();
+        thread::scope(|s| {
+            for t in 0..4u32 {
+                let skl = &skl;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("key-{}-{}", t, i).into_bytes();
+                        skl.put(key.clone(), MetaData::new(t, 1, i, 3));
+                        assert_eq!(skl.get(&key).unwrap().unwrap().file_id, t);
+                    }
+                });
+            }
+        });
+        let mut iter = skl.iterator(&IteratorOptions::default());
+        let mut count = 0;
+        while iter.next().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 400);
+    }
+}
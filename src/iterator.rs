@@ -0,0 +1,188 @@
+use crate::db::Engine;
+use crate::error::E::{self, Nil};
+use crate::error::R;
+use crate::index::iterator::SortedIterator;
+use crate::index::IndexIterator;
+use crate::options::IteratorOptions;
+
+/// 根据 key 读取 value
+type ReadFn<'a> = Box<dyn Fn(&[u8]) -> R<Vec<u8>> + 'a>;
+
+/// 按 key 的顺序遍历 (key, value)。value 在遍历到时才从 data file 中读取，遍历期间被删除的 key 会被跳过。
+/// 有序索引在遍历时逐个定位 key，可能遍历到之后写入的 key；其他索引在创建时复制范围内的 key
+pub struct Iter<'a> {
+    index_iter: Box<dyn IndexIterator>,
+    read: ReadFn<'a>,
+
+    /// 创建迭代器时遇到的错误，第一次 next 时返回
+    error: Option<E>,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(
+        index_iter: Box<dyn IndexIterator>,
+        read: impl Fn(&[u8]) -> R<Vec<u8>> + 'a,
+    ) -> Self {
+        Self {
+            index_iter,
+            read: Box::new(read),
+            error: None,
+        }
+    }
+
+    /// 只返回一个错误的迭代器
+    pub(crate) fn failed(error: E) -> Self {
+        Self {
+            index_iter: Box::new(SortedIterator::new(Vec::new(), false)),
+            read: Box::new(|_| Err(Nil)),
+            error: Some(error),
+        }
+    }
+
+    /// 回到第一个 key，逆序遍历时为最后一个 key
+    pub fn rewind(&mut self) {
+        self.index_iter.rewind();
+    }
+
+    /// 定位到第一个大于等于 key 的位置，逆序遍历时为第一个小于等于 key 的位置
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.index_iter.seek(key.as_ref());
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = R<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let key = match self.index_iter.next()? {
+                Ok((key, _)) => key.to_vec(),
+                Err(e) => return Some(Err(e)),
+            };
+            match (self.read)(&key) {
+                Ok(value) => return Some(Ok((key, value))),
+                Err(Nil) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// 按 key 的顺序遍历 options 范围内的数据，例如 user:1000..user:2000
+    pub fn iter(&self, options: IteratorOptions) -> Iter<'_> {
+        let index_iter = self.mem_index.read().iterator(&options);
+        Iter::new(index_iter, move |key| self.read(key))
+    }
+
+    /// 按 key 的顺序遍历以 prefix 开头的数据，例如 tenant/entity/ 下的所有 key。
+    /// BTree 索引只需要定位到 prefix 所在的范围；hash 索引没有顺序，需要扫描全部 key 再排序
+    pub fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter<'_> {
+        self.iter(IteratorOptions::prefix(prefix.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{get_default_options, index_types, open_engine};
+
+    fn keys(iter: Iter) -> Vec<String> {
+        iter.map(|item| String::from_utf8(item.unwrap().0).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_iter() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.index_type = index_type;
+            let engine = open_engine(options);
+            for i in (0..20).rev() {
+                engine
+                    .put(format!("user:{:04}", 1000 + i * 100), vec![i as u8 + 1])
+                    .unwrap();
+            }
+            engine.put("order:1", vec![1]).unwrap();
+
+            let items: Vec<(Vec<u8>, Vec<u8>)> = engine
+                .iter(IteratorOptions::default())
+                .map(|item| item.unwrap())
+                .collect();
+            assert_eq!(items.len(), 21);
+            assert_eq!(items[0].0, "order:1".as_bytes());
+            assert_eq!(items[1], ("user:1000".as_bytes().to_vec(), vec![1]));
+            assert_eq!(items[20], ("user:2900".as_bytes().to_vec(), vec![20]));
+
+            let options = IteratorOptions {
+                lower_bound: Some("user:1000".as_bytes().to_vec()),
+                upper_bound: Some("user:2000".as_bytes().to_vec()),
+                reverse: false,
+            };
+            let range = keys(engine.iter(options.clone()));
+            assert_eq!(range.len(), 10);
+            assert_eq!(range[0], "user:1000");
+            assert_eq!(range[9], "user:1900");
+
+            let mut iter = engine.iter(IteratorOptions {
+                reverse: true,
+                ..options
+            });
+            assert_eq!(iter.next().unwrap().unwrap().0, "user:1900".as_bytes());
+            iter.seek("user:1450");
+            assert_eq!(iter.next().unwrap().unwrap().0, "user:1400".as_bytes());
+            iter.rewind();
+            assert_eq!(iter.next().unwrap().unwrap().0, "user:1900".as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        for index_type in index_types() {
+            let mut options = get_default_options();
+            options.index_type = index_type;
+            let engine = open_engine(options);
+            for tenant in ["a", "b", "ab"] {
+                for id in 0..3 {
+                    engine
+                        .put(format!("{}/user/{}", tenant, id), vec![1])
+                        .unwrap();
+                    engine
+                        .put(format!("{}/order/{}", tenant, id), vec![1])
+                        .unwrap();
+                }
+            }
+
+            let tenant_a = keys(engine.scan_prefix("a/"));
+            assert_eq!(tenant_a.len(), 6);
+            assert!(tenant_a.iter().all(|k| k.starts_with("a/")));
+            assert_eq!(tenant_a[0], "a/order/0");
+            assert_eq!(
+                keys(engine.scan_prefix("ab/user/")),
+                vec!["ab/user/0", "ab/user/1", "ab/user/2"]
+            );
+            assert!(keys(engine.scan_prefix("c/")).is_empty());
+            assert_eq!(keys(engine.scan_prefix("")).len(), 18);
+        }
+    }
+
+    #[test]
+    fn test_iter_skips_deleted_keys() {
+        let engine = open_engine(get_default_options());
+        for k in ["a", "b", "c"] {
+            engine.put(k, k.as_bytes().to_vec()).unwrap();
+        }
+        let mut iter = engine.iter(IteratorOptions::default());
+        assert_eq!(iter.next().unwrap().unwrap().0, "a".as_bytes());
+        engine.delete("b").unwrap();
+        engine.put("c", "new".as_bytes().to_vec()).unwrap();
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            ("c".as_bytes().to_vec(), "new".as_bytes().to_vec())
+        );
+        assert!(iter.next().is_none());
+    }
+}
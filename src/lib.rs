@@ -1,6 +1,13 @@
-mod data;
-mod db;
-mod error;
-mod fio;
-mod index;
-mod options;
+pub mod batch;
+pub mod checkpoint;
+pub mod data;
+pub mod db;
+pub mod error;
+pub mod fio;
+pub mod index;
+pub mod iterator;
+pub mod merge;
+pub mod options;
+pub mod repair;
+pub mod snapshot;
+pub mod transaction;
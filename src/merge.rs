@@ -0,0 +1,798 @@
+use crate::checkpoint::remove_checkpoint_file;
+use crate::data::datafile::{DataFile, DataFileType, DATA_FILE_HEADER_SIZE, DATA_FILE_SUFFIX};
+use crate::data::file_stat::{add_dead_bytes, add_total_bytes};
+use crate::data::hint_file::{HintFile, HINT_FILE_SUFFIX};
+use crate::data::meta_data::MetaData;
+use crate::db::Engine;
+use crate::error::E::{Failed2CreateDataDir, Failed2Merge, Failed2UpdateMemIndex, MergeInProgress};
+use crate::error::R;
+use log::{error, info, warn};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+
+/// merge 的结果先写到数据目录下的这个子目录中
+const MERGE_DIR_NAME: &str = "merge";
+
+/// merged data file 和 hint file 全部写完并 sync 后才会创建该文件，内容为 "non_merge_file_id merged_file_count"
+const MERGE_FINISHED_FILE_NAME: &str = "merge-finished";
+
+/// 一次 merge 写入 merge 目录的结果
+struct MergeOutput {
+    /// id 小于它的 data file 都参与了 merge
+    non_merge_file_id: u32,
+
+    /// merged data file 的 id 为 0..merged_file_count
+    merged_file_count: u32,
+
+    /// (key, 旧的 MetaData, merged data file 中的 MetaData)
+    rewritten: Vec<(Vec<u8>, MetaData, MetaData)>,
+
+    /// 已经过期、没有重写的 entry 的 (key, 旧的 MetaData)
+    expired: Vec<(Vec<u8>, MetaData)>,
+}
+
+/// 重写 id 小于 non_merge_file_id 的 older file 的结果
+enum MergeAttempt {
+    Finished(MergeOutput),
+
+    /// 重写到 file_id 时 merged data file 需要的 id 超出了参与 merge 的区间
+    OutOfFileIds {
+        file_id: u32,
+    },
+}
+
+impl Engine {
+    /// 将所有 older file 中仍然存活的 entry 重写到新的 merged data file 中，并生成对应的 hint file。
+    /// 过期版本和 tombstone 都会被丢弃，active file 不参与 merge。
+    ///
+    /// merge 过程分为两步:
+    /// 1. 在 merge 目录中写 merged data file 和 hint file，全部 sync 后写入 merge-finished 标记
+    /// 2. 持有 older files 和 index 的写锁，把 merged data file 移动到数据目录，删除旧文件，更新 index
+    ///
+    /// 第 2 步中途崩溃时，下次 Engine::open 根据 merge-finished 标记继续完成，没有标记则丢弃 merge 目录
+    pub fn merge(&self) -> R<()> {
+        let _merge_guard = match self.merge_lock.try_lock() {
+            Some(guard) => guard,
+            None => return Err(MergeInProgress),
+        };
+
+        let output = match self.write_merge_files()? {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        self.install_merge_files(output)
+    }
+
+    /// 根据 Options 中的阈值判断是否需要自动 merge
+    pub(crate) fn need_merge(&self) -> bool {
+        let active_file_id = self.active_file.read().file_id();
+        let (older_file_bytes, dead_bytes) = self
+            .file_stats
+            .read()
+            .values()
+            .filter(|file_stat| file_stat.file_id != active_file_id)
+            .fold((0, 0), |(total, dead), file_stat| {
+                (total + file_stat.total_bytes, dead + file_stat.dead_bytes)
+            });
+        if older_file_bytes == 0 || older_file_bytes < self.options.merge_min_older_file_bytes {
+            return false;
+        }
+
+        let ratio = dead_bytes as f64 / older_file_bytes as f64;
+        ratio >= self.options.merge_ratio_threshold as f64
+    }
+
+    /// merge 的第一步，没有 older file 时返回 None
+    fn write_merge_files(&self) -> R<Option<MergeOutput>> {
+        // 1. 确定参与 merge 的文件, id 小于当前 active file 的 older file 都参与
+        // 存活的快照引用的文件以及之后的文件都不参与，只合并前缀保证旧版本不会覆盖新版本
+        let mut non_merge_file_id = self.active_file.read().file_id();
+        if let Some(file_id) = self.snapshots.read().min_referenced_file_id() {
+            non_merge_file_id = non_merge_file_id.min(file_id);
+        }
+
+        // merged data file 比原来的文件多时 (例如旧格式的 entry 按当前格式重写后变长，或者调小了 file_threshold)
+        // 复用的 file id 不够用，缩小 merge 的范围重试，其余的 entry 留在原来的文件中
+        loop {
+            match self.write_merge_files_below(non_merge_file_id)? {
+                Some(MergeAttempt::Finished(output)) => return Ok(Some(output)),
+                Some(MergeAttempt::OutOfFileIds { file_id }) => {
+                    warn!(
+                        "merged data files exceed the file id range below {}, merge files below {} only",
+                        non_merge_file_id, file_id
+                    );
+                    non_merge_file_id = file_id;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// 把 id 小于 non_merge_file_id 的 older file 重写到 merge 目录中，没有这样的文件时返回 None
+    fn write_merge_files_below(&self, non_merge_file_id: u32) -> R<Option<MergeAttempt>> {
+        let dir_path = self.options.dir_path.clone();
+        let mut merge_file_ids: Vec<u32> = self
+            .older_files
+            .read()
+            .keys()
+            .copied()
+            .filter(|file_id| *file_id < non_merge_file_id)
+            .collect();
+        if merge_file_ids.is_empty() {
+            return Ok(None);
+        }
+        merge_file_ids.sort();
+
+        // 2. 清理上次残留的 merge 目录
+        let merge_dir = get_merge_dir(&dir_path);
+        if merge_dir.is_dir() {
+            remove_dir(&merge_dir)?;
+        }
+        if let Err(e) = fs::create_dir_all(&merge_dir) {
+            error!("failed to create merge dir, {}", e);
+            return Err(Failed2CreateDataDir);
+        }
+        let merge_dir_path = merge_dir.display().to_string();
+
+        // 3. 依次重写每个 older file 中存活的 entry, 存活指 index 中的 MetaData 仍然指向该 entry,
+        // 或者 merge 期间该 entry 被覆盖，但是快照仍然引用它
+        let mut merged_file_id = 0;
+        let mut merged_file = DataFile::new(merge_dir_path.clone(), merged_file_id)?;
+        let mut hint_file = HintFile::new(&merge_dir_path, merged_file_id)?;
+        let mut rewritten = Vec::new();
+        let mut expired = Vec::new();
+        for file_id in merge_file_ids {
+            let older_files = self.older_files.read();
+            let entries = match older_files.get(&file_id) {
+                Some(older_file) => older_file.get_all_entries_with_metadata()?,
+                None => continue,
+            };
+            drop(older_files);
+
+            for entry_with_metadata in entries {
+                let mut entry = entry_with_metadata.entry;
+                let old_meta_data = entry_with_metadata.meta_data;
+                if entry.is_tombstone() {
+                    continue;
+                }
+                let mem_index = self.mem_index.read();
+                if mem_index.get(entry.k())? != Some(old_meta_data)
+                    && !self.snapshots.read().references(entry.k(), old_meta_data)
+                {
+                    continue;
+                }
+                drop(mem_index);
+
+                // 过期的 entry 不再重写
+                if entry.is_expired() {
+                    expired.push((entry.into_k(), old_meta_data));
+                    continue;
+                }
+
+                // index 中的 entry 都已经提交，merged file 中不再需要 batch 的提交标记
+                if entry.batch_seq() != 0 {
+                    entry.set_batch_seq(0);
+                }
+                let data = entry.encode();
+                if merged_file.next_write_begin_pos() > DATA_FILE_HEADER_SIZE
+                    && merged_file.next_write_begin_pos() + data.len() > self.options.file_threshold
+                {
+                    merged_file.sync()?;
+                    hint_file.sync()?;
+                    merged_file_id += 1;
+                    // merged data file 复用参与 merge 的 file id 区间, 不能与没有参与 merge 的文件冲突
+                    if merged_file_id >= non_merge_file_id {
+                        remove_dir(&merge_dir)?;
+                        return Ok(Some(MergeAttempt::OutOfFileIds { file_id }));
+                    }
+                    merged_file = DataFile::new(merge_dir_path.clone(), merged_file_id)?;
+                    hint_file = HintFile::new(&merge_dir_path, merged_file_id)?;
+                }
+
+                let write_begin_pos = merged_file.next_write_begin_pos();
+                merged_file.append(data)?;
+                let new_meta_data = MetaData::new(
+                    merged_file_id,
+                    entry.get_self_size(),
+                    write_begin_pos,
+                    entry.tstamp(),
+                );
+                hint_file.write_hint(entry.k().to_vec(), &new_meta_data, entry.expire_at())?;
+                rewritten.push((entry.k().to_vec(), old_meta_data, new_meta_data));
+            }
+        }
+        merged_file.sync()?;
+        hint_file.sync()?;
+
+        // 4. 所有结果都已落盘，写入 merge-finished 标记
+        let merged_file_count = merged_file_id + 1;
+        write_merge_finished_file(&merge_dir, non_merge_file_id, merged_file_count)?;
+        Ok(Some(MergeAttempt::Finished(MergeOutput {
+            non_merge_file_id,
+            merged_file_count,
+            rewritten,
+            expired,
+        })))
+    }
+
+    /// merge 的第二步，替换 older file 并更新 index
+    fn install_merge_files(&self, output: MergeOutput) -> R<()> {
+        let dir_path = self.options.dir_path.clone();
+
+        // 加锁顺序与写入一致: active file -> older files -> index -> snapshots -> file stats
+        // 写入只持有 index 的读锁，用 active file 的可升级读锁排除并发的写入
+        // 检查点锁保证替换 older file 之后不会再写入旧的检查点
+        let _checkpoint_guard = self.checkpoint_lock.lock();
+        let _active_file = self.active_file.upgradable_read();
+        let mut older_files = self.older_files.write();
+        let mem_index = self.mem_index.read();
+        let snapshots = self.snapshots.read();
+        let mut file_stats = self.file_stats.write();
+
+        // 1. 移动 merged data file 和 hint file，删除旧文件
+        recover_merge(&dir_path)?;
+
+        // 2. 用 merged data file 替换参与 merge 的 older file
+        older_files.retain(|file_id, _| *file_id >= output.non_merge_file_id);
+        file_stats.retain(|file_id, _| *file_id >= output.non_merge_file_id);
+        for file_id in 0..output.merged_file_count {
+            let full_path = DataFile::get_file_full_path(dir_path.clone(), file_id.to_string());
+            let data_file = DataFile::create_from_full_path(
+                full_path.display().to_string(),
+                DataFileType::OLD,
+                self.options.io_type,
+            )?;
+            add_total_bytes(&mut file_stats, file_id, data_file.next_write_begin_pos());
+            older_files.insert(file_id, data_file);
+        }
+
+        // 3. merge 期间被更新或者删除的 key 保持不变，它们在 merged data file 中的副本是可回收的
+        // 快照仍然引用的版本指向 merged data file 中的副本
+        for (key, old_meta_data, new_meta_data) in output.rewritten {
+            snapshots.relocate(&key, old_meta_data, new_meta_data);
+            if mem_index.get(&key)? == Some(old_meta_data) {
+                if !mem_index.put(key, new_meta_data) {
+                    return Err(Failed2UpdateMemIndex);
+                }
+            } else {
+                add_dead_bytes(
+                    &mut file_stats,
+                    new_meta_data.file_id,
+                    new_meta_data.entry_sz,
+                );
+            }
+        }
+
+        // 4. merge 期间没有被更新的过期 key 从 index 中删除，快照中引用的过期版本视为不存在
+        for (key, old_meta_data) in output.expired {
+            snapshots.forget(&key, old_meta_data);
+            if mem_index.get(&key)? == Some(old_meta_data) {
+                mem_index.delete(&key)?;
+            }
+        }
+        info!(
+            "merge finished, data files below {} are merged into {} files",
+            output.non_merge_file_id, output.merged_file_count
+        );
+        Ok(())
+    }
+}
+
+/// 后台 merge 线程，按 Options::merge_interval 定期检查是否需要 merge
+pub(crate) struct MergeWorker {
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MergeWorker {
+    /// 没有设置 merge_interval 时不启动线程
+    pub(crate) fn start(engine: &Engine) -> Self {
+        let interval = match engine.options.merge_interval {
+            Some(interval) => interval,
+            None => {
+                return Self {
+                    stop_sender: None,
+                    handle: None,
+                }
+            }
+        };
+
+        let engine = engine.share();
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("bitcask-merge".to_string())
+            .spawn(move || {
+                // 收到停止信号或者 sender 已经被 drop 时退出
+                while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    if !engine.need_merge() {
+                        continue;
+                    }
+                    match engine.merge() {
+                        Ok(_) | Err(MergeInProgress) => {}
+                        Err(e) => warn!("background merge failed, {}", e),
+                    }
+                }
+            });
+        match handle {
+            Ok(handle) => Self {
+                stop_sender: Some(stop_sender),
+                handle: Some(handle),
+            },
+            Err(e) => {
+                error!("failed to start background merge, {}", e);
+                Self {
+                    stop_sender: None,
+                    handle: None,
+                }
+            }
+        }
+    }
+
+    /// 停止后台线程并等待正在进行的 merge 结束
+    pub(crate) fn stop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 完成 merge 目录中已经写完的 merge，没有 merge-finished 标记时说明 merge 没有写完，直接丢弃。
+/// 每一步都是可重入的，中途崩溃后再次执行可以得到相同的结果
+pub(crate) fn recover_merge(dir_path: &str) -> R<()> {
+    let merge_dir = get_merge_dir(dir_path);
+    if !merge_dir.is_dir() {
+        return Ok(());
+    }
+
+    let (non_merge_file_id, merged_file_count) = match read_merge_finished_file(&merge_dir) {
+        Some(finished) => finished,
+        None => return remove_dir(&merge_dir),
+    };
+
+    // 1. 用 merged data file 覆盖同 id 的文件，先删除旧的 hint file，避免它与新的 data file 配对
+    // 检查点指向被替换的文件，也要先删除
+    remove_checkpoint_file(dir_path)?;
+    let dir = Path::new(dir_path);
+    for file_id in 0..merged_file_count {
+        let data_file_name = file_id.to_string() + DATA_FILE_SUFFIX;
+        let hint_file_name = file_id.to_string() + HINT_FILE_SUFFIX;
+        let merged_data_file = merge_dir.join(&data_file_name);
+        if merged_data_file.is_file() {
+            remove_file(&dir.join(&hint_file_name))?;
+            rename_file(&merged_data_file, &dir.join(&data_file_name))?;
+        }
+        let merged_hint_file = merge_dir.join(&hint_file_name);
+        if merged_hint_file.is_file() {
+            rename_file(&merged_hint_file, &dir.join(&hint_file_name))?;
+        }
+    }
+
+    // 2. 删除其余参与了 merge 的 data file 和 hint file
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            error!("failed to read data dir, {}", e);
+            return Err(Failed2Merge);
+        }
+    };
+    for entry in read_dir.flatten() {
+        let file_name = entry.file_name().into_string().unwrap_or_default();
+        let file_id = file_name
+            .strip_suffix(DATA_FILE_SUFFIX)
+            .or_else(|| file_name.strip_suffix(HINT_FILE_SUFFIX))
+            .and_then(|file_id| file_id.parse::<u32>().ok());
+        if let Some(file_id) = file_id {
+            if file_id >= merged_file_count && file_id < non_merge_file_id {
+                remove_file(&entry.path())?;
+            }
+        }
+    }
+    sync_dir(dir);
+
+    // 3. 最后删除 merge 目录，其中包含 merge-finished 标记
+    remove_dir(&merge_dir)
+}
+
+fn get_merge_dir(dir_path: &str) -> PathBuf {
+    Path::new(dir_path).join(MERGE_DIR_NAME)
+}
+
+fn write_merge_finished_file(
+    merge_dir: &Path,
+    non_merge_file_id: u32,
+    merged_file_count: u32,
+) -> R<()> {
+    let content = format!("{} {}", non_merge_file_id, merged_file_count);
+    let res = File::create(merge_dir.join(MERGE_FINISHED_FILE_NAME)).and_then(|mut file| {
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+    });
+    if let Err(e) = res {
+        error!("failed to write merge finished file, {}", e);
+        return Err(Failed2Merge);
+    }
+    sync_dir(merge_dir);
+    Ok(())
+}
+
+fn read_merge_finished_file(merge_dir: &Path) -> Option<(u32, u32)> {
+    let content = fs::read_to_string(merge_dir.join(MERGE_FINISHED_FILE_NAME)).ok()?;
+    let mut parts = content.split_whitespace();
+    let non_merge_file_id = parts.next()?.parse::<u32>().ok()?;
+    let merged_file_count = parts.next()?.parse::<u32>().ok()?;
+    Some((non_merge_file_id, merged_file_count))
+}
+
+fn rename_file(from: &Path, to: &Path) -> R<()> {
+    if let Err(e) = fs::rename(from, to) {
+        error!(
+            "failed to move {} to {}, {}",
+            from.display(),
+            to.display(),
+            e
+        );
+        return Err(Failed2Merge);
+    }
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> R<()> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            error!("failed to remove {}, {}", path.display(), e);
+            Err(Failed2Merge)
+        }
+    }
+}
+
+fn remove_dir(path: &Path) -> R<()> {
+    if let Err(e) = fs::remove_dir_all(path) {
+        error!("failed to remove {}, {}", path.display(), e);
+        return Err(Failed2Merge);
+    }
+    Ok(())
+}
+
+/// 持久化目录中的 rename 和 delete
+fn sync_dir(path: &Path) {
+    if let Ok(dir) = File::open(path) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{get_default_options, open_engine};
+
+    fn dir_size(dir_path: &str) -> u64 {
+        fs::read_dir(dir_path)
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn test_merge_without_older_files() {
+        let engine = open_engine(get_default_options());
+        engine.merge().unwrap();
+        engine
+            .put("hello", "world".to_string().into_bytes())
+            .unwrap();
+        engine.merge().unwrap();
+        assert_eq!(engine.read("hello").unwrap(), "world".as_bytes());
+    }
+
+    #[test]
+    fn test_merge_reclaims_dead_entries() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for round in 0..5 {
+            for i in 0..20 {
+                engine
+                    .put(
+                        format!("key-{}", i),
+                        format!("value-{}-{}", i, round).into_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+        for i in 0..10 {
+            engine.delete(format!("key-{}", i)).unwrap();
+        }
+        let size_before = dir_size(&options.dir_path);
+        let older_files_before = engine.older_files.read().len();
+
+        engine.merge().unwrap();
+
+        assert!(dir_size(&options.dir_path) < size_before);
+        assert!(engine.older_files.read().len() < older_files_before);
+        assert!(!get_merge_dir(&options.dir_path).exists());
+        for i in 0..10 {
+            assert!(engine.read(format!("key-{}", i)).is_err());
+        }
+        for i in 10..20 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}-4", i).into_bytes());
+        }
+
+        // merge 后继续写入，重启后数据一致
+        engine.put("key-0", "new".to_string().into_bytes()).unwrap();
+        drop(engine);
+
+        let engine = open_engine(options);
+        assert_eq!(engine.read("key-0").unwrap(), "new".as_bytes());
+        for i in 1..10 {
+            assert!(engine.read(format!("key-{}", i)).is_err());
+        }
+        for i in 10..20 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}-4", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_merge_out_of_file_ids() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        for i in 0..10 {
+            engine
+                .put(format!("dead-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        for i in 0..10 {
+            engine.delete(format!("dead-{}", i)).unwrap();
+        }
+        drop(engine);
+
+        // 调大 file_threshold 写出一个大文件，再调小，重写这个文件需要的 file id 比它之后的 id 还多
+        options.file_threshold = 4096;
+        let engine = open_engine(options.clone());
+        let large_file_id = engine.active_file.read().file_id();
+        for i in 0..60 {
+            engine
+                .put(format!("key-{:02}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert_eq!(engine.active_file.read().file_id(), large_file_id);
+        drop(engine);
+        options.file_threshold = 256;
+        let engine = open_engine(options.clone());
+        engine.put("last", "1".as_bytes().to_vec()).unwrap();
+        assert_eq!(engine.active_file.read().file_id(), large_file_id + 1);
+
+        // 只合并大文件之前的文件，大文件保持不变
+        let large_file_len = engine.older_files.read()[&large_file_id].next_write_begin_pos();
+        let older_files_before = engine.older_files.read().len();
+        engine.merge().unwrap();
+        assert!(engine.older_files.read().len() < older_files_before);
+        assert_eq!(
+            engine.older_files.read()[&large_file_id].next_write_begin_pos(),
+            large_file_len
+        );
+        let check = |engine: &Engine| {
+            for i in 0..10 {
+                assert!(engine.read(format!("dead-{}", i)).is_err());
+            }
+            for i in 0..60 {
+                assert_eq!(
+                    engine.read(format!("key-{:02}", i)).unwrap(),
+                    format!("value-{}", i).into_bytes()
+                );
+            }
+            assert_eq!(engine.read("last").unwrap(), "1".as_bytes());
+        };
+        check(&engine);
+        drop(engine);
+        check(&open_engine(options));
+    }
+
+    #[test]
+    fn test_merge_twice() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        for round in 0..2 {
+            for i in 0..30 {
+                engine
+                    .put(
+                        format!("key-{}", i),
+                        format!("value-{}", round).into_bytes(),
+                    )
+                    .unwrap();
+            }
+            engine.merge().unwrap();
+        }
+        drop(engine);
+
+        let engine = open_engine(options);
+        for i in 0..30 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                "value-1".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_recover_finished_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        for round in 0..3 {
+            for i in 0..20 {
+                engine
+                    .put(
+                        format!("key-{}", i),
+                        format!("value-{}", round).into_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        // 写完 merge 目录后崩溃，下次启动时完成 merge
+        let output = engine.write_merge_files().unwrap().unwrap();
+        drop(engine);
+        assert!(get_merge_dir(&options.dir_path).is_dir());
+
+        let engine = open_engine(options.clone());
+        assert!(!get_merge_dir(&options.dir_path).exists());
+        assert_eq!(
+            engine.older_files.read().len() as u32,
+            output.merged_file_count
+        );
+        for file_id in 0..output.merged_file_count {
+            assert!(HintFile::exists(&options.dir_path, file_id));
+        }
+        for i in 0..20 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                "value-2".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_discard_unfinished_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+
+        // 没有 merge-finished 标记的 merge 目录会被丢弃
+        engine.write_merge_files().unwrap().unwrap();
+        fs::remove_file(get_merge_dir(&options.dir_path).join(MERGE_FINISHED_FILE_NAME)).unwrap();
+        drop(engine);
+
+        let engine = open_engine(options.clone());
+        assert!(!get_merge_dir(&options.dir_path).exists());
+        for i in 0..20 {
+            let v = engine.read(format!("key-{}", i)).unwrap();
+            assert_eq!(v, format!("value-{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_need_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        options.merge_ratio_threshold = 0.4;
+        let engine = open_engine(options.clone());
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(!engine.need_merge());
+
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(engine.need_merge());
+
+        drop(engine);
+        let engine = open_engine(options.clone());
+        assert!(engine.need_merge());
+
+        engine.merge().unwrap();
+        assert!(!engine.need_merge());
+        drop(engine);
+
+        // older file 总大小没有达到阈值
+        options.merge_min_older_file_bytes = 1024 * 1024;
+        let engine = open_engine(options);
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        assert!(!engine.need_merge());
+    }
+
+    #[test]
+    fn test_background_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        options.merge_ratio_threshold = 0.3;
+        options.merge_interval = Some(std::time::Duration::from_millis(10));
+        let engine = open_engine(options);
+        for round in 0..5 {
+            for i in 0..20 {
+                engine
+                    .put(
+                        format!("key-{}", i),
+                        format!("value-{}", round).into_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        let mut merged = false;
+        for _ in 0..500 {
+            if !engine.need_merge() {
+                merged = true;
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(merged);
+        for i in 0..20 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                "value-4".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_on_close() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        options.merge_on_close = true;
+        let engine = open_engine(options.clone());
+        for round in 0..5 {
+            for i in 0..20 {
+                engine
+                    .put(
+                        format!("key-{}", i),
+                        format!("value-{}", round).into_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+        let size_before = dir_size(&options.dir_path);
+        drop(engine);
+        assert!(dir_size(&options.dir_path) < size_before);
+
+        let engine = open_engine(options);
+        for i in 0..20 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                "value-4".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_in_progress() {
+        let engine = open_engine(get_default_options());
+        let _merge_guard = engine.merge_lock.lock();
+        assert!(matches!(engine.merge(), Err(MergeInProgress)));
+    }
+}
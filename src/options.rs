@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(Debug, Clone)]
 pub struct Options {
     /// 数据库目录路径
@@ -11,11 +13,161 @@ pub struct Options {
 
     /// 索引类型
     pub index_type: IndexType,
+
+    /// older file 以及启动时构建索引使用的 IO 类型，active file 始终使用标准文件 IO
+    pub io_type: IOType,
+
+    /// 可回收的字节数占 older file 总大小的比例达到该值时才会自动 merge, 取值 [0, 1]
+    pub merge_ratio_threshold: f32,
+
+    /// older file 总大小达到该值时才会自动 merge, 字节为单位
+    pub merge_min_older_file_bytes: usize,
+
+    /// 后台检查是否需要 merge 的间隔，None 表示不启动后台 merge
+    pub merge_interval: Option<Duration>,
+
+    /// 关闭时是否 merge
+    pub merge_on_close: bool,
+
+    /// 后台写入索引检查点的间隔，None 表示不启动后台检查点
+    pub checkpoint_interval: Option<Duration>,
+
+    /// 关闭时是否写入索引检查点，下次启动时只需要重放检查点之后写入的数据
+    pub checkpoint_on_close: bool,
+
+    /// B+ 树索引在内存中缓存的页数，每页 4KB
+    pub index_cache_pages: usize,
+
+    /// 启动时并发扫描 older file 构建索引的线程数，为 0 时使用 1 个线程
+    pub index_rebuild_threads: usize,
+
+    /// 分片 hash 索引的分片数，为 0 时使用默认值 16
+    pub index_shards: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            dir_path: String::new(),
+            file_threshold: 200 * 1024,
+            syn_after_each_write: false,
+            index_type: IndexType::Hash,
+            io_type: IOType::StandardFIO,
+            merge_ratio_threshold: 0.5,
+            merge_min_older_file_bytes: 0,
+            merge_interval: None,
+            merge_on_close: false,
+            checkpoint_interval: None,
+            checkpoint_on_close: true,
+            index_cache_pages: 1024,
+            index_rebuild_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            index_shards: 16,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum IndexType {
+    /// 标准库 BTreeMap，有序
     BTree,
+
+    /// hash 表 (keydir)，范围遍历需要扫描全部 key
     Hash,
+
+    /// 并发跳表，有序，读不需要加锁
     SkipList,
+
+    /// 自适应基数树，有序，key 共享长前缀时占用内存更少
+    Art,
+
+    /// 紧凑的 hash 表，key 保存在 arena 中，MetaData 压缩为窄整数，要求 file_threshold 不超过 u32::MAX
+    CompactHash,
+
+    /// 按 key 的 hash 分片的 hash 表，分片数由 index_shards 指定，写入只锁住 key 所在的分片
+    ShardedHash,
+
+    /// 保存在数据目录中的 B+ 树，只缓存部分页，key 的数量不受内存限制，正常关闭后重启不需要重建索引。
+    /// key 不能超过 BPTREE_MAX_KEY_SIZE 字节，超过时写入返回 KeyTooLarge
+    /// 重启时不扫描 data file，已过期的 key 在 merge 回收之前仍然计入 key_num
+    BPlusTree,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IOType {
+    /// 标准文件 IO
+    StandardFIO,
+
+    /// 内存映射
+    MemoryMap,
+}
+
+/// Engine::iter 的遍历范围和方向
+#[derive(Clone, Debug, Default)]
+pub struct IteratorOptions {
+    /// 只遍历大于等于该值的 key, None 表示没有下界
+    pub lower_bound: Option<Vec<u8>>,
+
+    /// 只遍历小于该值的 key, None 表示没有上界
+    pub upper_bound: Option<Vec<u8>>,
+
+    /// 是否从大到小遍历
+    pub reverse: bool,
+}
+
+impl IteratorOptions {
+    /// 遍历以 prefix 开头的所有 key，转换成范围 [prefix, prefix 的后继)
+    pub fn prefix(prefix: &[u8]) -> Self {
+        // 后继是去掉末尾的 0xff 之后最后一个字节加 1，全是 0xff 时没有上界
+        let mut upper_bound = prefix.to_vec();
+        while upper_bound.last() == Some(&0xff) {
+            upper_bound.pop();
+        }
+        let upper_bound = match upper_bound.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Some(upper_bound)
+            }
+            None => None,
+        };
+        Self {
+            lower_bound: Some(prefix.to_vec()),
+            upper_bound,
+            reverse: false,
+        }
+    }
+
+    /// key 是否在遍历范围内
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.lower_bound
+            .as_ref()
+            .is_none_or(|lower| key >= lower.as_slice())
+            && self
+                .upper_bound
+                .as_ref()
+                .is_none_or(|upper| key < upper.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_options() {
+        let options = IteratorOptions::prefix("tenant/".as_bytes());
+        assert_eq!(options.upper_bound.unwrap(), "tenant0".as_bytes());
+
+        let options = IteratorOptions::prefix(&[0x01, 0xff, 0xff]);
+        assert_eq!(options.upper_bound, Some(vec![0x02]));
+        assert!(options.contains(&[0x01, 0xff, 0xff, 0x00]));
+        assert!(!options.contains(&[0x01, 0xff, 0xfe]));
+
+        let options = IteratorOptions::prefix(&[0xff]);
+        assert!(options.upper_bound.is_none());
+        assert!(options.contains(&[0xff, 0xff]));
+
+        let options = IteratorOptions::prefix(&[]);
+        assert!(options.upper_bound.is_none());
+        assert!(options.contains("any".as_bytes()));
+    }
 }
@@ -0,0 +1,405 @@
+use crate::checkpoint::remove_checkpoint_file;
+use crate::data::datafile::{DataFile, DataFileType, DATA_FILE_HEADER_SIZE, DATA_FILE_SUFFIX};
+use crate::data::entry::Entry;
+use crate::data::hint_file::HINT_FILE_SUFFIX;
+use crate::error::E::{DirPathIsEmpty, Failed2ReadDBDir, Failed2Repair, UnknownDataFileFormat};
+use crate::error::R;
+use crate::merge;
+use crate::options::{IOType, Options};
+use log::{error, info, warn};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 无法修复的文件以及被重写的文件的原始内容都移动到该目录下
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// 修复过程中重写的 data file 先写到该目录，写完后再替换原文件
+const REPAIR_DIR_NAME: &str = "repair";
+
+/// 单个 data file 的处理结果
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RepairAction {
+    /// 所有 entry 都完好，文件没有改动
+    Intact,
+
+    /// 文件中有损坏的数据, 完好的 entry 重写到了新文件中, 原文件移动到 quarantine
+    Rewritten,
+
+    /// 文件头损坏或者没有任何完好的 entry, 整个文件移动到 quarantine
+    Quarantined,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileRepair {
+    pub file_id: u32,
+    pub action: RepairAction,
+
+    /// 完好的 entry 数量以及占用的字节数
+    pub salvaged_entries: usize,
+    pub salvaged_bytes: usize,
+
+    /// 被丢弃的字节数
+    pub corrupted_bytes: usize,
+}
+
+/// 修复报告，按 file id 排序
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    pub files: Vec<FileRepair>,
+}
+
+impl RepairReport {
+    pub fn is_intact(&self) -> bool {
+        self.files.iter().all(|f| f.action == RepairAction::Intact)
+    }
+}
+
+impl Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for file in &self.files {
+            match file.action {
+                RepairAction::Intact => writeln!(
+                    f,
+                    "file {}: intact, {} entries",
+                    file.file_id, file.salvaged_entries
+                )?,
+                RepairAction::Rewritten => writeln!(
+                    f,
+                    "file {}: rewritten, salvaged {} entries ({} bytes), dropped {} corrupted bytes",
+                    file.file_id, file.salvaged_entries, file.salvaged_bytes, file.corrupted_bytes
+                )?,
+                RepairAction::Quarantined => writeln!(
+                    f,
+                    "file {}: quarantined, dropped {} corrupted bytes",
+                    file.file_id, file.corrupted_bytes
+                )?,
+            }
+        }
+        let damaged = self
+            .files
+            .iter()
+            .filter(|f| f.action != RepairAction::Intact)
+            .count();
+        write!(
+            f,
+            "checked {} data files, {} damaged",
+            self.files.len(),
+            damaged
+        )
+    }
+}
+
+/// 离线修复数据目录，调用时不能有 Engine 打开该目录。
+/// 逐个校验 data file 中每个 entry 的长度和 crc, 损坏的文件中完好的 entry 按原顺序重写到同 id 的新文件，
+/// 原文件移动到 quarantine 目录，对应的 hint file 直接删除，下次启动时重新扫描 data file
+pub fn repair(opts: &Options) -> R<RepairReport> {
+    let dir_path = opts.dir_path.as_str();
+    if dir_path.is_empty() {
+        return Err(DirPathIsEmpty);
+    }
+
+    // 先完成或者丢弃未完成的 merge，保证目录中的文件是一致的
+    merge::recover_merge(dir_path)?;
+    // 修复可能改变 entry 的位置，修复后第一次启动扫描全部 data file
+    remove_checkpoint_file(dir_path)?;
+
+    let mut file_ids = Vec::new();
+    let read_dir = fs::read_dir(dir_path).map_err(|e| {
+        error!("failed to read data dir, {}", e);
+        Failed2ReadDBDir
+    })?;
+    for entry in read_dir.flatten() {
+        let file_name = entry.file_name().into_string().unwrap_or_default();
+        if let Some(file_id) = file_name
+            .strip_suffix(DATA_FILE_SUFFIX)
+            .and_then(|file_id| file_id.parse::<u32>().ok())
+        {
+            file_ids.push(file_id);
+        }
+    }
+    file_ids.sort();
+
+    let dir = Path::new(dir_path);
+    let repair_dir = dir.join(REPAIR_DIR_NAME);
+    if repair_dir.is_dir() {
+        remove_dir(&repair_dir)?;
+    }
+    let mut report = RepairReport::default();
+    for file_id in file_ids {
+        let file_repair = repair_data_file(dir, &repair_dir, file_id)?;
+        match file_repair.action {
+            RepairAction::Intact => {}
+            RepairAction::Rewritten | RepairAction::Quarantined => warn!(
+                "data file {} is damaged, {:?}, dropped {} bytes",
+                file_id, file_repair.action, file_repair.corrupted_bytes
+            ),
+        }
+        report.files.push(file_repair);
+    }
+    if repair_dir.is_dir() {
+        remove_dir(&repair_dir)?;
+    }
+    info!("repair finished, {} data files checked", report.files.len());
+    Ok(report)
+}
+
+fn repair_data_file(dir: &Path, repair_dir: &Path, file_id: u32) -> R<FileRepair> {
+    let data_file_name = file_id.to_string() + DATA_FILE_SUFFIX;
+    let data_file_path = dir.join(&data_file_name);
+    let mut file_repair = FileRepair {
+        file_id,
+        action: RepairAction::Intact,
+        salvaged_entries: 0,
+        salvaged_bytes: 0,
+        corrupted_bytes: 0,
+    };
+
+    // 文件头不可识别时整个文件都不可信, 更高版本的文件不是损坏, 不做处理
+    let data_file = match DataFile::create_from_full_path(
+        data_file_path.display().to_string(),
+        DataFileType::OLD,
+        IOType::StandardFIO,
+    ) {
+        Ok(data_file) => data_file,
+        Err(UnknownDataFileFormat) => {
+            file_repair.action = RepairAction::Quarantined;
+            file_repair.corrupted_bytes = file_len(&data_file_path);
+            quarantine(dir, file_id)?;
+            return Ok(file_repair);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (entries, corrupted_bytes) = salvage_entries(&data_file)?;
+    drop(data_file);
+    file_repair.salvaged_entries = entries.len();
+    file_repair.salvaged_bytes = entries.iter().map(|e| e.get_self_size()).sum();
+    file_repair.corrupted_bytes = corrupted_bytes;
+    if corrupted_bytes == 0 {
+        return Ok(file_repair);
+    }
+
+    if entries.is_empty() {
+        file_repair.action = RepairAction::Quarantined;
+        quarantine(dir, file_id)?;
+        return Ok(file_repair);
+    }
+
+    // 完好的 entry 先写到 repair 目录，落盘后再保留原文件的副本并替换原文件
+    file_repair.action = RepairAction::Rewritten;
+    create_dir(repair_dir)?;
+    let repaired_file = DataFile::new(repair_dir.display().to_string(), file_id)?;
+    for entry in &entries {
+        repaired_file.append(entry.encode())?;
+    }
+    repaired_file.sync()?;
+    drop(repaired_file);
+
+    let quarantine_dir = create_dir(&dir.join(QUARANTINE_DIR_NAME))?;
+    copy_file(
+        &data_file_path,
+        &quarantine_path(&quarantine_dir, &data_file_name),
+    )?;
+    remove_hint_file(dir, file_id)?;
+    rename_file(&repair_dir.join(&data_file_name), &data_file_path)?;
+    Ok(file_repair)
+}
+
+/// 逐字节向后寻找下一个 header、长度和 crc 都合法的 entry, 返回所有完好的 entry 以及被跳过的字节数。
+/// 只有 header 合法并且长度不超过文件末尾时才读取整个 entry 校验 crc，损坏的区域中大部分位置只读取 header
+fn salvage_entries(data_file: &DataFile) -> R<(Vec<Entry>, usize)> {
+    let file_len = data_file.next_write_begin_pos();
+    let mut entries = Vec::new();
+    let mut corrupted_bytes = 0;
+    let mut pos = DATA_FILE_HEADER_SIZE;
+    // 旧版本的 entry 按当前格式重写
+    let version = data_file.version();
+    let header_size = Entry::header_size(version);
+    let mut header_buf = vec![0; header_size];
+    while pos < file_len {
+        let mut entry = None;
+        if data_file.read_with_given_pos(pos, &mut header_buf)? == header_size
+            && Entry::is_valid_header(&header_buf, version)
+        {
+            let (ksz, value_sz) = Entry::decode_sizes_with_version(&header_buf, version);
+            let entry_len = header_size + ksz + value_sz;
+            if pos + entry_len <= file_len {
+                let mut buf = vec![0; entry_len];
+                data_file.read_with_given_pos(pos, &mut buf)?;
+                entry = Entry::decode_with_version(buf, version).map(|e| (e, entry_len));
+            }
+        }
+
+        match entry {
+            Some((entry, entry_len)) => {
+                pos += entry_len;
+                entries.push(entry);
+            }
+            None => {
+                pos += 1;
+                corrupted_bytes += 1;
+            }
+        }
+    }
+    Ok((entries, corrupted_bytes))
+}
+
+/// data file 和对应的 hint file 移动到 quarantine 目录
+fn quarantine(dir: &Path, file_id: u32) -> R<()> {
+    let quarantine_dir = create_dir(&dir.join(QUARANTINE_DIR_NAME))?;
+    let data_file_name = file_id.to_string() + DATA_FILE_SUFFIX;
+    remove_hint_file(dir, file_id)?;
+    rename_file(
+        &dir.join(&data_file_name),
+        &quarantine_path(&quarantine_dir, &data_file_name),
+    )
+}
+
+/// 多次修复时 quarantine 目录中可能已有同名文件，追加序号避免覆盖
+fn quarantine_path(quarantine_dir: &Path, file_name: &str) -> PathBuf {
+    let mut path = quarantine_dir.join(file_name);
+    let mut seq = 1;
+    while path.exists() {
+        path = quarantine_dir.join(format!("{}.{}", file_name, seq));
+        seq += 1;
+    }
+    path
+}
+
+fn file_len(path: &Path) -> usize {
+    fs::metadata(path).map(|m| m.len() as usize).unwrap_or(0)
+}
+
+fn remove_hint_file(dir: &Path, file_id: u32) -> R<()> {
+    let path = dir.join(file_id.to_string() + HINT_FILE_SUFFIX);
+    match fs::remove_file(&path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            error!("failed to remove {}, {}", path.display(), e);
+            Err(Failed2Repair)
+        }
+    }
+}
+
+fn create_dir(path: &Path) -> R<PathBuf> {
+    if let Err(e) = fs::create_dir_all(path) {
+        error!("failed to create {}, {}", path.display(), e);
+        return Err(Failed2Repair);
+    }
+    Ok(path.to_path_buf())
+}
+
+fn copy_file(from: &Path, to: &Path) -> R<()> {
+    let res = fs::copy(from, to).and_then(|_| fs::File::open(to)?.sync_all());
+    if let Err(e) = res {
+        error!(
+            "failed to copy {} to {}, {}",
+            from.display(),
+            to.display(),
+            e
+        );
+        return Err(Failed2Repair);
+    }
+    Ok(())
+}
+
+fn rename_file(from: &Path, to: &Path) -> R<()> {
+    if let Err(e) = fs::rename(from, to) {
+        error!(
+            "failed to move {} to {}, {}",
+            from.display(),
+            to.display(),
+            e
+        );
+        return Err(Failed2Repair);
+    }
+    Ok(())
+}
+
+fn remove_dir(path: &Path) -> R<()> {
+    if let Err(e) = fs::remove_dir_all(path) {
+        error!("failed to remove {}, {}", path.display(), e);
+        return Err(Failed2Repair);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{get_default_options, open_engine};
+    use crate::db::Engine;
+    use crate::error::E;
+
+    fn corrupt(dir_path: &str, file_id: u32, offset: usize) {
+        let path = DataFile::get_file_full_path(dir_path.to_string(), file_id.to_string());
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[offset] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_repair_intact_dir() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("hello", "world".as_bytes().to_vec()).unwrap();
+        drop(engine);
+
+        let report = repair(&options).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].salvaged_entries, 1);
+        assert!(!Path::new(&options.dir_path)
+            .join(QUARANTINE_DIR_NAME)
+            .exists());
+    }
+
+    #[test]
+    fn test_repair_damaged_files() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options.clone());
+        for i in 0..20 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+        let meta_data = engine
+            .mem_index
+            .read()
+            .get("key-1".as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta_data.file_id, 0);
+        drop(engine);
+
+        // file 0 中的 key-1 损坏, file 1 的文件头损坏
+        corrupt(&options.dir_path, 0, meta_data.entry_start_pos + 4);
+        corrupt(&options.dir_path, 1, 0);
+        assert!(matches!(
+            Engine::open(options.clone()),
+            Err(E::UnknownDataFileFormat)
+        ));
+
+        let report = repair(&options).unwrap();
+        assert!(report.to_string().contains("file 0: rewritten"));
+        assert!(!report.is_intact());
+        assert_eq!(report.files[0].action, RepairAction::Rewritten);
+        assert_eq!(report.files[0].corrupted_bytes, meta_data.entry_sz);
+        assert_eq!(report.files[1].action, RepairAction::Quarantined);
+        let quarantine_dir = Path::new(&options.dir_path).join(QUARANTINE_DIR_NAME);
+        assert!(quarantine_dir.join("0.bck").is_file());
+        assert!(quarantine_dir.join("1.bck").is_file());
+
+        let engine = open_engine(options.clone());
+        assert_eq!(engine.read("key-0").unwrap(), "value-0".as_bytes());
+        assert!(engine.read("key-1").is_err());
+        assert_eq!(engine.read("key-19").unwrap(), "value-19".as_bytes());
+        drop(engine);
+
+        // 修复后的目录再次检查是完好的
+        assert!(repair(&options).unwrap().is_intact());
+    }
+}
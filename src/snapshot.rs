@@ -0,0 +1,340 @@
+use crate::data::entry::Entry;
+use crate::data::meta_data::MetaData;
+use crate::db::Engine;
+use crate::error::E::{EmptyKey, Nil};
+use crate::error::R;
+use crate::index::iterator::SortedIterator;
+use crate::iterator::Iter;
+use crate::options::IteratorOptions;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// 快照创建之后被覆盖或者删除的 key 在快照创建时的版本，None 表示快照创建时 key 不存在
+#[derive(Default)]
+pub(crate) struct SnapshotVersions {
+    versions: Mutex<HashMap<Vec<u8>, Option<MetaData>>>,
+}
+
+/// 所有存活的快照，写入时把被覆盖的版本保存到每个快照中
+#[derive(Default)]
+pub(crate) struct Snapshots {
+    next_id: u64,
+    live: HashMap<u64, Arc<SnapshotVersions>>,
+}
+
+impl Snapshots {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// key 即将被覆盖或者删除，只保留每个快照看到的第一个版本
+    pub(crate) fn retain_version(&self, key: &[u8], meta_data: Option<MetaData>) {
+        for snapshot in self.live.values() {
+            snapshot
+                .versions
+                .lock()
+                .entry(key.to_vec())
+                .or_insert(meta_data);
+        }
+    }
+
+    /// 是否有快照仍然引用该版本
+    pub(crate) fn references(&self, key: &[u8], meta_data: MetaData) -> bool {
+        self.live
+            .values()
+            .any(|snapshot| snapshot.versions.lock().get(key) == Some(&Some(meta_data)))
+    }
+
+    /// 过期的版本被 merge 回收，快照中视为不存在
+    pub(crate) fn forget(&self, key: &[u8], meta_data: MetaData) {
+        for snapshot in self.live.values() {
+            if let Some(version) = snapshot.versions.lock().get_mut(key) {
+                if *version == Some(meta_data) {
+                    *version = None;
+                }
+            }
+        }
+    }
+
+    /// 快照引用的最小 file id，merge 只能合并比它小的文件
+    pub(crate) fn min_referenced_file_id(&self) -> Option<u32> {
+        self.live
+            .values()
+            .flat_map(|snapshot| {
+                snapshot
+                    .versions
+                    .lock()
+                    .values()
+                    .flatten()
+                    .map(|meta_data| meta_data.file_id)
+                    .min()
+            })
+            .min()
+    }
+
+    /// merge 把快照引用的版本重写到了 merged data file 中
+    pub(crate) fn relocate(&self, key: &[u8], old_meta_data: MetaData, new_meta_data: MetaData) {
+        for snapshot in self.live.values() {
+            if let Some(meta_data) = snapshot.versions.lock().get_mut(key) {
+                if *meta_data == Some(old_meta_data) {
+                    *meta_data = Some(new_meta_data);
+                }
+            }
+        }
+    }
+}
+
+/// 只读快照，看到的是创建时刻的数据，不受之后的写入和 merge 影响。
+/// 快照存活期间，每次写入都要把被覆盖的版本保存到快照中，merge 也不会合并快照引用的文件，
+/// 不再使用时应尽快 drop
+pub struct Snapshot<'a> {
+    engine: &'a Engine,
+    id: u64,
+    versions: Arc<SnapshotVersions>,
+}
+
+impl Engine {
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // 与写入一样持有 active file 的可升级读锁，保证没有写到一半的 batch
+        let _active_file = self.active_file.upgradable_read();
+        let mut snapshots = self.snapshots.write();
+        let id = snapshots.next_id;
+        snapshots.next_id += 1;
+        let versions = Arc::new(SnapshotVersions::default());
+        snapshots.live.insert(id, versions.clone());
+        Snapshot {
+            engine: self,
+            id,
+            versions,
+        }
+    }
+}
+
+impl Snapshot<'_> {
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> R<Vec<u8>> {
+        let (_, entry) = self.read_with_meta_data(key.as_ref())?.ok_or(Nil)?;
+        if entry.is_expired() {
+            return Err(Nil);
+        }
+        Ok(entry.v().to_owned())
+    }
+
+    /// 读取快照创建时 key 对应的 entry 以及 MetaData，不检查 entry 是否过期，key 不存在时返回 None
+    pub(crate) fn read_with_meta_data(&self, key: &[u8]) -> R<Option<(MetaData, Entry)>> {
+        if key.is_empty() {
+            return Err(EmptyKey);
+        }
+
+        // 加锁顺序与 Engine::read 一致
+        let active_file = self.engine.active_file.read();
+        let older_files = self.engine.older_files.read();
+        let mem_index = self.engine.mem_index.read();
+        let meta_data = match self.versions.versions.lock().get(key) {
+            Some(meta_data) => *meta_data,
+            None => mem_index.get(key)?,
+        };
+        drop(mem_index);
+
+        match meta_data {
+            Some(meta_data) => {
+                let entry = Engine::read_entry_at(&active_file, &older_files, &meta_data)?;
+                Ok(Some((meta_data, entry)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 快照创建之后 key 是否被写入或者删除过
+    pub(crate) fn is_modified(&self, key: &[u8]) -> bool {
+        self.versions.versions.lock().contains_key(key)
+    }
+
+    /// 按 key 的顺序遍历快照创建时 options 范围内的数据
+    pub fn iter(&self, options: IteratorOptions) -> Iter<'_> {
+        // 快照创建之后写入的 key 不可见，之后被删除的 key 仍然可见
+        let _active_file = self.engine.active_file.read();
+        let mem_index = self.engine.mem_index.read();
+        let versions = self.versions.versions.lock();
+        let mut index_iter = mem_index.iterator(&options);
+        let mut items = Vec::new();
+        while let Some(item) = index_iter.next() {
+            let (key, meta_data) = match item {
+                Ok(item) => item,
+                Err(e) => return Iter::failed(e),
+            };
+            if !versions.contains_key(key) {
+                items.push((key.to_vec(), *meta_data));
+            }
+        }
+        for (key, meta_data) in versions.iter() {
+            if let Some(meta_data) = meta_data {
+                items.push((key.clone(), *meta_data));
+            }
+        }
+        let index_iter = SortedIterator::from_unsorted(items, &options);
+        Iter::new(Box::new(index_iter), move |key| self.get(key))
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.engine.snapshots.write().live.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatch;
+    use crate::db::tests::{get_default_options, open_engine};
+
+    #[test]
+    fn test_snapshot() {
+        let engine = open_engine(get_default_options());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        engine.put("b", "2".as_bytes().to_vec()).unwrap();
+
+        let snapshot = engine.snapshot();
+        engine.put("a", "10".as_bytes().to_vec()).unwrap();
+        engine.put("a", "100".as_bytes().to_vec()).unwrap();
+        engine.delete("b").unwrap();
+        engine.put("c", "3".as_bytes().to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("b", "20".as_bytes().to_vec()).unwrap();
+        batch.delete("c").unwrap();
+        engine.write_batch(batch).unwrap();
+
+        assert_eq!(snapshot.get("a").unwrap(), "1".as_bytes());
+        assert_eq!(snapshot.get("b").unwrap(), "2".as_bytes());
+        assert!(matches!(snapshot.get("c"), Err(Nil)));
+        assert_eq!(engine.read("a").unwrap(), "100".as_bytes());
+        assert_eq!(engine.read("b").unwrap(), "20".as_bytes());
+        assert!(matches!(engine.read("c"), Err(Nil)));
+
+        let snapshot2 = engine.snapshot();
+        engine.put("a", "1000".as_bytes().to_vec()).unwrap();
+        assert_eq!(snapshot.get("a").unwrap(), "1".as_bytes());
+        assert_eq!(snapshot2.get("a").unwrap(), "100".as_bytes());
+
+        let items: Vec<(Vec<u8>, Vec<u8>)> = snapshot
+            .iter(IteratorOptions::default())
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                ("a".as_bytes().to_vec(), "1".as_bytes().to_vec()),
+                ("b".as_bytes().to_vec(), "2".as_bytes().to_vec()),
+            ]
+        );
+        let options = IteratorOptions {
+            lower_bound: Some("b".as_bytes().to_vec()),
+            reverse: true,
+            ..Default::default()
+        };
+        let items: Vec<Vec<u8>> = snapshot2
+            .iter(options)
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(items, vec!["b".as_bytes().to_vec()]);
+
+        drop(snapshot);
+        drop(snapshot2);
+        assert!(engine.snapshots.read().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_with_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 256;
+        let engine = open_engine(options);
+        engine.put("old", "0".as_bytes().to_vec()).unwrap();
+        for i in 0..10 {
+            engine
+                .put(format!("key-{}", i), format!("value-{}", i).into_bytes())
+                .unwrap();
+        }
+
+        let snapshot = engine.snapshot();
+        for i in 0..10 {
+            engine
+                .put(
+                    format!("key-{}", i),
+                    format!("new-value-{}", i).into_bytes(),
+                )
+                .unwrap();
+        }
+        engine.delete("old").unwrap();
+
+        // 快照引用的 file 0 不参与 merge
+        let older_file_bytes = || -> usize {
+            let active_file_id = engine.active_file.read().file_id();
+            engine
+                .file_stats()
+                .iter()
+                .filter(|s| s.file_id != active_file_id)
+                .map(|s| s.total_bytes)
+                .sum()
+        };
+        let before = older_file_bytes();
+        engine.merge().unwrap();
+        assert_eq!(older_file_bytes(), before);
+        assert_eq!(snapshot.get("old").unwrap(), "0".as_bytes());
+        for i in 0..10 {
+            assert_eq!(
+                snapshot.get(format!("key-{}", i)).unwrap(),
+                format!("value-{}", i).into_bytes()
+            );
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                format!("new-value-{}", i).into_bytes()
+            );
+        }
+
+        // 快照释放之后 merge 可以回收旧版本
+        drop(snapshot);
+        engine.merge().unwrap();
+        assert!(older_file_bytes() < before);
+        assert!(matches!(engine.read("old"), Err(Nil)));
+        for i in 0..10 {
+            assert_eq!(
+                engine.read(format!("key-{}", i)).unwrap(),
+                format!("new-value-{}", i).into_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_relocated_by_merge() {
+        let engine = open_engine(get_default_options());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        let snapshot = engine.snapshot();
+        let old_meta_data = engine
+            .mem_index
+            .read()
+            .get("a".as_bytes())
+            .unwrap()
+            .unwrap();
+
+        // 模拟 merge 写 merged file 期间 key 被覆盖: 快照引用的版本被 merge 重写到了新的位置
+        let new_meta_data = MetaData::new(
+            old_meta_data.file_id,
+            old_meta_data.entry_sz,
+            old_meta_data.entry_start_pos,
+            old_meta_data.tstamp + 1,
+        );
+        engine.put("a", "2".as_bytes().to_vec()).unwrap();
+        let snapshots = engine.snapshots.read();
+        assert!(snapshots.references("a".as_bytes(), old_meta_data));
+        assert_eq!(
+            snapshots.min_referenced_file_id(),
+            Some(old_meta_data.file_id)
+        );
+        snapshots.relocate("a".as_bytes(), old_meta_data, new_meta_data);
+        assert!(!snapshots.references("a".as_bytes(), old_meta_data));
+        assert!(snapshots.references("a".as_bytes(), new_meta_data));
+        drop(snapshots);
+        assert_eq!(snapshot.get("a").unwrap(), "1".as_bytes());
+    }
+}
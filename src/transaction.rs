@@ -0,0 +1,254 @@
+use crate::data::entry::Entry;
+use crate::db::Engine;
+use crate::error::E::{EmptyKey, Nil, TransactionConflict};
+use crate::error::R;
+use crate::snapshot::Snapshot;
+use std::collections::HashMap;
+
+/// 乐观事务，读取的是事务开始时的快照，写入先缓存在事务中，
+/// commit 时检查读过的 key 是否被修改，没有冲突时原子写入。
+/// 事务存活期间与快照一样保留被覆盖的版本，不再使用时应尽快 commit 或者 drop
+pub struct Transaction<'a> {
+    engine: &'a Engine,
+
+    /// 事务开始时的快照，之后的写入对事务不可见
+    snapshot: Snapshot<'a>,
+
+    /// 读过的 key 以及读到的版本的 tstamp，None 表示 key 不存在
+    reads: HashMap<Vec<u8>, Option<u64>>,
+
+    /// 缓存的写入，tombstone 表示删除
+    writes: HashMap<Vec<u8>, Entry>,
+}
+
+impl Engine {
+    pub fn begin_transaction(&self) -> Transaction<'_> {
+        Transaction {
+            engine: self,
+            snapshot: self.snapshot(),
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+        }
+    }
+}
+
+impl Transaction<'_> {
+    /// 优先读取事务自己的写入，否则读取事务开始时的快照并记录读到的版本
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> R<Vec<u8>> {
+        let key = key.as_ref();
+        if let Some(entry) = self.writes.get(key) {
+            if entry.is_tombstone() {
+                return Err(Nil);
+            }
+            return Ok(entry.v().to_owned());
+        }
+
+        let (tstamp, value) = match self.snapshot.read_with_meta_data(key)? {
+            // 过期的 key 读不到，但仍然记录读到的版本用于冲突检查
+            Some((meta_data, entry)) if entry.is_expired() => (Some(meta_data.tstamp), Err(Nil)),
+            Some((meta_data, entry)) => (Some(meta_data.tstamp), Ok(entry.v().to_owned())),
+            None => (None, Err(Nil)),
+        };
+        self.reads.entry(key.to_vec()).or_insert(tstamp);
+        value
+    }
+
+    pub fn put<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> R<()> {
+        let key = key.as_ref();
+        self.engine.check_key_size(key)?;
+        let entry = Entry::new(key.to_vec(), value)?;
+        self.writes.insert(key.to_vec(), entry);
+        Ok(())
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> R<()> {
+        let key = key.as_ref();
+        if key.is_empty() {
+            return Err(EmptyKey);
+        }
+        self.engine.check_key_size(key)?;
+        let entry = Entry::get_tombstone_with_given_key(key.to_vec())?;
+        self.writes.insert(key.to_vec(), entry);
+        Ok(())
+    }
+
+    /// 读过的 key 在事务开始后被修改时返回 TransactionConflict，事务中的写入全部丢弃，调用方可以重试。
+    /// 当前版本的 tstamp 与读到的不同，或者快照为它保留了旧版本 (同一毫秒内的写入 tstamp 相同) 都是冲突。
+    /// merge 移动 entry 的位置不改变 tstamp，不会误报冲突。
+    /// 检查和写入都在 active file 的可升级读锁内完成，期间不会有其他写入
+    pub fn commit(self) -> R<()> {
+        let mut active_file = self.engine.active_file.upgradable_read();
+        let mem_index = self.engine.mem_index.read();
+        for (key, tstamp) in &self.reads {
+            let current = mem_index.get(key)?.map(|meta_data| meta_data.tstamp);
+            if current != *tstamp || self.snapshot.is_modified(key) {
+                return Err(TransactionConflict);
+            }
+        }
+        drop(mem_index);
+
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let entries = self.engine.seal_batch(self.writes.into_values().collect());
+        self.engine.append_entries(&mut active_file, &entries)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{get_default_options, open_engine};
+    use std::thread;
+
+    #[test]
+    fn test_transaction() {
+        let options = get_default_options();
+        let engine = open_engine(options.clone());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        engine.put("b", "2".as_bytes().to_vec()).unwrap();
+
+        let mut txn = engine.begin_transaction();
+        assert_eq!(txn.get("a").unwrap(), "1".as_bytes());
+        txn.put("a", "10".as_bytes().to_vec()).unwrap();
+        txn.delete("b").unwrap();
+        txn.put("c", "3".as_bytes().to_vec()).unwrap();
+        assert!(txn.put("", vec![1]).is_err());
+
+        // 事务内可以读到自己的写入，提交前对外不可见
+        assert_eq!(txn.get("a").unwrap(), "10".as_bytes());
+        assert!(matches!(txn.get("b"), Err(Nil)));
+        assert!(matches!(engine.read("c"), Err(Nil)));
+        txn.commit().unwrap();
+
+        assert_eq!(engine.read("a").unwrap(), "10".as_bytes());
+        assert!(matches!(engine.read("b"), Err(Nil)));
+        assert_eq!(engine.read("c").unwrap(), "3".as_bytes());
+        drop(engine);
+
+        let engine = open_engine(options);
+        assert_eq!(engine.read("a").unwrap(), "10".as_bytes());
+        assert!(matches!(engine.read("b"), Err(Nil)));
+        assert_eq!(engine.read("c").unwrap(), "3".as_bytes());
+    }
+
+    #[test]
+    fn test_transaction_conflict() {
+        let engine = open_engine(get_default_options());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+
+        let mut txn = engine.begin_transaction();
+        assert_eq!(txn.get("a").unwrap(), "1".as_bytes());
+        txn.put("b", "2".as_bytes().to_vec()).unwrap();
+        engine.put("a", "2".as_bytes().to_vec()).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionConflict)));
+        assert!(matches!(engine.read("b"), Err(Nil)));
+
+        // 读到不存在的 key 之后 key 被写入同样是冲突
+        let mut txn = engine.begin_transaction();
+        assert!(matches!(txn.get("c"), Err(Nil)));
+        txn.put("c", "1".as_bytes().to_vec()).unwrap();
+        engine.put("c", "2".as_bytes().to_vec()).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionConflict)));
+        assert_eq!(engine.read("c").unwrap(), "2".as_bytes());
+
+        // 没有读过的 key 被修改不算冲突
+        let mut txn = engine.begin_transaction();
+        txn.put("d", "1".as_bytes().to_vec()).unwrap();
+        engine.put("a", "3".as_bytes().to_vec()).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(engine.read("d").unwrap(), "1".as_bytes());
+    }
+
+    #[test]
+    fn test_transaction_consistent_view() {
+        let engine = open_engine(get_default_options());
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        engine.put("b", "1".as_bytes().to_vec()).unwrap();
+
+        // 事务开始之后的写入对事务不可见，两次读取看到的是同一时刻的数据
+        let mut txn = engine.begin_transaction();
+        assert_eq!(txn.get("a").unwrap(), "1".as_bytes());
+        engine.put("b", "2".as_bytes().to_vec()).unwrap();
+        engine.put("c", "2".as_bytes().to_vec()).unwrap();
+        assert_eq!(txn.get("b").unwrap(), "1".as_bytes());
+        assert!(matches!(txn.get("c"), Err(Nil)));
+        txn.put("d", "1".as_bytes().to_vec()).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionConflict)));
+        assert!(matches!(engine.read("d"), Err(Nil)));
+
+        // 同一毫秒内的覆盖写入 tstamp 可能相同，同样是冲突
+        let mut txn = engine.begin_transaction();
+        assert_eq!(txn.get("a").unwrap(), "1".as_bytes());
+        engine.put("a", "2".as_bytes().to_vec()).unwrap();
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionConflict)));
+        assert!(engine.snapshots.read().is_empty());
+    }
+
+    #[test]
+    fn test_transaction_with_merge() {
+        let mut options = get_default_options();
+        options.file_threshold = 128;
+        let engine = open_engine(options);
+        engine.put("x", "0".as_bytes().to_vec()).unwrap();
+        engine.put("x", "1".as_bytes().to_vec()).unwrap();
+        engine.put("a", "1".as_bytes().to_vec()).unwrap();
+        for i in 0..5 {
+            engine.put(format!("key-{}", i), vec![1]).unwrap();
+        }
+
+        // merge 移动了 entry 的位置，但是 key 没有被修改，不是冲突
+        let mut txn = engine.begin_transaction();
+        assert_eq!(txn.get("a").unwrap(), "1".as_bytes());
+        let before = engine
+            .mem_index
+            .read()
+            .get("a".as_bytes())
+            .unwrap()
+            .unwrap();
+        engine.merge().unwrap();
+        let after = engine
+            .mem_index
+            .read()
+            .get("a".as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_ne!(before, after);
+        assert_eq!(before.tstamp, after.tstamp);
+        txn.put("a", "2".as_bytes().to_vec()).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(engine.read("a").unwrap(), "2".as_bytes());
+    }
+
+    #[test]
+    fn test_concurrent_counter() {
+        let engine = open_engine(get_default_options());
+        engine.put("counter", 0u64.to_le_bytes().to_vec()).unwrap();
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        loop {
+                            let mut txn = engine.begin_transaction();
+                            let value = txn.get("counter").unwrap();
+                            let counter = u64::from_le_bytes(value.try_into().unwrap());
+                            txn.put("counter", (counter + 1).to_le_bytes().to_vec())
+                                .unwrap();
+                            match txn.commit() {
+                                Ok(_) => break,
+                                Err(TransactionConflict) => continue,
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let value = engine.read("counter").unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 200);
+    }
+}